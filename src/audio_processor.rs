@@ -0,0 +1,471 @@
+use crate::{
+    memory::{ mappers::ExpansionChip, MemoryHandler, MemoryOperation, MemoryRegion },
+    processor::{ settings::{ Settings, SettingsObserver }, CPU },
+    region::Region,
+    snapshot::{ Snapshot, StateReader, StateWriter },
+};
+use blip_buffer::BlipBuffer;
+use dmc::DMC;
+use expansion::{ create_expansion_audio, is_register, ExpansionAudio };
+use filters::FilterChain;
+use frame_counter::FrameCounter;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+mod blip_buffer;
+mod dmc;
+mod expansion;
+mod filters;
+mod frame_counter;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AudioChannel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    // expansion chips, each is one channel
+    VRC6,
+    VRC7,
+    FDS,
+    MMC5,
+    Namco163,
+    Sunsoft5B,
+}
+
+pub const CHANNEL_COUNT: usize = 11;
+
+impl AudioChannel {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name.to_lowercase().as_str() {
+            "pulse1" => Ok(AudioChannel::Pulse1),
+            "pulse2" => Ok(AudioChannel::Pulse2),
+            "triangle" => Ok(AudioChannel::Triangle),
+            "noise" => Ok(AudioChannel::Noise),
+            "dmc" => Ok(AudioChannel::DMC),
+            "vrc6" => Ok(AudioChannel::VRC6),
+            "vrc7" => Ok(AudioChannel::VRC7),
+            "fds" => Ok(AudioChannel::FDS),
+            "mmc5" => Ok(AudioChannel::MMC5),
+            "n163" | "namco163" => Ok(AudioChannel::Namco163),
+            "5b" | "fme7" | "sunsoft5b" => Ok(AudioChannel::Sunsoft5B),
+            _ => Err("Unknown audio channel, expected pulse1, pulse2, triangle, noise, dmc, vrc6, vrc7, fds, mmc5, n163 or 5b"),
+        }
+    }
+}
+
+// Comma separated channel names, like "noise,dmc"
+pub fn parse_channel_list(list: &str) -> Result<Vec<AudioChannel>, &'static str> {
+    list.split(',').filter(|name| !name.trim().is_empty()).map(|name| AudioChannel::from_name(name.trim())).collect()
+}
+
+// Comma separated channel=volume pairs, like "pulse1=0.5,dmc=2"
+pub fn parse_channel_volumes(list: &str) -> Result<Vec<(AudioChannel, f32)>, &'static str> {
+    list.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| {
+        let (name, volume) = pair.split_once('=').ok_or("Channel volume should look like name=volume")?;
+        let volume = volume.trim().parse::<f32>().ok().filter(|volume| *volume >= 0.0).ok_or("Channel volume should be a non-negative number")?;
+        Ok((AudioChannel::from_name(name.trim())?, volume))
+    }).collect()
+}
+
+// Nonlinear mixer, channel outputs are scaled by their gains first
+fn mix(outputs: [u8; 5], gains: [f32; 5]) -> f32 {
+    let [pulse_1, pulse_2, triangle, noise, dmc] = std::array::from_fn(|channel| outputs[channel] as f32 * gains[channel]);
+    let pulse_sum = pulse_1 + pulse_2;
+    let pulse = if pulse_sum == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulse_sum + 100.0) };
+    let tnd_sum = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+    let tnd = if tnd_sum == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd_sum + 100.0) };
+    pulse + tnd
+}
+
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: DMC,
+    frame_counter: FrameCounter,
+    expansion: Vec<Box<dyn ExpansionAudio>>,
+    cycle: u64,
+    channel_gains: [f32; CHANNEL_COUNT], // volume, or 0 for muted channels
+    last_outputs: [u8; 5],
+    last_expansion_output: f32,
+    last_level: f32,
+    blip: BlipBuffer,
+    filters: FilterChain,
+}
+
+impl APU {
+    pub fn new(region: Region) -> Self {
+        let settings = Settings::default();
+        let mut apu = Self {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: DMC::new(region),
+            frame_counter: FrameCounter::new(region),
+            expansion: vec![],
+            cycle: 0,
+            channel_gains: [1.0; CHANNEL_COUNT],
+            last_outputs: [0; 5],
+            last_expansion_output: 0.0,
+            last_level: 0.0,
+            blip: BlipBuffer::new(region.get_cpu_clock_rate(), settings.sample_rate),
+            filters: FilterChain::new(settings.sample_rate),
+        };
+        apu.settings_updated(&settings);
+        apu
+    }
+
+    // Cartridge sound chip, its registers are handled by the APU too
+    pub fn add_expansion_audio(&mut self, chip: ExpansionChip, region: Region) {
+        self.expansion.push(create_expansion_audio(chip, region));
+    }
+
+    // Memory hooks expansion chips need, with APU as their handler
+    pub fn get_expansion_hooks(&self) -> Vec<(MemoryOperation, MemoryRegion)> {
+        self.expansion.iter().flat_map(|chip| {
+            let writes = chip.get_write_registers().into_iter().map(|registers| (MemoryOperation::Write, registers));
+            let reads = chip.get_read_registers().into_iter().map(|registers| (MemoryOperation::Read, registers));
+            writes.chain(reads).collect::<Vec<_>>()
+        }).map(|(operation, (start, length))| (operation, MemoryRegion::new(start as usize, length as usize))).collect()
+    }
+
+    // Called once per CPU cycle, after the CPU. DMC sample fetches go through CPU's DMA unit.
+    pub fn tick(&mut self, cpu: &mut CPU) {
+        self.cycle += 1;
+        let clocks = self.frame_counter.clock();
+        if clocks.quarter {
+            self.pulse_1.envelope.clock();
+            self.pulse_2.envelope.clock();
+            self.noise.envelope.clock();
+            self.triangle.clock_linear_counter();
+        }
+        if clocks.half {
+            self.pulse_1.length.clock();
+            self.pulse_2.length.clock();
+            self.triangle.length.clock();
+            self.noise.length.clock();
+            self.pulse_1.clock_sweep();
+            self.pulse_2.clock_sweep();
+        }
+
+        if self.cycle.is_multiple_of(2) {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if let Some(sample) = cpu.take_dmc_sample() {
+            self.dmc.receive_sample(sample);
+        }
+        if let Some(address) = self.dmc.take_dma_request() {
+            cpu.request_dmc_dma(address);
+        }
+        for chip in &mut self.expansion {
+            chip.clock();
+        }
+
+        self.update_output();
+    }
+
+    fn get_channel_outputs(&self) -> [u8; 5] {
+        [
+            self.pulse_1.get_output(),
+            self.pulse_2.get_output(),
+            self.triangle.get_output(),
+            self.noise.get_output(),
+            self.dmc.get_output(),
+        ]
+    }
+
+    // Expansion chips are mixed linearly on top of APU
+    fn get_expansion_output(&self) -> f32 {
+        self.expansion.iter().map(|chip| chip.get_output() * self.channel_gains[chip.get_channel() as usize]).sum()
+    }
+
+    fn get_level(&self) -> f32 {
+        mix(self.last_outputs, self.channel_gains[..5].try_into().unwrap()) + self.last_expansion_output
+    }
+
+    // Mixer only runs when some channel changed, blip buffer gets the difference
+    fn update_output(&mut self) {
+        let outputs = self.get_channel_outputs();
+        let expansion_output = self.get_expansion_output();
+        if outputs != self.last_outputs || expansion_output != self.last_expansion_output {
+            self.last_outputs = outputs;
+            self.last_expansion_output = expansion_output;
+            self.set_level(self.get_level());
+        }
+        self.blip.clock();
+    }
+
+    fn set_level(&mut self, level: f32) {
+        self.blip.add_delta(level - self.last_level);
+        self.last_level = level;
+    }
+
+    // Samples produced since last call, at sample rate from settings
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.blip.read_samples().into_iter()
+            .map(|sample| (self.filters.process(sample) * i16::MAX as f32).clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect()
+    }
+
+    // Frame counter and DMC share the CPU IRQ line
+    pub fn get_irq_output(&self) -> bool {
+        self.frame_counter.irq_flag || self.dmc.irq_flag
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let status = self.pulse_1.length.is_active() as u8
+            | (self.pulse_2.length.is_active() as u8) << 1
+            | (self.triangle.length.is_active() as u8) << 2
+            | (self.noise.length.is_active() as u8) << 3
+            | (self.dmc.is_active() as u8) << 4
+            | (self.frame_counter.irq_flag as u8) << 6
+            | (self.dmc.irq_flag as u8) << 7;
+        self.frame_counter.irq_flag = false; // reading acknowledges frame IRQ, but not DMC one
+        status
+    }
+
+    fn write_enable(&mut self, value: u8) {
+        self.pulse_1.length.set_enabled(value & 0x01 != 0);
+        self.pulse_2.length.set_enabled(value & 0x02 != 0);
+        self.triangle.length.set_enabled(value & 0x04 != 0);
+        self.noise.length.set_enabled(value & 0x08 != 0);
+        self.dmc.set_enabled(value & 0x10 != 0);
+    }
+}
+
+// $4000-$4013, $4015 and $4017 writes, $4015 reads
+impl MemoryHandler for APU {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4015 => self.read_status(),
+            _ => self.expansion.iter_mut()
+                .find(|chip| is_register(&chip.get_read_registers(), address))
+                .map_or(0, |chip| chip.read(address)),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let register = address & 0x03;
+        match address {
+            0x4000..=0x4003 => self.pulse_1.write(register, value),
+            0x4004..=0x4007 => self.pulse_2.write(register, value),
+            0x4008..=0x400B => self.triangle.write(register, value),
+            0x400C..=0x400F => self.noise.write(register, value),
+            0x4010..=0x4013 => self.dmc.write(register, value),
+            0x4015 => self.write_enable(value),
+            0x4017 => self.frame_counter.write(value, self.cycle % 2 == 1),
+            _ => for chip in &mut self.expansion {
+                if is_register(&chip.get_write_registers(), address) {
+                    chip.write(address, value);
+                }
+            },
+        }
+    }
+}
+
+impl SettingsObserver for APU {
+    fn settings_updated(&mut self, settings: &Settings) {
+        self.blip.set_rates(1_000_000_000.0 / settings.clock_delta, settings.sample_rate);
+        self.filters.set_sample_rate(settings.sample_rate);
+        self.channel_gains = std::array::from_fn(|channel| {
+            if settings.muted_channels[channel] { 0.0 } else { settings.channel_volumes[channel] }
+        });
+        self.last_expansion_output = self.get_expansion_output();
+        self.set_level(self.get_level());
+    }
+}
+
+// Output pipeline (blip buffer and filters) isn't part of emulator state
+impl Snapshot for APU {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.triangle.save_state(state);
+        self.noise.save_state(state);
+        self.dmc.save_state(state);
+        self.frame_counter.save_state(state);
+        for chip in &self.expansion {
+            chip.save_state(state);
+        }
+        state.write_u64(self.cycle);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.triangle.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.dmc.load_state(state)?;
+        self.frame_counter.load_state(state)?;
+        for chip in &mut self.expansion {
+            chip.load_state(state)?;
+        }
+        self.cycle = state.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod audio_processor_tests {
+    use crate::{ memory::MEMORY_SIZE, snapshot, MEM };
+    use super::*;
+
+    fn run(apu: &mut APU, cpu: &mut CPU, cycles: usize) {
+        for _ in 0..cycles {
+            apu.tick(cpu);
+        }
+    }
+
+    #[test]
+    fn test_mixer_levels() {
+        assert_eq!(mix([0; 5], [1.0; 5]), 0.0);
+        assert!((mix([15, 15, 0, 0, 0], [1.0; 5]) - 0.2585).abs() < 0.001);
+        assert!((mix([0, 0, 15, 15, 127], [1.0; 5]) - 0.7415).abs() < 0.001);
+        assert_eq!(mix([15, 15, 0, 0, 0], [0.5, 0.5, 1.0, 1.0, 1.0]), mix([15, 0, 0, 0, 0], [1.0; 5]));
+    }
+
+    #[test]
+    fn test_channel_lists() {
+        assert_eq!(parse_channel_list("noise, DMC"), Ok(vec![AudioChannel::Noise, AudioChannel::DMC]));
+        assert_eq!(parse_channel_list(""), Ok(vec![]));
+        assert!(parse_channel_list("square").is_err());
+        assert_eq!(parse_channel_volumes("pulse1=0.5,triangle=2"), Ok(vec![(AudioChannel::Pulse1, 0.5), (AudioChannel::Triangle, 2.0)]));
+        assert!(parse_channel_volumes("pulse1").is_err());
+        assert!(parse_channel_volumes("pulse1=-1").is_err());
+        assert_eq!(parse_channel_list("n163,fme7,vrc7"), Ok(vec![AudioChannel::Namco163, AudioChannel::Sunsoft5B, AudioChannel::VRC7]));
+    }
+
+    #[test]
+    fn test_expansion_audio_is_mixed_in() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        apu.add_expansion_audio(ExpansionChip::VRC6 { swapped_lines: false }, Region::NTSC);
+        let hooks = apu.get_expansion_hooks();
+        assert_eq!(hooks.len(), 3);
+        assert!(hooks.iter().all(|(operation, _)| *operation == MemoryOperation::Write));
+        assert!(hooks[1].1.inside_region(0xA002));
+
+        run(&mut apu, &mut cpu, 1); // triangle starts at 15, so APU isn't at 0 either
+        let silent_level = apu.get_level();
+        apu.write(0x9000, 0x8F); // constant volume 15
+        apu.write(0x9002, 0x80);
+        run(&mut apu, &mut cpu, 1);
+        assert!((apu.get_level() - silent_level - 15.0 * expansion::PULSE_STEP).abs() < 0.0001);
+
+        let mut settings = Settings::default();
+        settings.muted_channels[AudioChannel::VRC6 as usize] = true;
+        apu.settings_updated(&settings);
+        assert_eq!(apu.get_level(), silent_level);
+    }
+
+    #[test]
+    fn test_status_register() {
+        let mut apu = APU::new(Region::NTSC);
+        apu.write(0x4015, 0x0F);
+        apu.write(0x4003, 0x08);
+        apu.write(0x400F, 0x08);
+        assert_eq!(apu.read(0x4015), 0b0000_1001);
+
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.read(0x4015), 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        run(&mut apu, &mut cpu, 29829);
+        assert!(apu.get_irq_output());
+
+        assert_eq!(apu.read(0x4015) & 0x40, 0x40);
+        assert_eq!(apu.read(0x4015) & 0x40, 0);
+        assert!(!apu.get_irq_output());
+    }
+
+    #[test]
+    fn test_dmc_fetches_through_cpu() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        let mut memory = MEM::new(MEMORY_SIZE);
+        memory.data[0x0200..0x0300].fill(0xEA); // NOP
+        cpu.store_pc(0x0200);
+        apu.write(0x4013, 0x00); // 1 byte sample at $C000
+        apu.write(0x4015, 0x10);
+        assert_eq!(apu.read(0x4015), 0x10);
+
+        for _ in 0..8 {
+            cpu.tick(&mut memory).unwrap();
+            apu.tick(&mut cpu);
+        }
+        assert_eq!(apu.read(0x4015), 0x00);
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        apu.settings_updated(&Settings { sample_rate: 48000, clock_delta: Region::NTSC.get_cpu_clock_delta(), ..Settings::default() });
+        play_pulse_1(&mut apu);
+        run(&mut apu, &mut cpu, Region::NTSC.get_cpu_clock_rate() as usize / 10);
+
+        let samples = apu.take_samples();
+        assert!(samples.len().abs_diff(4800) <= 1);
+        assert!(samples.iter().any(|sample| *sample > 0));
+        assert!(apu.take_samples().is_empty());
+    }
+
+    fn play_pulse_1(apu: &mut APU) {
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0xFD);
+        apu.write(0x4003, 0x00); // ~440 Hz, constant volume 15
+    }
+
+    #[test]
+    fn test_muted_channel_is_silent() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        let mut settings = Settings { clock_delta: Region::NTSC.get_cpu_clock_delta(), ..Settings::default() };
+        settings.muted_channels[AudioChannel::Pulse1 as usize] = true;
+        apu.settings_updated(&settings);
+        play_pulse_1(&mut apu);
+        run(&mut apu, &mut cpu, 100_000);
+
+        let samples = apu.take_samples();
+        // power-on step of triangle output fades away first
+        assert!(samples[samples.len() / 2..].iter().all(|sample| sample.abs() < 10));
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut apu = APU::new(Region::NTSC);
+        let mut cpu = CPU::new();
+        apu.write(0x4015, 0x0F);
+        apu.write(0x4000, 0x3F);
+        apu.write(0x4003, 0x08);
+        run(&mut apu, &mut cpu, 1000);
+        let state = snapshot::save_all(&[&apu]);
+
+        let mut restored = APU::new(Region::NTSC);
+        snapshot::load_all(&mut [&mut restored], &state).unwrap();
+        assert_eq!(snapshot::save_all(&[&restored]), state);
+        run(&mut apu, &mut cpu, 1000);
+        run(&mut restored, &mut cpu, 1000);
+        assert_eq!(snapshot::save_all(&[&restored]), snapshot::save_all(&[&apu]));
+    }
+}
@@ -0,0 +1,136 @@
+use std::f64::consts::PI;
+
+// Sub-sample positions a step can start at
+const PHASES: usize = 32;
+// Taps of band-limited impulse, also output latency is half of it
+const KERNEL_WIDTH: usize = 16;
+// Fraction of Nyquist frequency left in, rest of the band is used by kernel's roll-off
+const CUTOFF: f64 = 0.9;
+
+// Band-limited step synthesis. Signal comes in as amplitude changes at CPU cycle precision, each change is
+// spread over a few output samples as windowed sinc impulse. Output is running sum of impulses, so every step
+// becomes a band-limited step and nothing above Nyquist frequency gets folded back as aliasing.
+pub struct BlipBuffer {
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    samples_per_clock: f64,
+    time: f64, // in output samples since start of buffer
+    impulses: Vec<f32>,
+    integrator: f32,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        Self {
+            kernel: create_kernel(),
+            samples_per_clock: sample_rate as f64 / clock_rate,
+            time: 0.0,
+            impulses: vec![0.0; KERNEL_WIDTH],
+            integrator: 0.0,
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: u32) {
+        self.samples_per_clock = sample_rate as f64 / clock_rate;
+    }
+
+    // Amplitude change at current time
+    pub fn add_delta(&mut self, delta: f32) {
+        let position = self.time as usize;
+        let phase = ((self.time.fract() * PHASES as f64) as usize).min(PHASES - 1);
+        for (impulse, tap) in self.impulses[position..position + KERNEL_WIDTH].iter_mut().zip(&self.kernel[phase]) {
+            *impulse += delta * tap;
+        }
+    }
+
+    pub fn clock(&mut self) {
+        self.time += self.samples_per_clock;
+        let needed = self.time as usize + KERNEL_WIDTH + 1;
+        if self.impulses.len() < needed {
+            self.impulses.resize(needed, 0.0);
+        }
+    }
+
+    // Samples before current time can't change anymore
+    pub fn read_samples(&mut self) -> Vec<f32> {
+        let available = self.time as usize;
+        let samples = self.impulses.drain(..available).map(|impulse| {
+            self.integrator += impulse;
+            self.integrator
+        }).collect();
+        self.impulses.resize(self.impulses.len().max(KERNEL_WIDTH + 1), 0.0);
+        self.time -= available as f64;
+        samples
+    }
+}
+
+// One impulse per phase, each sums to 1 so steps keep their height
+fn create_kernel() -> Vec<[f32; KERNEL_WIDTH]> {
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let mut taps = [0.0; KERNEL_WIDTH];
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let x = i as f64 - (KERNEL_WIDTH / 2) as f64 + 1.0 - offset;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * CUTOFF * x).sin() / (PI * CUTOFF * x) };
+            // Blackman window over kernel width
+            let position = (x + KERNEL_WIDTH as f64 / 2.0) / KERNEL_WIDTH as f64;
+            let window = 0.42 - 0.5 * (2.0 * PI * position).cos() + 0.08 * (4.0 * PI * position).cos();
+            *tap = (sinc * window) as f32;
+        }
+        let sum: f32 = taps.iter().sum();
+        taps.map(|tap| tap / sum)
+    }).collect()
+}
+
+#[cfg(test)]
+mod blip_buffer_tests {
+    use super::*;
+
+    #[test]
+    fn test_step_settles_at_its_height() {
+        let mut blip = BlipBuffer::new(1024.0, 128);
+        for cycle in 0..1024 {
+            if cycle == 205 {
+                blip.add_delta(1.0);
+            }
+            blip.clock();
+        }
+        let samples = blip.read_samples();
+
+        assert_eq!(samples.len(), 128);
+        assert!(samples[..10].iter().all(|sample| *sample == 0.0));
+        assert!(samples[40..].iter().all(|sample| (sample - 1.0).abs() < 0.001));
+    }
+
+    #[test]
+    fn test_samples_come_out_in_pieces() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 44100);
+        let mut count = 0;
+        for _ in 0..10 {
+            for _ in 0..178_977 {
+                blip.clock();
+            }
+            count += blip.read_samples().len();
+        }
+        assert!(count.abs_diff(44100) <= 1);
+    }
+
+    // Square wave above Nyquist frequency would alias into audible band if it was just sampled
+    #[test]
+    fn test_high_frequency_is_attenuated() {
+        let mut blip = BlipBuffer::new(1_789_773.0, 44100);
+        let mut level = 0.0;
+        for cycle in 0..178_977 {
+            if cycle % 20 == 0 { // ~45 kHz square wave, naive sampling would turn it into loud ~600 Hz tone
+                let new_level = if level == 0.0 { 1.0 } else { 0.0 };
+                blip.add_delta(new_level - level);
+                level = new_level;
+            }
+            blip.clock();
+        }
+        let samples = blip.read_samples();
+        let settled = &samples[100..];
+        let (min, max) = settled.iter().fold((f32::MAX, f32::MIN), |(min, max), sample| (min.min(*sample), max.max(*sample)));
+
+        assert!(max - min < 0.3, "peak to peak {}", max - min);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::{ region::Region, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+// In CPU cycles
+const NTSC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const PAL_RATES: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+// Delta modulation channel, $4010-$4013. Plays 1 bit deltas fetched from CPU memory by DMC DMA.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub struct DMC {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    looped: bool,
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    dma_requested: bool,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq_flag: bool,
+}
+
+impl DMC {
+    pub fn new(region: Region) -> Self {
+        let rates = match region {
+            Region::NTSC | Region::Dendy => &NTSC_RATES,
+            Region::PAL => &PAL_RATES,
+        };
+        Self {
+            rates,
+            irq_enabled: false,
+            looped: false,
+            timer_period: rates[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            dma_requested: false,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+                self.looped = value & 0x40 != 0;
+                self.timer_period = self.rates[(value & 0x0F) as usize];
+            },
+            1 => self.output_level = value & 0x7F,
+            2 => self.sample_address = 0xC000 + value as u16 * 64,
+            _ => self.sample_length = value as u16 * 16 + 1,
+        }
+    }
+
+    // $4015 bit 4, enabling only restarts sample that has already finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Address to fetch when sample buffer runs empty, asked for only once until byte arrives
+    pub fn take_dma_request(&mut self) -> Option<u16> {
+        if self.sample_buffer.is_some() || self.bytes_remaining == 0 || self.dma_requested {
+            return None;
+        }
+        self.dma_requested = true;
+        Some(self.current_address)
+    }
+
+    pub fn receive_sample(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        self.dma_requested = false;
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looped {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn get_output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+impl Snapshot for DMC {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.irq_enabled);
+        state.write_bool(self.looped);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.output_level);
+        state.write_u16(self.sample_address);
+        state.write_u16(self.sample_length);
+        state.write_u16(self.current_address);
+        state.write_u16(self.bytes_remaining);
+        state.write_option_u8(self.sample_buffer);
+        state.write_bool(self.dma_requested);
+        state.write_u8(self.shift_register);
+        state.write_u8(self.bits_remaining);
+        state.write_bool(self.silence);
+        state.write_bool(self.irq_flag);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.irq_enabled = state.read_bool()?;
+        self.looped = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.output_level = state.read_u8()?;
+        self.sample_address = state.read_u16()?;
+        self.sample_length = state.read_u16()?;
+        self.current_address = state.read_u16()?;
+        self.bytes_remaining = state.read_u16()?;
+        self.sample_buffer = state.read_option_u8()?;
+        self.dma_requested = state.read_bool()?;
+        self.shift_register = state.read_u8()?;
+        self.bits_remaining = state.read_u8()?;
+        self.silence = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dmc_tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_fetching() {
+        let mut dmc = DMC::new(Region::NTSC);
+        dmc.write(2, 0xFF); // $FFC0
+        dmc.write(3, 0x04); // 65 bytes, so address wraps around
+        dmc.set_enabled(true);
+
+        assert_eq!(dmc.take_dma_request(), Some(0xFFC0));
+        assert_eq!(dmc.take_dma_request(), None); // already waiting for DMA
+        dmc.receive_sample(0x00);
+        assert_eq!(dmc.take_dma_request(), None); // buffer is full
+        for _ in 0..63 {
+            dmc.sample_buffer = None;
+            assert!(dmc.take_dma_request().is_some());
+            dmc.receive_sample(0x00);
+        }
+        dmc.sample_buffer = None;
+        assert_eq!(dmc.take_dma_request(), Some(0x8000));
+    }
+
+    #[test]
+    fn test_irq_at_sample_end() {
+        let mut dmc = DMC::new(Region::NTSC);
+        dmc.write(0, 0x80);
+        dmc.set_enabled(true); // 1 byte sample
+        dmc.take_dma_request();
+        dmc.receive_sample(0x00);
+        assert!(dmc.irq_flag);
+        assert!(!dmc.is_active());
+
+        dmc.set_enabled(true); // acknowledges IRQ
+        assert!(!dmc.irq_flag);
+    }
+
+    #[test]
+    fn test_output_deltas() {
+        let mut dmc = DMC::new(Region::NTSC);
+        dmc.write(0, 0x0F); // 54 cycles per bit
+        dmc.write(1, 0x40);
+        dmc.set_enabled(true);
+        dmc.take_dma_request();
+        dmc.receive_sample(0b0000_0111);
+
+        for _ in 0..8 * 54 { // empty shift register has to run out first
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.get_output(), 0x40);
+        for _ in 0..8 * 54 {
+            dmc.clock_timer();
+        }
+        assert_eq!(dmc.get_output(), 0x40 + 3*2 - 5*2);
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{ memory::{ mappers::ExpansionChip, MemoryHandler }, region::Region, snapshot::Snapshot };
+
+use super::AudioChannel;
+use fds::FDSAudio;
+use mmc5::MMC5Audio;
+use namco163::Namco163Audio;
+use sunsoft5b::Sunsoft5BAudio;
+use vrc6::VRC6Audio;
+use vrc7::VRC7Audio;
+
+mod fds;
+mod mmc5;
+mod namco163;
+mod sunsoft5b;
+mod vrc6;
+mod vrc7;
+
+// Mixer output for one step of APU pulse volume, expansion chips are scaled relative to it.
+// Pulse at full volume mixes to 95.88 / (8128 / 15 + 100).
+pub const PULSE_STEP: f32 = 0.1494 / 15.0;
+
+// Sound chip on cartridge. Its registers go through the APU, which clocks it and adds its output to the mix.
+pub trait ExpansionAudio: MemoryHandler + Snapshot {
+    fn get_channel(&self) -> AudioChannel;
+    // Registers as (start, length)
+    fn get_write_registers(&self) -> Vec<(u16, u16)>;
+    fn get_read_registers(&self) -> Vec<(u16, u16)> {
+        vec![]
+    }
+    // Clocked every CPU cycle
+    fn clock(&mut self);
+    // In APU mixer units, see PULSE_STEP
+    fn get_output(&self) -> f32;
+}
+
+pub fn create_expansion_audio(chip: ExpansionChip, region: Region) -> Box<dyn ExpansionAudio> {
+    match chip {
+        ExpansionChip::VRC6 { swapped_lines } => Box::new(VRC6Audio::new(swapped_lines)),
+        ExpansionChip::VRC7 => Box::new(VRC7Audio::new()),
+        ExpansionChip::FDS => Box::new(FDSAudio::new()),
+        ExpansionChip::MMC5 => Box::new(MMC5Audio::new(region)),
+        ExpansionChip::Namco163 => Box::new(Namco163Audio::new()),
+        ExpansionChip::Sunsoft5B => Box::new(Sunsoft5BAudio::new()),
+    }
+}
+
+pub fn is_register(registers: &[(u16, u16)], address: u16) -> bool {
+    registers.iter().any(|(start, length)| (*start..start + length).contains(&address))
+}
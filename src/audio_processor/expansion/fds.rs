@@ -0,0 +1,392 @@
+use crate::{ audio_processor::AudioChannel, memory::MemoryHandler, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// Full volume wave at full amplitude is about 2.4 times as loud as APU pulse
+const FULL_VOLUME: f32 = PULSE_STEP * 15.0 * 2.4;
+// Master volume is 2/2, 2/3, 2/4 or 2/5, in 36ths
+const MASTER_VOLUMES: [u32; 4] = [36, 24, 17, 14];
+const MAX_LEVEL: u32 = 63 * 32 * 36;
+// What each modulation table entry adds to modulation counter, entry 4 resets it instead
+const MODULATION_STEPS: [i32; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+const MODULATION_RESET: u8 = 4;
+
+// Volume and modulation envelopes work the same. Gain moves by one towards 0 or 32, but can be set up to 63.
+#[derive(Debug, Default)]
+struct FdsEnvelope {
+    speed: u8,
+    increase: bool,
+    disabled: bool, // gain is set directly
+    gain: u8,
+    timer: u32,
+}
+
+impl FdsEnvelope {
+    fn write(&mut self, value: u8, master_speed: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset_timer(master_speed);
+    }
+
+    fn reset_timer(&mut self, master_speed: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled || master_speed == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.reset_timer(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+// Famicom Disk System sound: one 64 step wavetable channel with 6 bit samples, its pitch bent by a second
+// 64 step table of modulation deltas. Wave RAM is at $4040-$407F, other registers at $4080-$408A.
+#[allow(clippy::upper_case_acronyms)]
+pub struct FDSAudio {
+    wave_table: [u8; 64],
+    wave_write_enabled: bool, // also holds output at its last level
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_frequency: u16,
+    wave_accumulator: u16, // wave steps when it overflows
+    wave_position: u8,
+    volume: FdsEnvelope,
+    master_volume: u8,
+    master_envelope_speed: u8,
+    output_level: u32,
+    modulation: FdsEnvelope,
+    modulation_table: [u8; 64],
+    modulation_position: u8,
+    modulation_halted: bool,
+    modulation_frequency: u16,
+    modulation_accumulator: u16,
+    modulation_counter: i8, // 7 bit signed
+}
+
+impl FDSAudio {
+    pub fn new() -> Self {
+        Self {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            envelopes_halted: false,
+            wave_frequency: 0,
+            wave_accumulator: 0,
+            wave_position: 0,
+            volume: FdsEnvelope::default(),
+            master_volume: 0,
+            master_envelope_speed: 0xE8, // BIOS sets this at boot, NSF files count on it too
+            output_level: 0,
+            modulation: FdsEnvelope::default(),
+            modulation_table: [0; 64],
+            modulation_position: 0,
+            modulation_halted: true,
+            modulation_frequency: 0,
+            modulation_accumulator: 0,
+            modulation_counter: 0,
+        }
+    }
+
+    // Counter times gain is rounded the way hardware does it and then scales wave frequency
+    fn get_pitch_offset(&self) -> i32 {
+        if self.modulation_halted {
+            return 0;
+        }
+        let counter = self.modulation_counter as i32;
+        let mut offset = counter * self.modulation.gain as i32;
+        let remainder = offset & 0x0F;
+        offset >>= 4;
+        if remainder > 0 && offset & 0x80 == 0 {
+            offset += if counter < 0 { -1 } else { 2 };
+        }
+        if offset >= 192 {
+            offset -= 256;
+        } else if offset < -64 {
+            offset += 256;
+        }
+        let offset = self.wave_frequency as i32 * offset;
+        (offset >> 6) + (offset & 0x3F >= 32) as i32
+    }
+
+    fn set_modulation_counter(&mut self, value: i32) {
+        self.modulation_counter = ((value << 1) as i8) >> 1; // wraps to 7 bits
+    }
+
+    fn clock_modulation(&mut self) {
+        if self.modulation_halted || self.modulation_frequency == 0 {
+            return;
+        }
+        let (accumulator, overflow) = self.modulation_accumulator.overflowing_add(self.modulation_frequency);
+        self.modulation_accumulator = accumulator;
+        if !overflow {
+            return;
+        }
+        let step = self.modulation_table[self.modulation_position as usize];
+        if step == MODULATION_RESET {
+            self.set_modulation_counter(0);
+        } else {
+            self.set_modulation_counter(self.modulation_counter as i32 + MODULATION_STEPS[step as usize]);
+        }
+        self.modulation_position = (self.modulation_position + 1) % 64;
+    }
+
+    fn clock_wave(&mut self) {
+        if self.wave_halted || self.wave_write_enabled {
+            return;
+        }
+        let pitch = self.wave_frequency as i32 + self.get_pitch_offset();
+        if pitch <= 0 {
+            return;
+        }
+        let (accumulator, overflow) = self.wave_accumulator.overflowing_add(pitch as u16);
+        self.wave_accumulator = accumulator;
+        if overflow {
+            self.wave_position = (self.wave_position + 1) % 64;
+        }
+    }
+}
+
+impl MemoryHandler for FDSAudio {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x4040..=0x407F => self.wave_table[address as usize & 0x3F],
+            0x4090 => self.volume.gain,
+            0x4092 => self.modulation.gain,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write_enabled => self.wave_table[address as usize & 0x3F] = value & 0x3F,
+            0x4080 => self.volume.write(value, self.master_envelope_speed),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00FF) | (value as u16 & 0x0F) << 8;
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.envelopes_halted {
+                    self.volume.reset_timer(self.master_envelope_speed);
+                    self.modulation.reset_timer(self.master_envelope_speed);
+                }
+            },
+            0x4084 => self.modulation.write(value, self.master_envelope_speed),
+            0x4085 => self.set_modulation_counter(value as i32),
+            0x4086 => self.modulation_frequency = (self.modulation_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.modulation_frequency = (self.modulation_frequency & 0x00FF) | (value as u16 & 0x0F) << 8;
+                self.modulation_halted = value & 0x80 != 0;
+                if self.modulation_halted {
+                    self.modulation_accumulator = 0;
+                }
+            },
+            // table can only be written while modulation is halted, each write fills two entries
+            0x4088 if self.modulation_halted => {
+                let position = self.modulation_position as usize;
+                self.modulation_table[position] = value & 0x07;
+                self.modulation_table[(position + 1) % 64] = value & 0x07;
+                self.modulation_position = (self.modulation_position + 2) % 64;
+            },
+            0x4089 => {
+                self.master_volume = value & 0x03;
+                self.wave_write_enabled = value & 0x80 != 0;
+            },
+            0x408A => self.master_envelope_speed = value,
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for FDSAudio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::FDS
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x4040, 0x40), (0x4080, 0x0B)]
+    }
+
+    fn get_read_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x4040, 0x40), (0x4090, 0x01), (0x4092, 0x01)]
+    }
+
+    fn clock(&mut self) {
+        if !self.wave_halted && !self.envelopes_halted {
+            self.volume.clock(self.master_envelope_speed);
+            self.modulation.clock(self.master_envelope_speed);
+        }
+        self.clock_modulation();
+        self.clock_wave();
+        if !self.wave_write_enabled {
+            let gain = self.volume.gain.min(32) as u32;
+            self.output_level = self.wave_table[self.wave_position as usize] as u32 * gain * MASTER_VOLUMES[self.master_volume as usize];
+        }
+    }
+
+    fn get_output(&self) -> f32 {
+        self.output_level as f32 / MAX_LEVEL as f32 * FULL_VOLUME
+    }
+}
+
+impl Snapshot for FdsEnvelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.speed);
+        state.write_bool(self.increase);
+        state.write_bool(self.disabled);
+        state.write_u8(self.gain);
+        state.write_u64(self.timer as u64);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.speed = state.read_u8()?;
+        self.increase = state.read_bool()?;
+        self.disabled = state.read_bool()?;
+        self.gain = state.read_u8()?;
+        self.timer = state.read_u64()? as u32;
+        Ok(())
+    }
+}
+
+impl Snapshot for FDSAudio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.wave_table);
+        state.write_bool(self.wave_write_enabled);
+        state.write_bool(self.wave_halted);
+        state.write_bool(self.envelopes_halted);
+        state.write_u16(self.wave_frequency);
+        state.write_u16(self.wave_accumulator);
+        state.write_u8(self.wave_position);
+        self.volume.save_state(state);
+        state.write_u8(self.master_volume);
+        state.write_u8(self.master_envelope_speed);
+        state.write_u64(self.output_level as u64);
+        self.modulation.save_state(state);
+        state.write_bytes(&self.modulation_table);
+        state.write_u8(self.modulation_position);
+        state.write_bool(self.modulation_halted);
+        state.write_u16(self.modulation_frequency);
+        state.write_u16(self.modulation_accumulator);
+        state.write_u8(self.modulation_counter as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.wave_table)?;
+        self.wave_write_enabled = state.read_bool()?;
+        self.wave_halted = state.read_bool()?;
+        self.envelopes_halted = state.read_bool()?;
+        self.wave_frequency = state.read_u16()?;
+        self.wave_accumulator = state.read_u16()?;
+        self.wave_position = state.read_u8()?;
+        self.volume.load_state(state)?;
+        self.master_volume = state.read_u8()?;
+        self.master_envelope_speed = state.read_u8()?;
+        self.output_level = state.read_u64()? as u32;
+        self.modulation.load_state(state)?;
+        state.read_bytes(&mut self.modulation_table)?;
+        self.modulation_position = state.read_u8()?;
+        self.modulation_halted = state.read_bool()?;
+        self.modulation_frequency = state.read_u16()?;
+        self.modulation_accumulator = state.read_u16()?;
+        self.modulation_counter = state.read_u8()? as i8;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod fds_tests {
+    use super::*;
+
+    // Square wave at full volume, wave steps every 32 cycles
+    fn create_square_wave() -> FDSAudio {
+        let mut fds = FDSAudio::new();
+        fds.write(0x4089, 0x80);
+        for i in 0..64 {
+            fds.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        fds.write(0x4089, 0x00);
+        fds.write(0x4080, 0x80 | 32);
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x08);
+        fds
+    }
+
+    #[test]
+    fn test_wave_playback() {
+        let mut fds = create_square_wave();
+        let high_steps = (0..64).filter(|_| {
+            for _ in 0..32 {
+                fds.clock();
+            }
+            fds.get_output() > 0.0
+        }).count();
+        assert_eq!(high_steps, 32);
+        assert_eq!(fds.wave_position, 0);
+        assert!((fds.get_output() - FULL_VOLUME).abs() < 0.0001);
+
+        fds.write(0x4089, 0x83); // writing wave holds output, master volume 2/5
+        fds.clock();
+        assert_eq!(fds.wave_position, 0);
+        fds.write(0x4089, 0x03);
+        fds.clock();
+        assert!((fds.get_output() - FULL_VOLUME * 14.0 / 36.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_volume_envelope() {
+        let mut fds = create_square_wave();
+        fds.write(0x408A, 0x01);
+        fds.write(0x4080, 0x80);
+        fds.write(0x4080, 0x40); // increase at speed 0, every 8 cycles
+        for _ in 0..8 * 40 {
+            fds.clock();
+        }
+        assert_eq!(fds.read(0x4090), 32);
+
+        fds.write(0x4080, 0x80 | 50); // gain set directly can go past 32
+        assert_eq!(fds.read(0x4090), 50);
+    }
+
+    #[test]
+    fn test_modulation() {
+        let mut fds = create_square_wave();
+        fds.write(0x4087, 0x80);
+        for _ in 0..32 {
+            fds.write(0x4088, 0x01); // counter goes up by one every step
+        }
+        fds.write(0x4084, 0x80 | 32);
+        fds.write(0x4085, 0x7F); // -1
+        fds.write(0x4086, 0x00);
+        fds.write(0x4087, 0x08); // modulation steps every 32 cycles
+        assert_eq!(fds.get_pitch_offset(), -(0x800 * 2 / 64));
+        for _ in 0..32 * 3 {
+            fds.clock();
+        }
+        assert_eq!(fds.modulation_counter, 2);
+        assert_eq!(fds.get_pitch_offset(), 0x800 * 4 / 64);
+
+        fds.write(0x4088, 0x04); // ignored while modulation runs
+        fds.write(0x4085, 0x3F);
+        for _ in 0..32 {
+            fds.clock();
+        }
+        assert_eq!(fds.modulation_counter, -64); // wrapped
+    }
+}
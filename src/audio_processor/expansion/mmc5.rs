@@ -0,0 +1,166 @@
+use crate::{
+    audio_processor::{ pulse::Pulse, AudioChannel },
+    memory::MemoryHandler,
+    region::Region,
+    snapshot::{ Snapshot, StateReader, StateWriter },
+};
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// Raw PCM is mixed about as loud as DMC at half its value
+const PCM_STEP: f32 = 0.42 / 255.0;
+
+// Nintendo MMC5, two APU pulses without sweep at $5000-$5007 and 8 bit PCM at $5010-$5011.
+// PCM read mode needs the mapper to see reads from $8000-$BFFF, so only write mode is here.
+#[allow(clippy::upper_case_acronyms)]
+pub struct MMC5Audio {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    frame_period: u16, // envelopes and length counters run at fixed 240 Hz
+    frame_timer: u16,
+    cycle: u64,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_output: u8,
+}
+
+impl MMC5Audio {
+    pub fn new(region: Region) -> Self {
+        Self {
+            pulse_1: Pulse::new_without_sweep(),
+            pulse_2: Pulse::new_without_sweep(),
+            frame_period: (region.get_cpu_clock_rate() / 240.0) as u16,
+            frame_timer: 0,
+            cycle: 0,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_output: 0,
+        }
+    }
+}
+
+impl MemoryHandler for MMC5Audio {
+    fn read(&mut self, address: u16) -> u8 {
+        match address {
+            0x5015 => self.pulse_1.length.is_active() as u8 | (self.pulse_2.length.is_active() as u8) << 1,
+            _ => 0, // $5010 IRQ flag is only set in read mode
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let register = address & 0x03;
+        match address {
+            0x5000..=0x5003 => self.pulse_1.write(register, value),
+            0x5004..=0x5007 => self.pulse_2.write(register, value),
+            0x5010 => {
+                self.pcm_read_mode = value & 0x01 != 0;
+                self.pcm_irq_enabled = value & 0x80 != 0;
+            },
+            // writing 0 does nothing, it's used to signal end of sample in read mode
+            0x5011 if !self.pcm_read_mode && value != 0 => self.pcm_output = value,
+            0x5015 => {
+                self.pulse_1.length.set_enabled(value & 0x01 != 0);
+                self.pulse_2.length.set_enabled(value & 0x02 != 0);
+            },
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for MMC5Audio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::MMC5
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x5000, 0x08), (0x5010, 0x02), (0x5015, 0x01)]
+    }
+
+    fn get_read_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x5015, 0x01)]
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.frame_timer == 0 {
+            self.frame_timer = self.frame_period - 1;
+            for pulse in [&mut self.pulse_1, &mut self.pulse_2] {
+                pulse.envelope.clock();
+                pulse.length.clock();
+            }
+        } else {
+            self.frame_timer -= 1;
+        }
+        if self.cycle.is_multiple_of(2) {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+    }
+
+    // Pulses are mixed linearly, unlike on the APU
+    fn get_output(&self) -> f32 {
+        (self.pulse_1.get_output() + self.pulse_2.get_output()) as f32 * PULSE_STEP + self.pcm_output as f32 * PCM_STEP
+    }
+}
+
+impl Snapshot for MMC5Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        state.write_u16(self.frame_timer);
+        state.write_u64(self.cycle);
+        state.write_bool(self.pcm_read_mode);
+        state.write_bool(self.pcm_irq_enabled);
+        state.write_u8(self.pcm_output);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.frame_timer = state.read_u16()?;
+        self.cycle = state.read_u64()?;
+        self.pcm_read_mode = state.read_bool()?;
+        self.pcm_irq_enabled = state.read_bool()?;
+        self.pcm_output = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod mmc5_tests {
+    use super::*;
+
+    #[test]
+    fn test_pulses_and_length_counters() {
+        let mut mmc5 = MMC5Audio::new(Region::NTSC);
+        mmc5.write(0x5015, 0x03);
+        mmc5.write(0x5000, 0b1011_1111); // 50% duty, constant volume 15
+        mmc5.write(0x5002, 0xFF);
+        mmc5.write(0x5003, 0x07); // period APU pulse would mute because of sweep
+        mmc5.write(0x5007, 0x08); // length 254 on pulse 2
+        assert_eq!(mmc5.read(0x5015), 0x03);
+
+        let high_steps = (0..16).filter(|_| {
+            for _ in 0..0x800 * 2 {
+                mmc5.clock();
+            }
+            mmc5.get_output() > 0.0
+        }).count();
+        assert_eq!(high_steps, 8);
+
+        mmc5.write(0x5015, 0x01);
+        assert_eq!(mmc5.read(0x5015), 0x01);
+    }
+
+    #[test]
+    fn test_pcm_write_mode() {
+        let mut mmc5 = MMC5Audio::new(Region::NTSC);
+        mmc5.write(0x5011, 0x80);
+        mmc5.write(0x5011, 0x00); // ignored
+        assert_eq!(mmc5.get_output(), 0x80 as f32 * PCM_STEP);
+
+        mmc5.write(0x5010, 0x01);
+        mmc5.write(0x5011, 0x40);
+        assert_eq!(mmc5.pcm_output, 0x80);
+    }
+}
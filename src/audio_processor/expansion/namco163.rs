@@ -0,0 +1,203 @@
+use crate::{ audio_processor::AudioChannel, memory::MemoryHandler, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// One channel switches to the next every 15 CPU cycles
+const CHANNEL_CYCLES: u8 = 15;
+// Loudness differs between boards, this is about the middle. With one channel, full volume wave at
+// full amplitude is a bit louder than APU pulse.
+const VOLUME_STEP: f32 = PULSE_STEP / 6.0;
+
+// Namco 163, up to 8 wavetable channels. Waves and channel registers share 128 bytes of RAM,
+// accessed through $F800 (address) and $4800 (data). Channel 8 registers are at $78-$7F, channel 1 at $40-$47.
+pub struct Namco163Audio {
+    ram: [u8; 0x80],
+    address: u8,
+    auto_increment: bool,
+    disabled: bool,
+    cycle: u8,
+    current_channel: usize, // counts down from 7
+    outputs: [i16; 8], // last output of each channel
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; 0x80],
+            address: 0,
+            auto_increment: false,
+            disabled: false,
+            cycle: 0,
+            current_channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    fn get_channel_count(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    // Phase is 24 bit with sample index in top byte, it wraps at wave length
+    fn update_channel(&mut self, channel: usize) {
+        let base = 0x40 + channel * 8;
+        let registers = &self.ram[base..base + 8];
+        let frequency = registers[0] as u32 | (registers[2] as u32) << 8 | (registers[4] as u32 & 0x03) << 16;
+        let length = (256 - (registers[4] & 0xFC) as u32) << 16;
+        let phase = registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+        let wave_address = registers[6];
+        let volume = (registers[7] & 0x0F) as i16;
+
+        let phase = (phase + frequency) % length;
+        let sample_address = wave_address.wrapping_add((phase >> 16) as u8);
+        let byte = self.ram[sample_address as usize / 2 % 0x80];
+        let sample = if sample_address.is_multiple_of(2) { byte & 0x0F } else { byte >> 4 };
+        self.outputs[channel] = (sample as i16 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl MemoryHandler for Namco163Audio {
+    fn read(&mut self, _address: u16) -> u8 {
+        let value = self.ram[self.address as usize];
+        if self.auto_increment {
+            self.address = (self.address + 1) % 0x80;
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address & 0xF800 {
+            0x4800 => {
+                self.ram[self.address as usize] = value;
+                if self.auto_increment {
+                    self.address = (self.address + 1) % 0x80;
+                }
+            },
+            0xE000 => self.disabled = value & 0x40 != 0,
+            0xF800 => {
+                self.address = value & 0x7F;
+                self.auto_increment = value & 0x80 != 0;
+            },
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for Namco163Audio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::Namco163
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x4800, 0x0800), (0xE000, 0x0800), (0xF800, 0x0800)]
+    }
+
+    fn get_read_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x4800, 0x0800)]
+    }
+
+    fn clock(&mut self) {
+        if self.disabled {
+            return;
+        }
+        self.cycle += 1;
+        if self.cycle < CHANNEL_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+        self.update_channel(self.current_channel);
+        let first_channel = 8 - self.get_channel_count();
+        self.current_channel = if self.current_channel <= first_channel { 7 } else { self.current_channel - 1 };
+    }
+
+    // Hardware plays one channel at a time, which whines at high channel counts. Average is what it sounds like
+    // without the whine, so each added channel makes others quieter.
+    fn get_output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+        let count = self.get_channel_count();
+        let sum: i16 = self.outputs[8 - count..].iter().sum();
+        sum as f32 / count as f32 * VOLUME_STEP
+    }
+}
+
+impl Snapshot for Namco163Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_u8(self.address);
+        state.write_bool(self.auto_increment);
+        state.write_bool(self.disabled);
+        state.write_u8(self.cycle);
+        state.write_u8(self.current_channel as u8);
+        for output in self.outputs {
+            state.write_u16(output as u16);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.ram)?;
+        self.address = state.read_u8()?;
+        self.auto_increment = state.read_bool()?;
+        self.disabled = state.read_bool()?;
+        self.cycle = state.read_u8()?;
+        self.current_channel = state.read_u8()? as usize;
+        for output in &mut self.outputs {
+            *output = state.read_u16()? as i16;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod namco163_tests {
+    use super::*;
+
+    fn write_ram(n163: &mut Namco163Audio, address: u8, values: &[u8]) {
+        n163.write(0xF800, 0x80 | address);
+        for value in values {
+            n163.write(0x4800, *value);
+        }
+    }
+
+    #[test]
+    fn test_ram_port() {
+        let mut n163 = Namco163Audio::new();
+        write_ram(&mut n163, 0x10, &[1, 2, 3]);
+        n163.write(0xF800, 0x80 | 0x10);
+        assert_eq!([n163.read(0x4800), n163.read(0x4800), n163.read(0x4800)], [1, 2, 3]);
+        n163.write(0xF800, 0x11); // no auto increment
+        assert_eq!([n163.read(0x4800), n163.read(0x4800)], [2, 2]);
+    }
+
+    #[test]
+    fn test_wave_playback() {
+        let mut n163 = Namco163Audio::new();
+        write_ram(&mut n163, 0x00, &[0xF0, 0x5A]); // samples 0, 15, 10, 5
+        // channel 8: frequency $10000 (one sample per update), length 4, wave at 0, volume 2, one channel
+        write_ram(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x02]);
+        let outputs: Vec<f32> = (0..5).map(|_| {
+            for _ in 0..CHANNEL_CYCLES {
+                n163.clock();
+            }
+            n163.get_output() / VOLUME_STEP
+        }).collect();
+        assert_eq!(outputs, [14.0, 4.0, -6.0, -16.0, 14.0]);
+    }
+
+    #[test]
+    fn test_more_channels_share_output() {
+        let mut n163 = Namco163Audio::new();
+        write_ram(&mut n163, 0x00, &[0xFF]);
+        write_ram(&mut n163, 0x70, &[0x00, 0x00, 0x00, 0x00, 0xFC, 0x00, 0x00, 0x0F]);
+        write_ram(&mut n163, 0x78, &[0x00, 0x00, 0x00, 0x00, 0xFC, 0x00, 0x00, 0x1F]); // two channels
+        for _ in 0..CHANNEL_CYCLES * 2 {
+            n163.clock();
+        }
+        assert_eq!(n163.current_channel, 7);
+        assert_eq!(n163.get_output() / VOLUME_STEP, 7.0 * 15.0);
+    }
+}
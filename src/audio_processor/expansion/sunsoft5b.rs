@@ -0,0 +1,262 @@
+use crate::{ audio_processor::AudioChannel, memory::MemoryHandler, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// Chip runs at CPU clock, tone and noise counters are clocked at 1/16 of it
+const CLOCK_DIVIDER: u8 = 16;
+// One channel at full volume is a good deal louder than APU pulse
+const FULL_VOLUME: f32 = PULSE_STEP * 15.0 * 1.5;
+
+// Volume steps are 3 dB apart, 0 is silent
+fn get_amplitude(volume: u8) -> f32 {
+    if volume == 0 { 0.0 } else { 10f32.powf((volume as f32 - 15.0) * 3.0 / 20.0) }
+}
+
+// Sunsoft 5B (FME-7 with audio), a YM2149F. Three square tones that can be mixed with noise and envelope,
+// register number goes to $C000 and value to $E000.
+pub struct Sunsoft5BAudio {
+    registers: [u8; 16],
+    selected_register: u8,
+    divider: u8,
+    tone_timers: [u16; 3],
+    tone_levels: [bool; 3],
+    noise_timer: u8,
+    noise_shift_register: u32, // 17 bit
+    envelope_timer: u16,
+    envelope_step: u8, // 0-15, counts down on attack and up on decay
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5BAudio {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 16],
+            selected_register: 0,
+            divider: 0,
+            tone_timers: [0; 3],
+            tone_levels: [false; 3],
+            noise_timer: 0,
+            noise_shift_register: 1,
+            envelope_timer: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    fn get_tone_period(&self, channel: usize) -> u16 {
+        let period = self.registers[channel * 2] as u16 | (self.registers[channel * 2 + 1] as u16 & 0x0F) << 8;
+        period.max(1)
+    }
+
+    fn get_envelope_period(&self) -> u16 {
+        (self.registers[11] as u16 | (self.registers[12] as u16) << 8).max(1)
+    }
+
+    fn restart_envelope(&mut self) {
+        self.envelope_attack = self.registers[13] & 0x04 != 0;
+        self.envelope_step = 15;
+        self.envelope_timer = 0;
+        self.envelope_holding = false;
+    }
+
+    fn get_envelope_volume(&self) -> u8 {
+        if self.envelope_attack { 15 - self.envelope_step } else { self.envelope_step }
+    }
+
+    // Shape bits are continue, attack, alternate and hold
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step > 0 {
+            self.envelope_step -= 1;
+            return;
+        }
+        let shape = self.registers[13];
+        if shape & 0x08 == 0 {
+            self.envelope_attack = false; // drops to 0 and stays there
+            self.envelope_holding = true;
+        } else if shape & 0x01 != 0 {
+            if shape & 0x02 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            if shape & 0x02 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 15;
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        let feedback = (self.noise_shift_register ^ (self.noise_shift_register >> 3)) & 1;
+        self.noise_shift_register = (self.noise_shift_register >> 1) | (feedback << 16);
+    }
+}
+
+impl MemoryHandler for Sunsoft5BAudio {
+    fn read(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address & 0xE000 {
+            0xC000 => self.selected_register = value & 0x0F,
+            0xE000 => {
+                self.registers[self.selected_register as usize] = value;
+                if self.selected_register == 13 {
+                    self.restart_envelope();
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for Sunsoft5BAudio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::Sunsoft5B
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0xC000, 0x2000), (0xE000, 0x2000)]
+    }
+
+    fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+        for channel in 0..3 {
+            self.tone_timers[channel] += 1;
+            if self.tone_timers[channel] >= self.get_tone_period(channel) {
+                self.tone_timers[channel] = 0;
+                self.tone_levels[channel] = !self.tone_levels[channel];
+            }
+        }
+        self.noise_timer += 1;
+        // Noise period counts in two divider clocks
+        if self.noise_timer >= (self.registers[6] & 0x1F).max(1) * 2 {
+            self.noise_timer = 0;
+            self.clock_noise();
+        }
+        self.envelope_timer += 1;
+        if self.envelope_timer >= self.get_envelope_period() {
+            self.envelope_timer = 0;
+            self.clock_envelope();
+        }
+    }
+
+    fn get_output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise = self.noise_shift_register & 1 != 0;
+        (0..3).map(|channel| {
+            let tone_on = self.tone_levels[channel] || mixer & (1 << channel) != 0;
+            let noise_on = noise || mixer & (8 << channel) != 0;
+            if !(tone_on && noise_on) {
+                return 0.0;
+            }
+            let volume = self.registers[8 + channel];
+            get_amplitude(if volume & 0x10 != 0 { self.get_envelope_volume() } else { volume & 0x0F })
+        }).sum::<f32>() * FULL_VOLUME
+    }
+}
+
+impl Snapshot for Sunsoft5BAudio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_u8(self.selected_register);
+        state.write_u8(self.divider);
+        for channel in 0..3 {
+            state.write_u16(self.tone_timers[channel]);
+            state.write_bool(self.tone_levels[channel]);
+        }
+        state.write_u8(self.noise_timer);
+        state.write_u64(self.noise_shift_register as u64);
+        state.write_u16(self.envelope_timer);
+        state.write_u8(self.envelope_step);
+        state.write_bool(self.envelope_attack);
+        state.write_bool(self.envelope_holding);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.registers)?;
+        self.selected_register = state.read_u8()?;
+        self.divider = state.read_u8()?;
+        for channel in 0..3 {
+            self.tone_timers[channel] = state.read_u16()?;
+            self.tone_levels[channel] = state.read_bool()?;
+        }
+        self.noise_timer = state.read_u8()?;
+        self.noise_shift_register = state.read_u64()? as u32;
+        self.envelope_timer = state.read_u16()?;
+        self.envelope_step = state.read_u8()?;
+        self.envelope_attack = state.read_bool()?;
+        self.envelope_holding = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod sunsoft5b_tests {
+    use super::*;
+
+    fn write_register(chip: &mut Sunsoft5BAudio, register: u8, value: u8) {
+        chip.write(0xC000, register);
+        chip.write(0xE000, value);
+    }
+
+    #[test]
+    fn test_tone_period() {
+        let mut chip = Sunsoft5BAudio::new();
+        write_register(&mut chip, 0, 10);
+        write_register(&mut chip, 7, 0b11_1110); // only tone A
+        write_register(&mut chip, 8, 15);
+        let mut toggles = 0;
+        let mut last = chip.get_output();
+        for _ in 0..16 * 10 * 8 {
+            chip.clock();
+            if chip.get_output() != last {
+                toggles += 1;
+                last = chip.get_output();
+            }
+        }
+        assert_eq!(toggles, 8);
+        assert_eq!(last, 0.0);
+    }
+
+    #[test]
+    fn test_volume_is_logarithmic() {
+        assert_eq!(get_amplitude(0), 0.0);
+        assert_eq!(get_amplitude(15), 1.0);
+        assert!((get_amplitude(13) - 0.5).abs() < 0.01); // 6 dB
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut chip = Sunsoft5BAudio::new();
+        write_register(&mut chip, 13, 0b1101); // attack and hold at top
+        assert_eq!(chip.get_envelope_volume(), 0);
+        for _ in 0..20 {
+            chip.clock_envelope();
+        }
+        assert_eq!(chip.get_envelope_volume(), 15);
+
+        write_register(&mut chip, 13, 0b1010); // decay, then attack, repeating
+        let volumes: Vec<u8> = (0..32).map(|_| {
+            chip.clock_envelope();
+            chip.get_envelope_volume()
+        }).collect();
+        assert_eq!(&volumes[14..18], [0, 0, 1, 2]);
+
+        write_register(&mut chip, 13, 0b0000); // decay once
+        for _ in 0..20 {
+            chip.clock_envelope();
+        }
+        assert_eq!(chip.get_envelope_volume(), 0);
+    }
+}
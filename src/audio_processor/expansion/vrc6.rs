@@ -0,0 +1,293 @@
+use crate::{ audio_processor::AudioChannel, memory::MemoryHandler, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// VRC6 pulse at some volume is about as loud as APU pulse at the same volume
+const VOLUME_STEP: f32 = PULSE_STEP;
+
+// Pulse with 16 step sequence and 8 duty cycles, can also output constant volume
+#[derive(Debug, Default)]
+struct VRC6Pulse {
+    volume: u8,
+    duty: u8, // high for duty + 1 steps out of 16
+    ignore_duty: bool,
+    enabled: bool,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl VRC6Pulse {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.ignore_duty = value & 0x80 != 0;
+                self.duty = (value >> 4) & 0x07;
+                self.volume = value & 0x0F;
+            },
+            1 => self.timer_period = (self.timer_period & 0x0F00) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                }
+            },
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.timer_period >> shift;
+            self.step = (self.step + 1) % 16;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    fn get_output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+// Accumulator grows by rate every other timer clock and resets after 7 additions
+#[derive(Debug, Default)]
+struct VRC6Sawtooth {
+    rate: u8,
+    enabled: bool,
+    timer_period: u16,
+    timer: u16,
+    step: u8,
+    accumulator: u8,
+}
+
+impl VRC6Sawtooth {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.rate = value & 0x3F,
+            1 => self.timer_period = (self.timer_period & 0x0F00) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.enabled = value & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            },
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.timer_period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step.is_multiple_of(2) {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-31
+    fn get_output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// Konami VRC6, two pulses and a sawtooth at $9000-$9003, $A000-$A002 and $B000-$B002
+#[allow(clippy::upper_case_acronyms)]
+pub struct VRC6Audio {
+    swapped_lines: bool, // mapper 26 has A0 and A1 swapped
+    pulse_1: VRC6Pulse,
+    pulse_2: VRC6Pulse,
+    sawtooth: VRC6Sawtooth,
+    halted: bool,
+    period_shift: u8, // all periods can be divided by 16 or 256
+}
+
+impl VRC6Audio {
+    pub fn new(swapped_lines: bool) -> Self {
+        Self {
+            swapped_lines,
+            pulse_1: VRC6Pulse::default(),
+            pulse_2: VRC6Pulse::default(),
+            sawtooth: VRC6Sawtooth::default(),
+            halted: false,
+            period_shift: 0,
+        }
+    }
+}
+
+impl MemoryHandler for VRC6Audio {
+    fn read(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let address = if self.swapped_lines {
+            (address & 0xFFFC) | ((address & 0x01) << 1) | ((address & 0x02) >> 1)
+        } else {
+            address
+        };
+        let register = address & 0x03;
+        match address & 0xF003 {
+            0x9003 => {
+                self.halted = value & 0x01 != 0;
+                self.period_shift = if value & 0x04 != 0 { 8 } else if value & 0x02 != 0 { 4 } else { 0 };
+            },
+            0x9000..=0x9002 => self.pulse_1.write(register, value),
+            0xA000..=0xA002 => self.pulse_2.write(register, value),
+            0xB000..=0xB002 => self.sawtooth.write(register, value),
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for VRC6Audio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::VRC6
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x9000, 4), (0xA000, 4), (0xB000, 4)]
+    }
+
+    fn clock(&mut self) {
+        if self.halted {
+            return;
+        }
+        self.pulse_1.clock(self.period_shift);
+        self.pulse_2.clock(self.period_shift);
+        self.sawtooth.clock(self.period_shift);
+    }
+
+    fn get_output(&self) -> f32 {
+        let sum = self.pulse_1.get_output() + self.pulse_2.get_output() + self.sawtooth.get_output();
+        sum as f32 * VOLUME_STEP
+    }
+}
+
+impl Snapshot for VRC6Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.volume);
+        state.write_u8(self.duty);
+        state.write_bool(self.ignore_duty);
+        state.write_bool(self.enabled);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.volume = state.read_u8()?;
+        self.duty = state.read_u8()?;
+        self.ignore_duty = state.read_bool()?;
+        self.enabled = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.step = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for VRC6Sawtooth {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.rate);
+        state.write_bool(self.enabled);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.step);
+        state.write_u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.rate = state.read_u8()?;
+        self.enabled = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.step = state.read_u8()?;
+        self.accumulator = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for VRC6Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        self.pulse_1.save_state(state);
+        self.pulse_2.save_state(state);
+        self.sawtooth.save_state(state);
+        state.write_bool(self.halted);
+        state.write_u8(self.period_shift);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.pulse_1.load_state(state)?;
+        self.pulse_2.load_state(state)?;
+        self.sawtooth.load_state(state)?;
+        self.halted = state.read_bool()?;
+        self.period_shift = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod vrc6_tests {
+    use super::*;
+
+    fn collect_steps(vrc6: &mut VRC6Audio, period: usize, count: usize) -> Vec<f32> {
+        (0..count).map(|_| {
+            for _ in 0..period {
+                vrc6.clock();
+            }
+            vrc6.get_output() / VOLUME_STEP
+        }).collect()
+    }
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = VRC6Audio::new(false);
+        vrc6.write(0x9000, 0x3A); // duty 4/16, volume 10
+        vrc6.write(0x9001, 0x00);
+        vrc6.write(0x9002, 0x80);
+        let steps = collect_steps(&mut vrc6, 1, 16);
+        assert_eq!(steps.iter().filter(|step| **step == 10.0).count(), 4);
+
+        vrc6.write(0x9000, 0x8A); // constant volume
+        assert!(collect_steps(&mut vrc6, 1, 16).iter().all(|step| *step == 10.0));
+    }
+
+    #[test]
+    fn test_sawtooth_ramp() {
+        let mut vrc6 = VRC6Audio::new(false);
+        vrc6.write(0xB000, 0x2A); // rate 42
+        vrc6.write(0xB001, 0x00);
+        vrc6.write(0xB002, 0x80);
+        let steps = collect_steps(&mut vrc6, 1, 14);
+        assert_eq!(steps, [0.0, 5.0, 5.0, 10.0, 10.0, 15.0, 15.0, 21.0, 21.0, 26.0, 26.0, 31.0, 31.0, 0.0]);
+    }
+
+    #[test]
+    fn test_swapped_lines_and_halt() {
+        let mut vrc6 = VRC6Audio::new(true);
+        vrc6.write(0x9000, 0x8F);
+        vrc6.write(0x9001, 0x80); // $9002 on mapper 24
+        assert_eq!(vrc6.get_output(), 15.0 * VOLUME_STEP);
+
+        vrc6.write(0x9003, 0x01);
+        vrc6.write(0xA000, 0x0F);
+        vrc6.write(0xA001, 0x80);
+        vrc6.clock();
+        assert_eq!(vrc6.pulse_2.step, 0); // halted
+    }
+}
@@ -0,0 +1,408 @@
+use std::f32::consts::PI;
+
+use crate::{ audio_processor::AudioChannel, memory::MemoryHandler, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::{ ExpansionAudio, PULSE_STEP };
+
+// Built-in instruments 1-15, instrument 0 is defined by registers $00-$07
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+const MULTIPLIERS: [f32; 16] = [0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0];
+// Key scale attenuation in dB by top 4 bits of frequency number, at the highest block
+const KEY_SCALE_TABLE: [f32; 16] = [0.0, 9.0, 12.0, 13.875, 15.0, 16.125, 16.875, 17.625, 18.0, 18.75, 19.125, 19.5, 19.875, 20.25, 20.625, 21.0];
+// Chip makes one sample every 72 clocks of its 3.58 MHz crystal
+const SAMPLE_CYCLES: u8 = 36;
+const SAMPLE_RATE: f32 = 3_579_545.0 / 72.0;
+// Envelope attenuation in dB where operator is considered silent
+const MAX_ATTENUATION: f32 = 48.0;
+// Phase shift in cycles that full modulator output causes
+const MODULATION_DEPTH: f32 = 2.0;
+const TREMOLO_RATE: f32 = 3.7; // in Hz
+const TREMOLO_DEPTH: f32 = 4.8; // in dB
+const VIBRATO_RATE: f32 = 6.4;
+const VIBRATO_DEPTH: f32 = 0.004; // about 7 cents
+// Channel at full volume is about as loud as APU pulse
+const CHANNEL_VOLUME: f32 = PULSE_STEP * 15.0;
+
+// Operator settings from instrument, index 0 is modulator and 1 is carrier
+struct OperatorPatch {
+    tremolo: bool,
+    vibrato: bool,
+    sustained: bool, // holds at sustain level until key off, otherwise fades like a percussion
+    key_scale_rate: bool,
+    multiplier: f32,
+    key_scale_level: u8,
+    rectified: bool, // negative half of sine wave is cut off
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    fn new(patch: &[u8; 8], index: usize) -> Self {
+        Self {
+            tremolo: patch[index] & 0x80 != 0,
+            vibrato: patch[index] & 0x40 != 0,
+            sustained: patch[index] & 0x20 != 0,
+            key_scale_rate: patch[index] & 0x10 != 0,
+            multiplier: MULTIPLIERS[(patch[index] & 0x0F) as usize],
+            key_scale_level: patch[2 + index] >> 6,
+            rectified: patch[3] & (0x08 << index) != 0,
+            attack_rate: patch[4 + index] >> 4,
+            decay_rate: patch[4 + index] & 0x0F,
+            sustain_level: patch[6 + index] >> 4,
+            release_rate: patch[6 + index] & 0x0F,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+#[derive(Debug, Default)]
+struct Operator {
+    phase: f32, // in cycles
+    attenuation: f32, // envelope, in dB
+    state: EnvelopeState,
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    // Rate 0-15 with key scaling becomes 0-63, four steps double the speed
+    fn get_rate(rate: u8, key_scale: u8) -> u8 {
+        if rate == 0 { 0 } else { (rate * 4 + key_scale).min(63) }
+    }
+
+    // Same timings as on OPL2: decay over 96 dB takes 39 s at rate 4
+    fn get_decay_step(rate: u8) -> f32 {
+        if rate == 0 {
+            return 0.0;
+        }
+        let milliseconds = 39280.0 / 2f32.powf((rate as f32 - 4.0) / 4.0);
+        96.0 / (milliseconds / 1000.0 * SAMPLE_RATE)
+    }
+
+    fn clock_envelope(&mut self, patch: &OperatorPatch, key_scale: u8, release_rate: u8) {
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = Self::get_rate(patch.attack_rate, key_scale);
+                if rate >= 60 {
+                    self.attenuation = 0.0;
+                } else if rate > 0 {
+                    // Attack is exponential in dB, fast at first
+                    let milliseconds = 2826.0 / 2f32.powf((rate as f32 - 4.0) / 4.0);
+                    self.attenuation *= 0.01f32.powf(1.0 / (milliseconds / 1000.0 * SAMPLE_RATE));
+                }
+                if self.attenuation < 0.1 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            },
+            EnvelopeState::Decay => {
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                self.attenuation += Self::get_decay_step(Self::get_rate(patch.decay_rate, key_scale));
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            },
+            EnvelopeState::Sustain if patch.sustained => (),
+            EnvelopeState::Sustain => self.attenuation += Self::get_decay_step(Self::get_rate(patch.release_rate, key_scale)),
+            EnvelopeState::Release => self.attenuation += Self::get_decay_step(Self::get_rate(release_rate, key_scale)),
+            EnvelopeState::Off => self.attenuation = MAX_ATTENUATION,
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            self.state = EnvelopeState::Off;
+        }
+    }
+
+    fn get_output(&self, patch: &OperatorPatch, level: f32, modulation: f32) -> f32 {
+        if self.state == EnvelopeState::Off {
+            return 0.0;
+        }
+        let sine = (2.0 * PI * (self.phase + modulation)).sin();
+        let wave = if patch.rectified && sine < 0.0 { 0.0 } else { sine };
+        wave * 10f32.powf(-(self.attenuation + level) / 20.0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FmChannel {
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f32; 2], // last two modulator outputs
+    output: f32,
+}
+
+// Konami VRC7, six channel FM synthesizer (cut down YM2413). Register number goes to $9010 and value to $9030.
+// Envelopes and waves are computed in floating point, which is close to the chip but not bit exact.
+#[allow(clippy::upper_case_acronyms)]
+pub struct VRC7Audio {
+    registers: [u8; 0x40],
+    address: u8,
+    cycle: u8,
+    tremolo_phase: f32,
+    vibrato_phase: f32,
+    channels: [FmChannel; 6],
+}
+
+impl VRC7Audio {
+    pub fn new() -> Self {
+        Self {
+            registers: [0; 0x40],
+            address: 0,
+            cycle: 0,
+            tremolo_phase: 0.0,
+            vibrato_phase: 0.0,
+            channels: Default::default(),
+        }
+    }
+
+    fn get_patch(&self, channel: usize) -> [u8; 8] {
+        match self.registers[0x30 + channel] >> 4 {
+            0 => self.registers[0..8].try_into().unwrap(),
+            instrument => PATCHES[instrument as usize - 1],
+        }
+    }
+
+    fn write_register(&mut self, value: u8) {
+        let address = self.address as usize;
+        if address >= self.registers.len() {
+            return;
+        }
+        let old_value = self.registers[address];
+        self.registers[address] = value;
+        if let 0x20..=0x25 = address {
+            let channel = &mut self.channels[address - 0x20];
+            let was_on = old_value & 0x10 != 0;
+            let is_on = value & 0x10 != 0;
+            if is_on && !was_on {
+                channel.modulator.key_on();
+                channel.carrier.key_on();
+            } else if was_on && !is_on && channel.carrier.state != EnvelopeState::Off {
+                channel.carrier.state = EnvelopeState::Release; // modulator isn't affected by key off
+            }
+        }
+    }
+
+    fn update_channel(&mut self, channel_index: usize, tremolo: f32, vibrato: f32) {
+        let patch = self.get_patch(channel_index);
+        let operators = [OperatorPatch::new(&patch, 0), OperatorPatch::new(&patch, 1)];
+        let frequency_number = self.registers[0x10 + channel_index] as u16 | (self.registers[0x20 + channel_index] as u16 & 0x01) << 8;
+        let block = (self.registers[0x20 + channel_index] >> 1) & 0x07;
+        let channel_sustain = self.registers[0x20 + channel_index] & 0x20 != 0;
+        let volume = self.registers[0x30 + channel_index] & 0x0F;
+        let modulator_level = (patch[2] & 0x3F) as f32 * 0.75;
+        let feedback = patch[3] & 0x07;
+        // Cycles per sample before multiplier, frequency number is in 1/2^19 steps at block 0
+        let increment = frequency_number as f32 * 2f32.powi(block as i32) / 524288.0;
+        let key_scale_level = (KEY_SCALE_TABLE[(frequency_number >> 5) as usize] - 6.0 * (7 - block) as f32).max(0.0);
+        let release_rate = if channel_sustain { 5 } else if operators[1].sustained { operators[1].release_rate } else { 7 };
+
+        let channel = &mut self.channels[channel_index];
+        let mut levels = [modulator_level, volume as f32 * 3.0];
+        for (index, (operator, patch)) in [&mut channel.modulator, &mut channel.carrier].into_iter().zip(&operators).enumerate() {
+            let key_scale = if patch.key_scale_rate { block << 1 | (frequency_number >> 8) as u8 } else { block >> 1 };
+            operator.clock_envelope(patch, key_scale, release_rate);
+            let vibrato = if patch.vibrato { vibrato } else { 1.0 };
+            operator.phase = (operator.phase + increment * patch.multiplier * vibrato).fract();
+            // Key scale level doubles with each setting step, starting from nothing
+            let key_scale_attenuation = match patch.key_scale_level { 0 => 0.0, level => key_scale_level / 2f32.powi(3 - level as i32) };
+            levels[index] += key_scale_attenuation + if patch.tremolo { tremolo } else { 0.0 };
+        }
+
+        let self_modulation = if feedback == 0 { 0.0 } else { (channel.feedback[0] + channel.feedback[1]) / 2.0 * MODULATION_DEPTH * 2f32.powi(feedback as i32 - 7) };
+        let modulator_output = channel.modulator.get_output(&operators[0], levels[0], self_modulation);
+        channel.feedback = [channel.feedback[1], modulator_output];
+        channel.output = channel.carrier.get_output(&operators[1], levels[1], modulator_output * MODULATION_DEPTH);
+    }
+}
+
+impl MemoryHandler for VRC7Audio {
+    fn read(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x9010 => self.address = value,
+            0x9030 => self.write_register(value),
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for VRC7Audio {
+    fn get_channel(&self) -> AudioChannel {
+        AudioChannel::VRC7
+    }
+
+    fn get_write_registers(&self) -> Vec<(u16, u16)> {
+        vec![(0x9010, 1), (0x9030, 1)]
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < SAMPLE_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+        self.tremolo_phase = (self.tremolo_phase + TREMOLO_RATE / SAMPLE_RATE).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_RATE / SAMPLE_RATE).fract();
+        let tremolo = (1.0 + (2.0 * PI * self.tremolo_phase).sin()) / 2.0 * TREMOLO_DEPTH;
+        let vibrato = 1.0 + (2.0 * PI * self.vibrato_phase).sin() * VIBRATO_DEPTH;
+        for channel in 0..self.channels.len() {
+            self.update_channel(channel, tremolo, vibrato);
+        }
+    }
+
+    fn get_output(&self) -> f32 {
+        self.channels.iter().map(|channel| channel.output).sum::<f32>() * CHANNEL_VOLUME
+    }
+}
+
+impl Snapshot for Operator {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_f32(self.phase);
+        state.write_f32(self.attenuation);
+        state.write_u8(self.state as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.phase = state.read_f32()?;
+        self.attenuation = state.read_f32()?;
+        self.state = match state.read_u8()? {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            3 => EnvelopeState::Release,
+            _ => EnvelopeState::Off,
+        };
+        Ok(())
+    }
+}
+
+impl Snapshot for VRC7Audio {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_u8(self.address);
+        state.write_u8(self.cycle);
+        state.write_f32(self.tremolo_phase);
+        state.write_f32(self.vibrato_phase);
+        for channel in &self.channels {
+            channel.modulator.save_state(state);
+            channel.carrier.save_state(state);
+            state.write_f32(channel.feedback[0]);
+            state.write_f32(channel.feedback[1]);
+            state.write_f32(channel.output);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.registers)?;
+        self.address = state.read_u8()?;
+        self.cycle = state.read_u8()?;
+        self.tremolo_phase = state.read_f32()?;
+        self.vibrato_phase = state.read_f32()?;
+        for channel in &mut self.channels {
+            channel.modulator.load_state(state)?;
+            channel.carrier.load_state(state)?;
+            channel.feedback = [state.read_f32()?, state.read_f32()?];
+            channel.output = state.read_f32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod vrc7_tests {
+    use super::*;
+
+    const FREQUENCY_NUMBER: u16 = 290; // 440 Hz at block 4
+
+    fn write_register(vrc7: &mut VRC7Audio, register: u8, value: u8) {
+        vrc7.write(0x9010, register);
+        vrc7.write(0x9030, value);
+    }
+
+    // Sine on carrier with instant attack and no decay, modulator turned all the way down
+    fn play_sine(vrc7: &mut VRC7Audio) {
+        for (register, value) in [0x21, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x00, 0xFF].into_iter().enumerate() {
+            write_register(vrc7, register as u8, value);
+        }
+        write_register(vrc7, 0x30, 0x00); // instrument 0, full volume
+        write_register(vrc7, 0x10, FREQUENCY_NUMBER as u8);
+        write_register(vrc7, 0x20, 0x10 | 4 << 1 | (FREQUENCY_NUMBER >> 8) as u8); // key on
+    }
+
+    fn run(vrc7: &mut VRC7Audio, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| {
+            for _ in 0..SAMPLE_CYCLES {
+                vrc7.clock();
+            }
+            vrc7.get_output()
+        }).collect()
+    }
+
+    #[test]
+    fn test_carrier_frequency() {
+        let mut vrc7 = VRC7Audio::new();
+        play_sine(&mut vrc7);
+        let samples = run(&mut vrc7, SAMPLE_RATE as usize);
+        let crossings = samples.windows(2).filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0)).count();
+        assert!(crossings.abs_diff(880) <= 2, "{crossings} zero crossings");
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak / CHANNEL_VOLUME - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_key_off_releases() {
+        let mut vrc7 = VRC7Audio::new();
+        play_sine(&mut vrc7);
+        run(&mut vrc7, 100);
+        write_register(&mut vrc7, 0x20, 4 << 1 | (FREQUENCY_NUMBER >> 8) as u8);
+        assert_eq!(vrc7.channels[0].carrier.state, EnvelopeState::Release);
+        run(&mut vrc7, 1000); // release rate 15
+        assert_eq!(vrc7.get_output(), 0.0);
+        assert_eq!(vrc7.channels[0].carrier.state, EnvelopeState::Off);
+    }
+
+    #[test]
+    fn test_built_in_instrument() {
+        let mut vrc7 = VRC7Audio::new();
+        write_register(&mut vrc7, 0x31, 0x30); // instrument 3 on channel 2
+        write_register(&mut vrc7, 0x11, 0xAC);
+        write_register(&mut vrc7, 0x21, 0x18);
+        let samples = run(&mut vrc7, 2000);
+        assert!(samples.iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(OperatorPatch::new(&vrc7.get_patch(1), 1).attack_rate, 0x0B);
+    }
+}
@@ -0,0 +1,104 @@
+use std::f32::consts::PI;
+
+#[derive(Debug, Clone, Copy)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// First order RC filter
+#[derive(Debug, Clone, Copy)]
+struct Filter {
+    kind: FilterKind,
+    cutoff: f32,
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl Filter {
+    fn new(kind: FilterKind, cutoff: f32, sample_rate: u32) -> Self {
+        let mut filter = Self { kind, cutoff, alpha: 0.0, previous_input: 0.0, previous_output: 0.0 };
+        filter.set_sample_rate(sample_rate);
+        filter
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        let rc = 1.0 / (2.0 * PI * self.cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        self.alpha = match self.kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.previous_output + input - self.previous_input),
+            FilterKind::LowPass => self.previous_output + self.alpha * (input - self.previous_output),
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+// What NES does to the signal on its way out: two high-pass filters remove DC offset
+// and low end, low-pass one takes off the harshest highs
+pub struct FilterChain {
+    filters: [Filter; 3],
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            filters: [
+                Filter::new(FilterKind::HighPass, 90.0, sample_rate),
+                Filter::new(FilterKind::HighPass, 440.0, sample_rate),
+                Filter::new(FilterKind::LowPass, 14000.0, sample_rate),
+            ],
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        for filter in &mut self.filters {
+            filter.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.filters.iter_mut().fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+#[cfg(test)]
+mod filters_tests {
+    use super::*;
+
+    #[test]
+    fn test_dc_offset_is_removed() {
+        let mut chain = FilterChain::new(44100);
+        let output: Vec<f32> = (0..44100).map(|_| chain.process(0.5)).collect();
+
+        assert!(output[0] > 0.2); // step still goes through
+        assert!(output[44099].abs() < 0.001);
+    }
+
+    #[test]
+    fn test_low_pass_attenuates_highs() {
+        let mut low_pass = Filter::new(FilterKind::LowPass, 14000.0, 44100);
+        let output: Vec<f32> = (0..1000).map(|i| low_pass.process(if i % 2 == 0 { 1.0 } else { -1.0 })).collect();
+        let peak = output[900..].iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+
+        assert!(peak < 0.6);
+    }
+
+    #[test]
+    fn test_passband_is_kept() {
+        let mut chain = FilterChain::new(48000);
+        let output: Vec<f32> = (0..48000).map(|i| chain.process((2.0 * PI * 2000.0 * i as f32 / 48000.0).sin())).collect();
+        let peak = output[24000..].iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+
+        assert!(peak > 0.8);
+    }
+}
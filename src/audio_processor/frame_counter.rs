@@ -0,0 +1,129 @@
+use crate::{ region::Region, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct FrameClocks {
+    pub quarter: bool, // envelopes and triangle linear counter
+    pub half: bool, // length counters and sweeps
+}
+
+// Divides CPU clock into quarter and half frame clocks, in 4 or 5 step mode ($4017)
+#[derive(Debug)]
+pub struct FrameCounter {
+    region: Region,
+    five_step: bool,
+    irq_inhibit: bool,
+    pub irq_flag: bool,
+    cycle: u16,
+    pending_write: Option<(u8, u8)>, // cycles left and written value
+}
+
+impl FrameCounter {
+    pub fn new(region: Region) -> Self {
+        Self { region, five_step: false, irq_inhibit: false, irq_flag: false, cycle: 0, pending_write: None }
+    }
+
+    // CPU cycles of the 4 steps, last one depends on the mode. Dendy uses NTSC timing.
+    fn get_steps(&self) -> [u16; 4] {
+        match (self.region, self.five_step) {
+            (Region::PAL, false) => [8313, 16627, 24939, 33253],
+            (Region::PAL, true) => [8313, 16627, 24939, 41565],
+            (_, false) => [7457, 14913, 22371, 29829],
+            (_, true) => [7457, 14913, 22371, 37281],
+        }
+    }
+
+    // Sequencer is reset 3 or 4 cycles after the write, depending on CPU/APU cycle alignment
+    pub fn write(&mut self, value: u8, odd_cycle: bool) {
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.irq_flag = false;
+        }
+        self.pending_write = Some((if odd_cycle { 4 } else { 3 }, value));
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock(&mut self) -> FrameClocks {
+        if let Some((delay, value)) = self.pending_write {
+            if delay > 1 {
+                self.pending_write = Some((delay - 1, value));
+            } else {
+                self.pending_write = None;
+                self.five_step = value & 0x80 != 0;
+                self.cycle = 0;
+                // 5 step mode clocks everything right away
+                return FrameClocks { quarter: self.five_step, half: self.five_step };
+            }
+        }
+
+        let steps = self.get_steps();
+        self.cycle += 1;
+        if self.cycle > steps[3] { // sequence is one cycle longer than its last step
+            self.cycle = 0;
+        }
+        if self.cycle == steps[3] && !self.five_step && !self.irq_inhibit {
+            self.irq_flag = true;
+        }
+        FrameClocks {
+            quarter: steps.contains(&self.cycle),
+            half: self.cycle == steps[1] || self.cycle == steps[3],
+        }
+    }
+}
+
+impl Snapshot for FrameCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.five_step);
+        state.write_bool(self.irq_inhibit);
+        state.write_bool(self.irq_flag);
+        state.write_u16(self.cycle);
+        state.write_option_u16(self.pending_write.map(|(delay, value)| u16::from_le_bytes([delay, value])));
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.five_step = state.read_bool()?;
+        self.irq_inhibit = state.read_bool()?;
+        self.irq_flag = state.read_bool()?;
+        self.cycle = state.read_u16()?;
+        self.pending_write = state.read_option_u16()?.map(|packed| {
+            let [delay, value] = packed.to_le_bytes();
+            (delay, value)
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod frame_counter_tests {
+    use super::*;
+
+    // Cycles on which half frame clocks happened
+    fn run(frame_counter: &mut FrameCounter, cycles: u32) -> Vec<u32> {
+        (1..=cycles).filter(|_| frame_counter.clock().half).collect()
+    }
+
+    #[test]
+    fn test_four_step_mode() {
+        let mut frame_counter = FrameCounter::new(Region::NTSC);
+        assert_eq!(run(&mut frame_counter, 2 * 29830), [14913, 29829, 29830 + 14913, 29830 + 29829]);
+        assert!(frame_counter.irq_flag);
+    }
+
+    #[test]
+    fn test_five_step_mode() {
+        let mut frame_counter = FrameCounter::new(Region::NTSC);
+        frame_counter.write(0x80, false);
+        // immediate clock 3 cycles after write
+        assert_eq!(run(&mut frame_counter, 3 + 37281), [3, 3 + 14913, 3 + 37281]);
+        assert!(!frame_counter.irq_flag);
+    }
+
+    #[test]
+    fn test_irq_inhibit() {
+        let mut frame_counter = FrameCounter::new(Region::PAL);
+        frame_counter.irq_flag = true;
+        frame_counter.write(0x40, true);
+        assert!(!frame_counter.irq_flag);
+        run(&mut frame_counter, 40000);
+        assert!(!frame_counter.irq_flag);
+    }
+}
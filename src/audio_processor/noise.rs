@@ -0,0 +1,127 @@
+use crate::{ region::Region, snapshot::{ Snapshot, StateReader, StateWriter } };
+
+use super::units::{ Envelope, LengthCounter };
+
+// In CPU cycles
+const NTSC_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const PAL_PERIODS: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+
+// Pseudo-random noise channel, $400C-$400F
+#[derive(Debug)]
+pub struct Noise {
+    periods: &'static [u16; 16],
+    short_mode: bool, // 93 step sequence instead of 32767 steps, sounds metallic
+    timer_period: u16,
+    timer: u16,
+    shift_register: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(region: Region) -> Self {
+        let periods = match region {
+            Region::NTSC | Region::Dendy => &NTSC_PERIODS,
+            Region::PAL => &PAL_PERIODS,
+        };
+        Self {
+            periods,
+            short_mode: false,
+            timer_period: periods[0],
+            timer: 0,
+            shift_register: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write_control(value);
+            },
+            1 => (),
+            2 => {
+                self.short_mode = value & 0x80 != 0;
+                self.timer_period = self.periods[(value & 0x0F) as usize];
+            },
+            _ => {
+                self.length.load(value >> 3);
+                self.envelope.restart();
+            },
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn get_output(&self) -> u8 {
+        if self.shift_register & 1 != 0 || !self.length.is_active() {
+            0
+        } else {
+            self.envelope.get_volume()
+        }
+    }
+}
+
+impl Snapshot for Noise {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.short_mode);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u16(self.shift_register);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.short_mode = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.shift_register = state.read_u16()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod noise_tests {
+    use super::*;
+
+    // Number of timer periods until shift register comes back to its starting value
+    fn get_sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new(Region::NTSC);
+        noise.write(2, if short_mode { 0x80 } else { 0x00 });
+        let start = noise.shift_register;
+        (1..).find(|_| {
+            for _ in 0..4 {
+                noise.clock_timer();
+            }
+            noise.shift_register == start
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_sequence_lengths() {
+        assert_eq!(get_sequence_length(false), 32767);
+        assert_eq!(get_sequence_length(true), 93);
+    }
+
+    #[test]
+    fn test_region_periods() {
+        let mut noise = Noise::new(Region::PAL);
+        noise.write(2, 0x0F);
+        assert_eq!(noise.timer_period, 3778);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::units::{ Envelope, LengthCounter };
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+// Square wave channel, $4000-$4003 and $4004-$4007
+#[derive(Debug, Default)]
+pub struct Pulse {
+    is_first: bool, // pulse 1 sweep negates with ones' complement
+    without_sweep: bool, // MMC5 pulses have no sweep unit, so nothing mutes them
+    duty: u8,
+    sequence_step: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Pulse {
+    pub fn new(is_first: bool) -> Self {
+        Self { is_first, ..Self::default() }
+    }
+
+    pub fn new_without_sweep() -> Self {
+        Self { without_sweep: true, ..Self::default() }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halted = value & 0x20 != 0;
+                self.envelope.write_control(value);
+            },
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.sequence_step = 0;
+                self.envelope.restart();
+            },
+        }
+    }
+
+    // Clocked every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn get_sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.timer_period.saturating_sub(change + self.is_first as u16)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // Sweep unit mutes channel even when it's disabled
+    fn is_muted(&self) -> bool {
+        !self.without_sweep && (self.timer_period < 8 || self.get_sweep_target() > 0x07FF)
+    }
+
+    // Clocked by half frames
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_muted() {
+            self.timer_period = self.get_sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn get_output(&self) -> u8 {
+        if self.is_muted() || !self.length.is_active() || DUTY_TABLE[self.duty as usize][self.sequence_step as usize] == 0 {
+            0
+        } else {
+            self.envelope.get_volume()
+        }
+    }
+}
+
+impl Snapshot for Pulse {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.duty);
+        state.write_u8(self.sequence_step);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_bool(self.sweep_enabled);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_negate);
+        state.write_u8(self.sweep_shift);
+        state.write_u8(self.sweep_divider);
+        state.write_bool(self.sweep_reload);
+        self.envelope.save_state(state);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.duty = state.read_u8()?;
+        self.sequence_step = state.read_u8()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.sweep_enabled = state.read_bool()?;
+        self.sweep_period = state.read_u8()?;
+        self.sweep_negate = state.read_bool()?;
+        self.sweep_shift = state.read_u8()?;
+        self.sweep_divider = state.read_u8()?;
+        self.sweep_reload = state.read_bool()?;
+        self.envelope.load_state(state)?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod pulse_tests {
+    use super::*;
+
+    fn create_playing_pulse(is_first: bool) -> Pulse {
+        let mut pulse = Pulse::new(is_first);
+        pulse.length.set_enabled(true);
+        pulse.write(0, 0b1011_1111); // 50% duty, constant volume 15
+        pulse.write(2, 0x00);
+        pulse.write(3, 0x01); // period $100
+        pulse
+    }
+
+    #[test]
+    fn test_duty_waveform() {
+        let mut pulse = create_playing_pulse(true);
+        let mut waveform = vec![];
+        for _ in 0..8 {
+            waveform.push(pulse.get_output());
+            for _ in 0..=0x100 {
+                pulse.clock_timer();
+            }
+        }
+        assert_eq!(waveform, [0, 15, 15, 15, 15, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_low_period_is_muted() {
+        let mut pulse = create_playing_pulse(true);
+        pulse.clock_timer(); // step 1 is high for every duty
+        pulse.write(3, 0x00);
+        pulse.write(2, 0x07);
+        pulse.clock_timer();
+        assert_eq!(pulse.get_output(), 0);
+    }
+
+    #[test]
+    fn test_sweep_negate_differs_between_channels() {
+        for (is_first, expected_period) in [(true, 0x0FF), (false, 0x100)] {
+            let mut pulse = create_playing_pulse(is_first);
+            pulse.write(2, 0x00);
+            pulse.write(3, 0x02); // period $200
+            pulse.write(1, 0b1000_1001); // enabled, period 0, negate, shift 1
+            pulse.clock_sweep();
+            assert_eq!(pulse.timer_period, expected_period);
+        }
+    }
+
+    #[test]
+    fn test_sweep_overflow_mutes() {
+        let mut pulse = create_playing_pulse(true);
+        pulse.write(2, 0xFF);
+        pulse.write(3, 0x06); // period $6FF, target with shift 1 is over $7FF
+        pulse.write(1, 0b0000_0001); // sweep disabled still mutes
+        pulse.clock_timer();
+        assert_eq!(pulse.get_output(), 0);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::units::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Triangle wave channel, $4008-$400B. Has no volume control, only linear and length counters gate it.
+#[derive(Debug, Default)]
+pub struct Triangle {
+    control: bool, // also halts length counter
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    sequence_step: u8,
+    pub length: LengthCounter,
+}
+
+impl Triangle {
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halted = self.control;
+                self.linear_reload_value = value & 0x7F;
+            },
+            1 => (),
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            },
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // Periods below 2 are ultrasonic and would only alias, games use them to silence the channel
+            if self.length.is_active() && self.linear_counter > 0 && self.timer_period >= 2 {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Clocked by quarter frames
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // 0-15, silenced triangle holds its last step instead of dropping to 0
+    pub fn get_output(&self) -> u8 {
+        SEQUENCE[self.sequence_step as usize]
+    }
+}
+
+impl Snapshot for Triangle {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.control);
+        state.write_u8(self.linear_reload_value);
+        state.write_u8(self.linear_counter);
+        state.write_bool(self.linear_reload);
+        state.write_u16(self.timer_period);
+        state.write_u16(self.timer);
+        state.write_u8(self.sequence_step);
+        self.length.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.control = state.read_bool()?;
+        self.linear_reload_value = state.read_u8()?;
+        self.linear_counter = state.read_u8()?;
+        self.linear_reload = state.read_bool()?;
+        self.timer_period = state.read_u16()?;
+        self.timer = state.read_u16()?;
+        self.sequence_step = state.read_u8()?;
+        self.length.load_state(state)
+    }
+}
+
+#[cfg(test)]
+mod triangle_tests {
+    use super::*;
+
+    fn create_playing_triangle(period: u16) -> Triangle {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        triangle.write(0, 0x7F);
+        triangle.write(2, period as u8);
+        triangle.write(3, (period >> 8) as u8);
+        triangle.clock_linear_counter();
+        triangle
+    }
+
+    #[test]
+    fn test_waveform() {
+        let mut triangle = create_playing_triangle(2);
+        let mut waveform = vec![];
+        for _ in 0..32 {
+            for _ in 0..3 {
+                triangle.clock_timer();
+            }
+            waveform.push(triangle.get_output());
+        }
+        assert_eq!(waveform[..4], [14, 13, 12, 11]);
+        assert_eq!(waveform[14..18], [0, 0, 1, 2]);
+        assert_eq!(waveform[31], 15);
+    }
+
+    #[test]
+    fn test_linear_counter_stops_sequencer() {
+        let mut triangle = create_playing_triangle(2);
+        triangle.write(0, 0x01); // control off, so reload flag clears after next clock
+        triangle.write(3, 0x00);
+        triangle.clock_linear_counter(); // reloaded to 1
+        triangle.clock_linear_counter(); // 0
+        for _ in 0..30 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.get_output(), 15);
+    }
+
+    #[test]
+    fn test_ultrasonic_period_holds_output() {
+        let mut triangle = create_playing_triangle(0);
+        for _ in 0..10 {
+            triangle.clock_timer();
+        }
+        assert_eq!(triangle.get_output(), 15);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+// Indexed by top 5 bits of length counter load registers
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Silences channel after a while, clocked by half frames
+#[derive(Debug, Default)]
+pub struct LengthCounter {
+    counter: u8,
+    pub halted: bool,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    // Disabled channel ($4015) can't be loaded and loses what it had
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.counter);
+        state.write_bool(self.halted);
+        state.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.counter = state.read_u8()?;
+        self.halted = state.read_bool()?;
+        self.enabled = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Either constant volume or sawtooth fading from 15 to 0, clocked by quarter frames
+#[derive(Debug, Default)]
+pub struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+    looped: bool,
+    constant: bool,
+    volume: u8, // also divider period
+}
+
+impl Envelope {
+    // Low 6 bits of $4000, $4004 and $400C
+    pub fn write_control(&mut self, value: u8) {
+        self.looped = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looped {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn get_volume(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+impl Snapshot for Envelope {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.start);
+        state.write_u8(self.divider);
+        state.write_u8(self.decay);
+        state.write_bool(self.looped);
+        state.write_bool(self.constant);
+        state.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.start = state.read_bool()?;
+        self.divider = state.read_u8()?;
+        self.decay = state.read_u8()?;
+        self.looped = state.read_bool()?;
+        self.constant = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod units_tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counter() {
+        let mut length = LengthCounter::default();
+        length.load(1);
+        assert!(!length.is_active()); // channel is disabled
+
+        length.set_enabled(true);
+        length.load(3); // 2 half frames
+        length.clock();
+        assert!(length.is_active());
+        length.clock();
+        assert!(!length.is_active());
+
+        length.load(0);
+        length.set_enabled(false);
+        assert!(!length.is_active());
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::default();
+        envelope.write_control(0x01); // divider period 1, so decay steps every other clock
+        envelope.restart();
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 15);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 14);
+
+        envelope.write_control(0x18); // constant volume
+        assert_eq!(envelope.get_volume(), 8);
+    }
+
+    #[test]
+    fn test_envelope_loop() {
+        let mut envelope = Envelope::default();
+        envelope.write_control(0x20); // loop, divider period 0
+        envelope.restart();
+        for _ in 0..16 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.get_volume(), 0);
+        envelope.clock();
+        assert_eq!(envelope.get_volume(), 15);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::processor::settings::{ Settings, SettingsObserver };
+
+// Pause, frame advance, fast forward and slow motion. Hotkeys go through here too,
+// so code embedding the emulator controls it the same way the player does.
+pub struct EmulationControl {
+    paused: bool,
+    frame_advance_pending: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    fast_forward_speed: f64,
+    slow_motion_speed: f64,
+}
+
+impl EmulationControl {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            paused: false,
+            frame_advance_pending: false,
+            fast_forward: false,
+            slow_motion: false,
+            fast_forward_speed: settings.fast_forward_speed,
+            slow_motion_speed: settings.slow_motion_speed,
+        }
+    }
+
+    #[allow(dead_code)] // for embedding
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    #[allow(dead_code)] // for embedding
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.frame_advance_pending = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.frame_advance_pending = false;
+    }
+
+    #[allow(dead_code)] // for embedding
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Running emulator gets paused, paused one runs one more frame
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.frame_advance_pending = true;
+        } else {
+            self.paused = true;
+        }
+    }
+
+    pub fn set_fast_forward(&mut self, enabled: bool) {
+        self.fast_forward = enabled;
+    }
+
+    pub fn set_slow_motion(&mut self, enabled: bool) {
+        self.slow_motion = enabled;
+    }
+
+    // Asked before every frame, frame advance is used up by it
+    pub fn should_run_frame(&mut self) -> bool {
+        !self.paused || std::mem::take(&mut self.frame_advance_pending)
+    }
+
+    pub fn get_speed_multiplier(&self) -> f64 {
+        let mut multiplier = 1.0;
+        if self.fast_forward { multiplier *= self.fast_forward_speed }
+        if self.slow_motion { multiplier *= self.slow_motion_speed }
+        multiplier
+    }
+}
+
+impl SettingsObserver for EmulationControl {
+    fn settings_updated(&mut self, settings: &Settings) {
+        self.fast_forward_speed = settings.fast_forward_speed;
+        self.slow_motion_speed = settings.slow_motion_speed;
+    }
+}
+
+#[cfg(test)]
+mod control_tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_and_frame_advance() {
+        let mut control = EmulationControl::new(&Settings::default());
+        assert!(control.should_run_frame());
+
+        control.advance_frame();
+        assert!(control.is_paused());
+        assert!(!control.should_run_frame());
+
+        control.advance_frame();
+        assert!(control.should_run_frame());
+        assert!(!control.should_run_frame());
+
+        control.toggle_pause();
+        assert!(control.should_run_frame());
+        assert!(control.should_run_frame());
+    }
+
+    #[test]
+    fn test_resume_drops_frame_advance() {
+        let mut control = EmulationControl::new(&Settings::default());
+        control.pause();
+        control.advance_frame();
+        control.resume();
+        control.pause();
+
+        assert!(!control.should_run_frame());
+    }
+
+    #[test]
+    fn test_speed_multipliers() {
+        let mut control = EmulationControl::new(&Settings { fast_forward_speed: 3.0, slow_motion_speed: 0.5, ..Settings::default() });
+        assert_eq!(control.get_speed_multiplier(), 1.0);
+
+        control.set_fast_forward(true);
+        assert_eq!(control.get_speed_multiplier(), 3.0);
+        control.set_slow_motion(true);
+        assert_eq!(control.get_speed_multiplier(), 1.5);
+
+        control.settings_updated(&Settings { fast_forward_speed: 8.0, slow_motion_speed: 0.25, ..Settings::default() });
+        assert_eq!(control.get_speed_multiplier(), 2.0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::{ Duration, Instant };
+
+use crate::{ processor::settings::{ Settings, SettingsObserver }, region::Region };
+
+// Late by more than this and pacer gives up catching up, like after pause or a slow frame
+const MAX_LATENESS_FRAMES: u32 = 2;
+// How much audio buffer fill can stretch or shrink a frame
+const MAX_AUDIO_ADJUSTMENT: f64 = 0.005;
+
+// Emulator runs a whole frame as fast as it can, then pacer sleeps until the frame's deadline.
+// Deadlines are a fixed frame length apart, so oversleeping one frame is made up on the next.
+// With audio sync frame length is nudged to keep audio buffer at target fill, because sound card
+// clock drifts from the system one and buffer would slowly run dry or overflow otherwise.
+pub struct FramePacer {
+    frame_duration: Duration, // at normal speed
+    emulation_speed: f64,
+    speed_multiplier: f64, // fast forward and slow motion
+    audio_target_fill: Option<usize>, // in samples
+    audio_adjustment: f64,
+    next_deadline: Option<Instant>,
+    fps_window_start: Option<Instant>,
+    fps_frames: u32,
+    average_fps: Option<f64>,
+}
+
+impl FramePacer {
+    pub fn new(region: Region) -> Self {
+        Self {
+            frame_duration: region.get_frame_duration(),
+            emulation_speed: 1.0,
+            speed_multiplier: 1.0,
+            audio_target_fill: None,
+            audio_adjustment: 1.0,
+            next_deadline: None,
+            fps_window_start: None,
+            fps_frames: 0,
+            average_fps: None,
+        }
+    }
+
+    pub fn set_speed_multiplier(&mut self, multiplier: f64) {
+        self.speed_multiplier = multiplier;
+    }
+
+    #[allow(dead_code)] // for audio output
+    pub fn enable_audio_sync(&mut self, target_fill: usize) {
+        self.audio_target_fill = Some(target_fill);
+    }
+
+    // Called by audio output once per frame with number of samples waiting to be played
+    #[allow(dead_code)] // for audio output
+    pub fn report_audio_fill(&mut self, buffered_samples: usize) {
+        if let Some(target_fill) = self.audio_target_fill {
+            let error = (buffered_samples as f64 - target_fill as f64) / target_fill.max(1) as f64;
+            // Too much audio buffered means emulator runs ahead, so frames get a bit longer
+            self.audio_adjustment = 1.0 + (error * MAX_AUDIO_ADJUSTMENT).clamp(-MAX_AUDIO_ADJUSTMENT, MAX_AUDIO_ADJUSTMENT);
+        }
+    }
+
+    fn get_paced_frame_duration(&self) -> Duration {
+        self.frame_duration.mul_f64(self.audio_adjustment / (self.emulation_speed * self.speed_multiplier))
+    }
+
+    pub fn wait_for_next_frame(&mut self) {
+        let sleep_time = self.schedule_frame(Instant::now());
+        if !sleep_time.is_zero() {
+            std::thread::sleep(sleep_time);
+        }
+        self.count_frame(Instant::now());
+    }
+
+    // Moves deadline one frame forward and returns how long to sleep until it
+    fn schedule_frame(&mut self, now: Instant) -> Duration {
+        let frame_duration = self.get_paced_frame_duration();
+        let deadline = match self.next_deadline {
+            Some(deadline) if now <= deadline + frame_duration * MAX_LATENESS_FRAMES => deadline,
+            _ => now,
+        };
+        self.next_deadline = Some(deadline + frame_duration);
+        deadline.saturating_duration_since(now)
+    }
+
+    fn count_frame(&mut self, now: Instant) {
+        let window_start = *self.fps_window_start.get_or_insert(now);
+        self.fps_frames += 1;
+        let elapsed = now.duration_since(window_start);
+        if elapsed >= Duration::from_secs(1) {
+            self.average_fps = Some(self.fps_frames as f64 / elapsed.as_secs_f64());
+            self.fps_window_start = Some(now);
+            self.fps_frames = 0;
+        }
+    }
+
+    // New average about once a second
+    pub fn take_average_fps(&mut self) -> Option<f64> {
+        self.average_fps.take()
+    }
+}
+
+impl SettingsObserver for FramePacer {
+    fn settings_updated(&mut self, settings: &Settings) {
+        self.emulation_speed = settings.emulation_speed;
+    }
+}
+
+#[cfg(test)]
+mod frame_pacer_tests {
+    use super::*;
+
+    fn create_pacer(frame_millis: u64) -> FramePacer {
+        let mut pacer = FramePacer::new(Region::NTSC);
+        pacer.frame_duration = Duration::from_millis(frame_millis);
+        pacer
+    }
+
+    #[test]
+    fn test_deadlines_are_frame_apart() {
+        let mut pacer = create_pacer(20);
+        let start = Instant::now();
+
+        assert_eq!(pacer.schedule_frame(start), Duration::ZERO);
+        assert_eq!(pacer.schedule_frame(start + Duration::from_millis(5)), Duration::from_millis(15));
+        // overslept previous frame, so this one is shorter
+        assert_eq!(pacer.schedule_frame(start + Duration::from_millis(32)), Duration::from_millis(8));
+    }
+
+    #[test]
+    fn test_late_frames_resync() {
+        let mut pacer = create_pacer(20);
+        let start = Instant::now();
+        pacer.schedule_frame(start);
+
+        let after_pause = start + Duration::from_secs(5);
+        assert_eq!(pacer.schedule_frame(after_pause), Duration::ZERO);
+        assert_eq!(pacer.schedule_frame(after_pause + Duration::from_millis(10)), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_speed() {
+        let mut pacer = create_pacer(20);
+        pacer.settings_updated(&Settings { emulation_speed: 2.0, ..Settings::default() });
+        pacer.set_speed_multiplier(0.25);
+
+        assert_eq!(pacer.get_paced_frame_duration(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_audio_fill_adjusts_frame_length() {
+        let mut pacer = create_pacer(20);
+        pacer.report_audio_fill(0);
+        assert_eq!(pacer.get_paced_frame_duration(), Duration::from_millis(20)); // audio sync is off
+
+        pacer.enable_audio_sync(1000);
+        pacer.report_audio_fill(2000);
+        assert!(pacer.get_paced_frame_duration() > Duration::from_millis(20));
+        pacer.report_audio_fill(500);
+        assert!(pacer.get_paced_frame_duration() < Duration::from_millis(20));
+        pacer.report_audio_fill(1000);
+        assert_eq!(pacer.get_paced_frame_duration(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_average_fps() {
+        let mut pacer = create_pacer(20);
+        let start = Instant::now();
+        for frame in 0..50 {
+            pacer.count_frame(start + Duration::from_millis(20) * frame);
+            assert_eq!(pacer.take_average_fps(), None);
+        }
+        pacer.count_frame(start + Duration::from_millis(1000));
+
+        assert_eq!(pacer.take_average_fps(), Some(51.0));
+        assert_eq!(pacer.take_average_fps(), None);
+    }
+}
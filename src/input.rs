@@ -0,0 +1,257 @@
+use minifb::Key;
+
+pub mod bindings;
+pub mod controller_port;
+pub mod standard_controller;
+pub mod zapper;
+pub mod empty_port;
+pub mod four_score;
+pub mod movie;
+
+use crate::pixel_processor::PPUPtrWrapper;
+
+pub use bindings::KeyBindings;
+pub use controller_port::{ ControllerPortDevice, ControllerPorts };
+pub use standard_controller::StandardController;
+pub use zapper::Zapper;
+pub use empty_port::EmptyPort;
+pub use four_score::{ FamicomFourPlayer, FourScore };
+
+pub const PLAYER_COUNT: usize = 4;
+
+// Bits are in order the controller reports them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NesButton {
+    A,
+    B,
+    Select,
+    Start,
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl NesButton {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "a" => Ok(NesButton::A),
+            "b" => Ok(NesButton::B),
+            "select" => Ok(NesButton::Select),
+            "start" => Ok(NesButton::Start),
+            "up" => Ok(NesButton::Up),
+            "down" => Ok(NesButton::Down),
+            "left" => Ok(NesButton::Left),
+            "right" => Ok(NesButton::Right),
+            _ => Err("Unknown button name"),
+        }
+    }
+
+    pub fn get_bit(&self) -> u8 {
+        1 << (*self as u8)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    Reset,
+    Pause,
+    FrameAdvance,
+    SaveState,
+    LoadState,
+    FastForward, // active while held
+    SlowMotion, // active while held
+    SpeedUp,
+    SpeedDown,
+    ReloadBindings,
+    Rewind, // active while held
+    Screenshot,
+    NextTrack, // NSF only
+    PreviousTrack,
+    SwitchDiskSide, // FDS only
+}
+
+impl Hotkey {
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name {
+            "reset" => Ok(Hotkey::Reset),
+            "pause" => Ok(Hotkey::Pause),
+            "frame_advance" => Ok(Hotkey::FrameAdvance),
+            "save_state" => Ok(Hotkey::SaveState),
+            "load_state" => Ok(Hotkey::LoadState),
+            "fast_forward" => Ok(Hotkey::FastForward),
+            "slow_motion" => Ok(Hotkey::SlowMotion),
+            "speed_up" => Ok(Hotkey::SpeedUp),
+            "speed_down" => Ok(Hotkey::SpeedDown),
+            "reload_bindings" => Ok(Hotkey::ReloadBindings),
+            "rewind" => Ok(Hotkey::Rewind),
+            "screenshot" => Ok(Hotkey::Screenshot),
+            "next_track" => Ok(Hotkey::NextTrack),
+            "previous_track" => Ok(Hotkey::PreviousTrack),
+            "switch_disk_side" => Ok(Hotkey::SwitchDiskSide),
+            _ => Err("Unknown hotkey name"),
+        }
+    }
+}
+
+// Keyboard state is sampled once per frame, when window receives new key events.
+// Headless input ignores keyboard and reports buttons set from code.
+pub struct Input {
+    bindings: KeyBindings,
+    bindings_path: Option<String>,
+    held_keys: Vec<Key>,
+    mouse_position: Option<(usize, usize)>, // in NES pixels
+    mouse_button: bool,
+    headless_buttons: Option<[u8; PLAYER_COUNT]>,
+}
+
+impl Input {
+    pub fn new(bindings_path: Option<String>) -> Result<Self, &'static str> {
+        let bindings = match &bindings_path {
+            Some(path) => KeyBindings::load_file(path)?,
+            None => KeyBindings::default(),
+        };
+        Ok(Self {
+            bindings,
+            bindings_path,
+            held_keys: vec![],
+            mouse_position: None,
+            mouse_button: false,
+            headless_buttons: None,
+        })
+    }
+
+    #[allow(dead_code)] // for tests and headless runs
+    pub fn new_headless() -> Self {
+        Self {
+            bindings: KeyBindings::default(),
+            bindings_path: None,
+            held_keys: vec![],
+            mouse_position: None,
+            mouse_button: false,
+            headless_buttons: Some([0; PLAYER_COUNT]),
+        }
+    }
+
+    // Only has effect on headless input
+    #[allow(dead_code)] // for tests and headless runs
+    pub fn set_buttons(&mut self, player: usize, buttons: u8) {
+        if let Some(headless_buttons) = &mut self.headless_buttons {
+            headless_buttons[player] = buttons;
+        }
+    }
+
+    // Returns hotkeys that were pressed since last update
+    pub fn update(&mut self, held_keys: Vec<Key>) -> Vec<Hotkey> {
+        let mut pressed_hotkeys = vec![];
+        for (key, hotkey) in &self.bindings.hotkeys {
+            if held_keys.contains(key) && !self.held_keys.contains(key) && !pressed_hotkeys.contains(hotkey) {
+                pressed_hotkeys.push(*hotkey);
+            }
+        }
+        self.held_keys = held_keys;
+        pressed_hotkeys
+    }
+
+    // Position is None when mouse isn't over the picture
+    pub fn update_mouse(&mut self, position: Option<(f32, f32)>, button: bool) {
+        self.mouse_position = position
+            .filter(|(x, y)| (0.0..256.0).contains(x) && (0.0..240.0).contains(y))
+            .map(|(x, y)| (x as usize, y as usize));
+        self.mouse_button = button;
+    }
+
+    // Movie playback replaces keyboard, None returns control back to keyboard
+    pub fn override_buttons(&mut self, buttons: Option<[u8; PLAYER_COUNT]>) {
+        self.headless_buttons = buttons;
+    }
+
+    pub fn get_mouse(&self) -> (Option<(usize, usize)>, bool) {
+        (self.mouse_position, self.mouse_button)
+    }
+
+    pub fn is_hotkey_held(&self, hotkey: Hotkey) -> bool {
+        self.bindings.hotkeys.iter()
+            .any(|(key, bound_hotkey)| *bound_hotkey == hotkey && self.held_keys.contains(key))
+    }
+
+    pub fn get_buttons(&self, player: usize) -> u8 {
+        if let Some(headless_buttons) = self.headless_buttons {
+            return headless_buttons[player];
+        }
+        let mut value = 0x00;
+        for (key, button) in &self.bindings.buttons[player] {
+            if self.held_keys.contains(key) {
+                value |= button.get_bit();
+            }
+        }
+        value
+    }
+
+    pub fn reload_bindings(&mut self) -> Result<(), &'static str> {
+        if let Some(path) = &self.bindings_path {
+            self.bindings = KeyBindings::load_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+// Device names for --port1/--port2, four player adapters take both ports
+pub fn create_port_device(name: &str, port: usize, ppu: PPUPtrWrapper) -> Result<Box<dyn ControllerPortDevice>, &'static str> {
+    match name {
+        "controller" => Ok(Box::new(StandardController::new(port))),
+        "zapper" => Ok(Box::new(Zapper::new(ppu))),
+        "fourscore" => Ok(Box::new(FourScore::new(port))),
+        "famicom4p" => Ok(Box::new(FamicomFourPlayer::new(port))),
+        "none" => Ok(Box::new(EmptyPort)),
+        _ => Err("Unknown port device, expected controller, zapper, fourscore, famicom4p or none"),
+    }
+}
+
+#[cfg(test)]
+mod input_tests {
+    use super::*;
+
+    #[test]
+    fn test_buttons() {
+        let mut input = Input::new(None).unwrap();
+        input.update(vec![Key::Z, Key::Right, Key::P]);
+
+        assert_eq!(input.get_buttons(0), 0b_1000_0001);
+        assert_eq!(input.get_buttons(1), 0b_0000_1000);
+    }
+
+    #[test]
+    fn test_headless_buttons() {
+        let mut input = Input::new_headless();
+        input.set_buttons(3, 0b_0100_0000);
+        input.update(vec![Key::Z]);
+
+        assert_eq!(input.get_buttons(0), 0);
+        assert_eq!(input.get_buttons(3), 0b_0100_0000);
+    }
+
+    #[test]
+    fn test_hotkeys_trigger_on_press() {
+        let mut input = Input::new(None).unwrap();
+
+        assert_eq!(input.update(vec![Key::F2, Key::Tab]), vec![Hotkey::Pause, Hotkey::FastForward]);
+        assert_eq!(input.update(vec![Key::F2, Key::Tab]), vec![]);
+        assert!(input.is_hotkey_held(Hotkey::FastForward));
+        assert_eq!(input.update(vec![]), vec![]);
+        assert!(!input.is_hotkey_held(Hotkey::FastForward));
+        assert_eq!(input.update(vec![Key::F2]), vec![Hotkey::Pause]);
+    }
+
+    #[test]
+    fn test_mouse_outside_picture() {
+        let mut input = Input::new(None).unwrap();
+
+        input.update_mouse(Some((12.5, 200.0)), true);
+        assert_eq!(input.get_mouse(), (Some((12, 200)), true));
+        input.update_mouse(Some((12.5, 240.0)), true);
+        assert_eq!(input.get_mouse(), (None, true));
+        input.update_mouse(None, false);
+        assert_eq!(input.get_mouse(), (None, false));
+    }
+}
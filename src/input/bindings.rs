@@ -0,0 +1,196 @@
+use minifb::Key;
+
+use super::{ Hotkey, NesButton, PLAYER_COUNT };
+
+// Keys as they're named in bindings file, names match minifb::Key
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+    Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal, Key::LeftBracket, Key::Minus,
+    Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash, Key::Backspace, Key::Delete, Key::End, Key::Enter,
+    Key::Escape, Key::Home, Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock, Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6, Key::NumPad7,
+    Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus,
+    Key::NumPadPlus, Key::NumPadEnter, Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+// Which keys are bound to NES buttons of every player and to emulator hotkeys.
+// Bindings file has one binding per line, like `player1.a = Z` or `hotkey.reset = F1, R`,
+// empty value unbinds the action and lines starting with # are ignored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBindings {
+    pub buttons: [Vec<(Key, NesButton)>; PLAYER_COUNT],
+    pub hotkeys: Vec<(Key, Hotkey)>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            buttons: [
+                vec![
+                    (Key::Z, NesButton::A),
+                    (Key::X, NesButton::B),
+                    (Key::C, NesButton::Select),
+                    (Key::V, NesButton::Start),
+                    (Key::Up, NesButton::Up),
+                    (Key::Down, NesButton::Down),
+                    (Key::Left, NesButton::Left),
+                    (Key::Right, NesButton::Right),
+                ],
+                vec![
+                    (Key::M, NesButton::A),
+                    (Key::N, NesButton::B),
+                    (Key::O, NesButton::Select),
+                    (Key::P, NesButton::Start),
+                    (Key::I, NesButton::Up),
+                    (Key::K, NesButton::Down),
+                    (Key::J, NesButton::Left),
+                    (Key::L, NesButton::Right),
+                ],
+                vec![
+                    (Key::NumPad2, NesButton::A),
+                    (Key::NumPad1, NesButton::B),
+                    (Key::NumPad7, NesButton::Select),
+                    (Key::NumPad9, NesButton::Start),
+                    (Key::NumPad8, NesButton::Up),
+                    (Key::NumPad5, NesButton::Down),
+                    (Key::NumPad4, NesButton::Left),
+                    (Key::NumPad6, NesButton::Right),
+                ],
+                vec![
+                    (Key::E, NesButton::A),
+                    (Key::Q, NesButton::B),
+                    (Key::Key1, NesButton::Select),
+                    (Key::Key2, NesButton::Start),
+                    (Key::W, NesButton::Up),
+                    (Key::S, NesButton::Down),
+                    (Key::A, NesButton::Left),
+                    (Key::D, NesButton::Right),
+                ],
+            ],
+            hotkeys: vec![
+                (Key::F1, Hotkey::Reset),
+                (Key::F2, Hotkey::Pause),
+                (Key::F3, Hotkey::FrameAdvance),
+                (Key::F5, Hotkey::SaveState),
+                (Key::F7, Hotkey::LoadState),
+                (Key::Tab, Hotkey::FastForward),
+                (Key::F4, Hotkey::SlowMotion),
+                (Key::Equal, Hotkey::SpeedUp),
+                (Key::Minus, Hotkey::SpeedDown),
+                (Key::F9, Hotkey::ReloadBindings),
+                (Key::Backspace, Hotkey::Rewind),
+                (Key::F12, Hotkey::Screenshot),
+                (Key::PageDown, Hotkey::NextTrack),
+                (Key::PageUp, Hotkey::PreviousTrack),
+                (Key::Insert, Hotkey::SwitchDiskSide),
+            ],
+        }
+    }
+}
+
+impl KeyBindings {
+    // Bindings file only overrides actions it mentions, the rest stay default
+    pub fn parse(input: &str) -> Result<Self, &'static str> {
+        let mut bindings = KeyBindings::default();
+        for line in input.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (action, keys) = line.split_once('=').ok_or("Binding should look like `action = key`")?;
+            let (section, name) = action.trim().split_once('.').ok_or("Action should look like `player1.a` or `hotkey.reset`")?;
+            let keys = parse_key_list(keys)?;
+            if section == "hotkey" {
+                let hotkey = Hotkey::from_name(name)?;
+                bindings.hotkeys.retain(|(_, bound_hotkey)| *bound_hotkey != hotkey);
+                bindings.hotkeys.extend(keys.iter().map(|key| (*key, hotkey)));
+            } else {
+                let player = parse_player(section)?;
+                let button = NesButton::from_name(name)?;
+                bindings.buttons[player].retain(|(_, bound_button)| *bound_button != button);
+                bindings.buttons[player].extend(keys.iter().map(|key| (*key, button)));
+            }
+        }
+        Ok(bindings)
+    }
+
+    pub fn load_file(path: &str) -> Result<Self, &'static str> {
+        let input = std::fs::read_to_string(path).map_err(|_| "Couldn't read bindings file")?;
+        Self::parse(&input)
+    }
+}
+
+// player1 is index 0
+fn parse_player(section: &str) -> Result<usize, &'static str> {
+    let number: usize = section.strip_prefix("player")
+        .and_then(|number| number.parse().ok())
+        .ok_or("Unknown section, expected playerN or hotkey")?;
+    if number == 0 || number > PLAYER_COUNT {
+        return Err("No such player");
+    }
+    Ok(number - 1)
+}
+
+fn parse_key_list(keys: &str) -> Result<Vec<Key>, &'static str> {
+    keys.split(',')
+        .map(|key| key.trim())
+        .filter(|key| !key.is_empty())
+        .map(parse_key)
+        .collect()
+}
+
+fn parse_key(name: &str) -> Result<Key, &'static str> {
+    KEYS.iter()
+        .find(|key| format!("{key:?}").eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or("Unknown key name")
+}
+
+#[cfg(test)]
+mod bindings_tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key("z"), Ok(Key::Z));
+        assert_eq!(parse_key("F12"), Ok(Key::F12));
+        assert_eq!(parse_key("numpad0"), Ok(Key::NumPad0));
+        assert!(parse_key("Hyper").is_err());
+    }
+
+    #[test]
+    fn test_parse_overrides_defaults() {
+        let bindings = KeyBindings::parse("
+            # swap A and B
+            player1.a = X
+            player1.b = Z, Space
+            player2.start =
+            hotkey.reset = R
+        ").unwrap();
+        let default = KeyBindings::default();
+
+        assert!(bindings.buttons[0].contains(&(Key::X, NesButton::A)));
+        assert!(bindings.buttons[0].contains(&(Key::Z, NesButton::B)));
+        assert!(bindings.buttons[0].contains(&(Key::Space, NesButton::B)));
+        assert!(!bindings.buttons[0].contains(&(Key::Z, NesButton::A)));
+        assert_eq!(bindings.buttons[0].len(), default.buttons[0].len() + 1);
+        assert!(bindings.buttons[1].iter().all(|(_, button)| *button != NesButton::Start));
+        assert!(bindings.hotkeys.contains(&(Key::R, Hotkey::Reset)));
+        assert!(!bindings.hotkeys.contains(&(Key::F1, Hotkey::Reset)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(KeyBindings::parse("player1.a Z").is_err());
+        assert!(KeyBindings::parse("player9.a = Z").is_err());
+        assert!(KeyBindings::parse("player1.turbo = Z").is_err());
+        assert!(KeyBindings::parse("hotkey.quit = Q").is_err());
+        assert!(KeyBindings::parse("player1.a = Hyper").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::memory::MemoryHandler;
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::Input;
+
+// Anything that can be plugged into controller port
+pub trait ControllerPortDevice: Snapshot {
+    // Called once per frame with fresh input state
+    fn update(&mut self, input: &Input);
+    // Bit 0 of $4016 writes, goes to both ports
+    fn write_strobe(&mut self, strobe: bool);
+    // Only bits 0-4 are driven by device, rest is open bus
+    fn read(&mut self) -> u8;
+}
+
+const DEVICE_BITS: u8 = 0b_0001_1111;
+
+// $4016 (write strobe, read port 1) and $4017 (read port 2)
+pub struct ControllerPorts {
+    ports: [Box<dyn ControllerPortDevice>; 2],
+}
+
+impl ControllerPorts {
+    pub fn new(port_1: Box<dyn ControllerPortDevice>, port_2: Box<dyn ControllerPortDevice>) -> Self {
+        Self {
+            ports: [port_1, port_2],
+        }
+    }
+
+    pub fn update(&mut self, input: &Input) {
+        for port in &mut self.ports {
+            port.update(input);
+        }
+    }
+}
+
+impl MemoryHandler for ControllerPorts {
+    fn read(&mut self, address: u16) -> u8 {
+        // CPU reads these with absolute addressing, so last value on the bus is high byte of address
+        let open_bus = (address >> 8) as u8 & !DEVICE_BITS;
+        let device_bits = match address {
+            0x4016 => self.ports[0].read(),
+            0x4017 => self.ports[1].read(),
+            _ => 0,
+        };
+        open_bus | (device_bits & DEVICE_BITS)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address == 0x4016 {
+            for port in &mut self.ports {
+                port.write_strobe(value & 0b_0000_0001 != 0);
+            }
+        }
+    }
+}
+
+impl Snapshot for ControllerPorts {
+    fn save_state(&self, state: &mut StateWriter) {
+        for port in &self.ports {
+            port.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        for port in &mut self.ports {
+            port.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod controller_port_tests {
+    use super::*;
+
+    // Returns how many times it was read
+    struct CountingDevice(u8);
+
+    impl ControllerPortDevice for CountingDevice {
+        fn update(&mut self, _input: &Input) {}
+        fn write_strobe(&mut self, _strobe: bool) {}
+        fn read(&mut self) -> u8 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    impl Snapshot for CountingDevice {
+        fn save_state(&self, state: &mut StateWriter) {
+            state.write_u8(self.0);
+        }
+
+        fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+            self.0 = state.read_u8()?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_ports_and_open_bus() {
+        let mut ports = ControllerPorts::new(Box::new(CountingDevice(0)), Box::new(CountingDevice(0x1F)));
+
+        assert_eq!(ports.read(0x4016), 0x41);
+        assert_eq!(ports.read(0x4016), 0x42);
+        assert_eq!(ports.read(0x4017), 0x40); // device bits wrapped to 0x20, which is open bus bit
+    }
+}
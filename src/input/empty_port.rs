@@ -0,0 +1,22 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::{ controller_port::ControllerPortDevice, Input };
+
+// Nothing plugged in, only open bus is read
+pub struct EmptyPort;
+
+impl ControllerPortDevice for EmptyPort {
+    fn update(&mut self, _input: &Input) {}
+    fn write_strobe(&mut self, _strobe: bool) {}
+    fn read(&mut self) -> u8 {
+        0
+    }
+}
+
+impl Snapshot for EmptyPort {
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), &'static str> {
+        Ok(())
+    }
+}
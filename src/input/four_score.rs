@@ -0,0 +1,201 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::{ controller_port::ControllerPortDevice, standard_controller::StandardController, Input };
+
+// Four Score plugs into both ports, each side has two controllers and reports its signature after them, most significant bit first.
+// Port 1 side has players 1 and 3, port 2 side has players 2 and 4.
+pub struct FourScore {
+    controllers: [StandardController; 2],
+    signature: u8,
+    strobe: bool,
+    read_count: usize,
+}
+
+impl FourScore {
+    pub fn new(port: usize) -> Self {
+        Self {
+            controllers: [StandardController::new(port), StandardController::new(port + 2)],
+            signature: if port == 0 { 0b_0001_0000 } else { 0b_0010_0000 },
+            strobe: false,
+            read_count: 0,
+        }
+    }
+}
+
+impl ControllerPortDevice for FourScore {
+    fn update(&mut self, input: &Input) {
+        for controller in &mut self.controllers {
+            controller.update(input);
+        }
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.read_count = 0;
+        }
+        for controller in &mut self.controllers {
+            controller.write_strobe(strobe);
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.controllers[0].read();
+        }
+        let value = match self.read_count {
+            0..=7 => self.controllers[0].read(),
+            8..=15 => self.controllers[1].read(),
+            16..=23 => (self.signature >> (23 - self.read_count)) & 0b_0000_0001,
+            _ => 1,
+        };
+        self.read_count = (self.read_count + 1).min(24);
+        value
+    }
+}
+
+impl Snapshot for FourScore {
+    fn save_state(&self, state: &mut StateWriter) {
+        for controller in &self.controllers {
+            controller.save_state(state);
+        }
+        state.write_bool(self.strobe);
+        state.write_u8(self.read_count as u8);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        for controller in &mut self.controllers {
+            controller.load_state(state)?;
+        }
+        self.strobe = state.read_bool()?;
+        self.read_count = state.read_u8()? as usize;
+        Ok(())
+    }
+}
+
+// Famicom four player adapter on expansion port, players 3 and 4 come in on bit 1 without any signature
+pub struct FamicomFourPlayer {
+    controllers: [StandardController; 2],
+}
+
+impl FamicomFourPlayer {
+    pub fn new(port: usize) -> Self {
+        Self {
+            controllers: [StandardController::new(port), StandardController::new(port + 2)],
+        }
+    }
+}
+
+impl ControllerPortDevice for FamicomFourPlayer {
+    fn update(&mut self, input: &Input) {
+        for controller in &mut self.controllers {
+            controller.update(input);
+        }
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        for controller in &mut self.controllers {
+            controller.write_strobe(strobe);
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        self.controllers[0].read() | (self.controllers[1].read() << 1)
+    }
+}
+
+impl Snapshot for FamicomFourPlayer {
+    fn save_state(&self, state: &mut StateWriter) {
+        for controller in &self.controllers {
+            controller.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        for controller in &mut self.controllers {
+            controller.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod four_score_tests {
+    use crate::memory::MemoryHandler;
+    use crate::snapshot;
+    use super::super::ControllerPorts;
+    use super::*;
+
+    fn read_bits(ports: &mut ControllerPorts, address: u16, count: usize) -> Vec<u8> {
+        (0..count).map(|_| ports.read(address) & 0b_0000_0011).collect()
+    }
+
+    fn prepare_input() -> Input {
+        let mut input = Input::new_headless();
+        input.set_buttons(0, 0b_0000_0001); // A
+        input.set_buttons(1, 0b_0000_0010); // B
+        input.set_buttons(2, 0b_0000_1000); // Start
+        input.set_buttons(3, 0b_1000_0000); // Right
+        input
+    }
+
+    #[test]
+    fn test_four_score_reports() {
+        let mut ports = ControllerPorts::new(Box::new(FourScore::new(0)), Box::new(FourScore::new(1)));
+        ports.update(&prepare_input());
+
+        ports.write(0x4016, 1);
+        ports.write(0x4016, 0);
+        assert_eq!(read_bits(&mut ports, 0x4016, 26), vec![
+            1, 0, 0, 0, 0, 0, 0, 0, // player 1
+            0, 0, 0, 1, 0, 0, 0, 0, // player 3
+            0, 0, 0, 1, 0, 0, 0, 0, // signature
+            1, 1,
+        ]);
+        assert_eq!(read_bits(&mut ports, 0x4017, 24), vec![
+            0, 1, 0, 0, 0, 0, 0, 0, // player 2
+            0, 0, 0, 0, 0, 0, 0, 1, // player 4
+            0, 0, 1, 0, 0, 0, 0, 0, // signature
+        ]);
+    }
+
+    #[test]
+    fn test_four_score_restarts_on_strobe() {
+        let mut ports = ControllerPorts::new(Box::new(FourScore::new(0)), Box::new(FourScore::new(1)));
+        ports.update(&prepare_input());
+
+        ports.write(0x4016, 1);
+        ports.write(0x4016, 0);
+        read_bits(&mut ports, 0x4016, 12);
+        ports.write(0x4016, 1);
+        ports.write(0x4016, 0);
+        assert_eq!(read_bits(&mut ports, 0x4016, 2), vec![1, 0]);
+    }
+
+    #[test]
+    fn test_snapshot_mid_read() {
+        let mut ports = ControllerPorts::new(Box::new(FourScore::new(0)), Box::new(FourScore::new(1)));
+        ports.update(&prepare_input());
+
+        ports.write(0x4016, 1);
+        ports.write(0x4016, 0);
+        read_bits(&mut ports, 0x4016, 11);
+        let state = snapshot::save_all(&[&ports]);
+        let expected = read_bits(&mut ports, 0x4016, 13);
+
+        let mut loaded = ControllerPorts::new(Box::new(FourScore::new(0)), Box::new(FourScore::new(1)));
+        snapshot::load_all(&mut [&mut loaded], &state).unwrap();
+        assert_eq!(read_bits(&mut loaded, 0x4016, 13), expected);
+    }
+
+    #[test]
+    fn test_famicom_four_player() {
+        let mut ports = ControllerPorts::new(Box::new(FamicomFourPlayer::new(0)), Box::new(FamicomFourPlayer::new(1)));
+        ports.update(&prepare_input());
+
+        ports.write(0x4016, 1);
+        ports.write(0x4016, 0);
+        assert_eq!(read_bits(&mut ports, 0x4016, 8), vec![0b01, 0, 0, 0b10, 0, 0, 0, 0]);
+        assert_eq!(read_bits(&mut ports, 0x4017, 8), vec![0, 0b01, 0, 0, 0, 0, 0, 0b10]);
+    }
+}
@@ -0,0 +1,352 @@
+use std::{ fs::File, io::{ BufWriter, Write } };
+
+use crate::region::Region;
+use crate::snapshot::{ self, Snapshot };
+
+use super::PLAYER_COUNT;
+
+// FM2 command bits
+pub const COMMAND_RESET: u8 = 0b_0000_0001;
+pub const COMMAND_POWER: u8 = 0b_0000_0010;
+
+// Gamepad in FM2 is written as RLDUTSBA, pressed buttons are any character except space and dot
+const GAMEPAD_BUTTONS: &[u8; 8] = b"RLDUTSBA";
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MovieFrame {
+    pub commands: u8,
+    pub buttons: [u8; PLAYER_COUNT],
+}
+
+// Movies are stored as FM2 text, so movies from FCEUX can be played too
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Movie {
+    pub region: Region, // FM2 only has a PAL flag, Dendy movies are written as NTSC
+    pub four_score: bool,
+    pub rom_filename: String,
+    pub save_state: Option<Vec<u8>>, // our own snapshot, movie starts from power on without it
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    pub fn parse_fm2(input: &str) -> Result<Self, &'static str> {
+        let mut movie = Movie::default();
+        let mut has_version = false;
+        for line in input.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                movie.frames.push(parse_fm2_frame(line, movie.four_score)?);
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "version" => has_version = value == "3",
+                "palFlag" => movie.region = if value == "1" { Region::PAL } else { Region::NTSC },
+                "fourscore" => movie.four_score = value == "1",
+                "romFilename" => movie.rom_filename = value.to_string(),
+                "binary" if value == "1" => return Err("Binary FM2 movies aren't supported"),
+                "savestate" if !value.is_empty() => movie.save_state = Some(decode_fm2_binary(value)?),
+                "port0" | "port1" if value == "2" => return Err("Zapper movies aren't supported"),
+                _ => (), // checksums, comments and subtitles aren't needed for playback
+            }
+        }
+        if !has_version {
+            return Err("Not an FM2 movie (version 3 expected)");
+        }
+        Ok(movie)
+    }
+
+    pub fn load_file(path: &str) -> Result<Self, &'static str> {
+        let input = std::fs::read_to_string(path).map_err(|_| "Couldn't read movie file")?;
+        Self::parse_fm2(&input)
+    }
+
+    pub fn get_fm2_header(&self) -> String {
+        let mut header = String::new();
+        header += "version 3\n";
+        header += "emuVersion 0\n";
+        header += "rerecordCount 0\n";
+        header += &format!("palFlag {}\n", (self.region == Region::PAL) as u8);
+        header += &format!("romFilename {}\n", self.rom_filename);
+        header += &format!("fourscore {}\n", self.four_score as u8);
+        header += if self.four_score { "port0 0\nport1 0\n" } else { "port0 1\nport1 1\n" };
+        header += "port2 0\n";
+        if let Some(state) = &self.save_state {
+            header += &format!("savestate base64:{}\n", encode_base64(state));
+        }
+        header
+    }
+
+    pub fn format_fm2_frame(&self, frame: &MovieFrame) -> String {
+        let players = if self.four_score { 4 } else { 2 };
+        let mut line = format!("|{}|", frame.commands);
+        for buttons in &frame.buttons[..players] {
+            for (i, name) in GAMEPAD_BUTTONS.iter().enumerate() {
+                line.push(if buttons & (0b_1000_0000 >> i) != 0 { *name as char } else { '.' });
+            }
+            line.push('|');
+        }
+        line.push('|'); // expansion port
+        line
+    }
+}
+
+fn parse_fm2_frame(line: &str, four_score: bool) -> Result<MovieFrame, &'static str> {
+    let mut fields = line.split('|').skip(1);
+    let commands = fields.next()
+        .and_then(|commands| commands.trim().parse().ok())
+        .ok_or("Bad FM2 frame commands")?;
+    let players = if four_score { 4 } else { 2 };
+    let mut frame = MovieFrame { commands, buttons: [0; PLAYER_COUNT] };
+    for player in 0..players {
+        let gamepad = fields.next().ok_or("FM2 frame has too few ports")?.as_bytes();
+        if gamepad.is_empty() { // nothing in port
+            continue;
+        }
+        if gamepad.len() != 8 {
+            return Err("Only gamepads are supported in FM2 frames");
+        }
+        for (i, button) in gamepad.iter().enumerate() {
+            if *button != b'.' && *button != b' ' {
+                frame.buttons[player] |= 0b_1000_0000 >> i;
+            }
+        }
+    }
+    Ok(frame)
+}
+
+// FM2 binary values are either "base64:" or "0x" hex
+fn decode_fm2_binary(value: &str) -> Result<Vec<u8>, &'static str> {
+    if let Some(base64) = value.strip_prefix("base64:") {
+        return decode_base64(base64);
+    }
+    let hex = value.strip_prefix("0x").ok_or("Unknown FM2 binary value encoding")?;
+    if hex.len() % 2 != 0 {
+        return Err("Bad FM2 hex value");
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).ok_or("Bad FM2 hex value")?, 16).map_err(|_| "Bad FM2 hex value"))
+        .collect()
+}
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut output = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8*i));
+        for i in 0..4 {
+            output.push(match i <= chunk.len() {
+                true => BASE64_ALPHABET[(bits >> (18 - 6*i)) as usize & 0b_0011_1111] as char,
+                false => '=',
+            });
+        }
+    }
+    output
+}
+
+fn decode_base64(input: &str) -> Result<Vec<u8>, &'static str> {
+    let input = input.trim_end_matches('=').as_bytes();
+    if input.len() % 4 == 1 {
+        return Err("Bad FM2 base64 value");
+    }
+    let mut output = vec![];
+    for chunk in input.chunks(4) {
+        let mut bits = 0u32;
+        for (i, character) in chunk.iter().enumerate() {
+            let value = BASE64_ALPHABET.iter().position(|c| c == character).ok_or("Bad FM2 base64 value")?;
+            bits |= (value as u32) << (18 - 6*i);
+        }
+        for i in 0..chunk.len() - 1 {
+            output.push((bits >> (16 - 8*i)) as u8);
+        }
+    }
+    Ok(output)
+}
+
+// Writes frames as they're played, so movie survives emulator being closed any time
+pub struct MovieRecorder {
+    movie: Movie, // header only, frames go straight to file
+    writer: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    pub fn new(path: &str, movie: Movie) -> Result<Self, &'static str> {
+        let file = File::create(path).map_err(|_| "Couldn't create movie file")?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(movie.get_fm2_header().as_bytes()).map_err(|_| "Couldn't write movie file")?;
+        Ok(Self { movie, writer })
+    }
+
+    pub fn record_frame(&mut self, frame: &MovieFrame) -> Result<(), &'static str> {
+        let line = self.movie.format_fm2_frame(frame) + "\n";
+        self.writer.write_all(line.as_bytes()).map_err(|_| "Couldn't write movie file")?;
+        self.writer.flush().map_err(|_| "Couldn't write movie file")
+    }
+}
+
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> Self {
+        Self { movie, frame: 0 }
+    }
+
+    // Movie only plays back the same way on the region it was recorded on
+    pub fn check_region(&self, region: Region) -> Result<(), &'static str> {
+        match (self.movie.region == Region::PAL) == (region == Region::PAL) {
+            true => Ok(()),
+            false => Err("Movie was recorded on a different region, use --region to match it"),
+        }
+    }
+
+    // Movies with embedded save state have to load it before the first frame
+    pub fn load_save_state(&self, components: &mut [&mut dyn Snapshot]) -> Result<(), &'static str> {
+        match &self.movie.save_state {
+            Some(state) => snapshot::load_all(components, state).map_err(|_| "Movie save state doesn't match this emulator or rom"),
+            None => Ok(()),
+        }
+    }
+
+    // None once movie is over
+    pub fn next_frame(&mut self) -> Option<MovieFrame> {
+        let frame = self.movie.frames.get(self.frame).copied();
+        self.frame += 1;
+        frame
+    }
+}
+
+#[cfg(test)]
+mod movie_tests {
+    use crate::memory::{ MEM, MEMORY_SIZE };
+    use crate::processor::CPU;
+    use super::*;
+
+    const FCEUX_MOVIE: &str = "version 3\r
+emuVersion 22020\r
+rerecordCount 12\r
+palFlag 0\r
+romFilename Super Mario Bros.\r
+romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\r
+guid 0D4D4D0A-8C5B-2F4D-A10F-EAA71DE7C5F8\r
+fourscore 0\r
+microphone 0\r
+port0 1\r
+port1 1\r
+port2 0\r
+FDS 0\r
+NewPPU 0\r
+comment author someone\r
+|0|........|........||\r
+|1|........|........||\r
+|0|...T...A|.L......||\r
+";
+
+    fn to_fm2(movie: &Movie) -> String {
+        let mut output = movie.get_fm2_header();
+        for frame in &movie.frames {
+            output += &movie.format_fm2_frame(frame);
+            output.push('\n');
+        }
+        output
+    }
+
+    #[test]
+    fn test_parse_fceux_movie() {
+        let movie = Movie::parse_fm2(FCEUX_MOVIE).unwrap();
+
+        assert_eq!(movie.rom_filename, "Super Mario Bros.");
+        assert!(!movie.four_score);
+        assert_eq!(movie.frames.len(), 3);
+        assert_eq!(movie.frames[1].commands, COMMAND_RESET);
+        assert_eq!(movie.frames[2].buttons, [0b_0001_0001, 0b_0100_0000, 0, 0]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for (four_score, region) in [(false, Region::NTSC), (true, Region::PAL)] {
+            let mut movie = Movie {
+                region,
+                four_score,
+                rom_filename: String::from("test.nes"),
+                save_state: None,
+                frames: vec![
+                    MovieFrame { commands: 0, buttons: [0b_1000_0001, 0b_0000_0010, 0, 0] },
+                    MovieFrame { commands: COMMAND_RESET, buttons: [0, 0, 0, 0] },
+                ],
+            };
+            if four_score {
+                movie.frames[0].buttons[2] = 0b_0100_0000;
+                movie.frames[1].buttons[3] = 0b_1111_1111;
+            }
+
+            assert_eq!(Movie::parse_fm2(&to_fm2(&movie)), Ok(movie));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_movies() {
+        assert!(Movie::parse_fm2("|0|........|........||").is_err());
+        assert!(Movie::parse_fm2("version 3\nbinary 1\n").is_err());
+        assert!(Movie::parse_fm2("version 3\nsavestate base64:A\n").is_err());
+        assert!(Movie::parse_fm2("version 3\nsavestate 0xABC\n").is_err());
+        assert!(Movie::parse_fm2("version 3\nport0 2\n").is_err());
+        assert!(Movie::parse_fm2("version 3\n|0|...|........||\n").is_err());
+    }
+
+    #[test]
+    fn test_player() {
+        let movie = Movie::parse_fm2(FCEUX_MOVIE).unwrap();
+        let mut player = MoviePlayer::new(movie);
+
+        assert_eq!(player.next_frame(), Some(MovieFrame::default()));
+        assert_eq!(player.next_frame().unwrap().commands, COMMAND_RESET);
+        assert!(player.next_frame().is_some());
+        assert!(player.next_frame().is_none());
+        assert!(player.check_region(Region::NTSC).is_ok());
+        assert!(player.check_region(Region::Dendy).is_ok());
+        assert!(player.check_region(Region::PAL).is_err());
+    }
+
+    #[test]
+    fn test_binary_values() {
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+        assert_eq!(encode_base64(b"M"), "TQ==");
+        for length in 0..8 {
+            let data: Vec<u8> = (0..length).map(|i| 0xF7 - i).collect();
+            assert_eq!(decode_fm2_binary(&format!("base64:{}", encode_base64(&data))), Ok(data));
+        }
+        assert_eq!(decode_fm2_binary("0x00ff1A"), Ok(vec![0x00, 0xFF, 0x1A]));
+    }
+
+    #[test]
+    fn test_play_from_save_state() {
+        let mut cpu = CPU::new();
+        let mut memory = MEM::new(MEMORY_SIZE);
+        memory.data[0x0200..0x0210].fill(0xEA); // NOP
+        cpu.store_pc(0x0200);
+        cpu.store_a(0x42);
+        cpu.tick(&mut memory).unwrap();
+        memory.data[0x0010] = 0x99;
+        let movie = Movie {
+            region: Region::NTSC,
+            four_score: false,
+            rom_filename: String::from("test.nes"),
+            save_state: Some(snapshot::save_all(&[&cpu, &memory])),
+            frames: vec![MovieFrame { commands: 0, buttons: [0b_0000_1000, 0, 0, 0] }],
+        };
+
+        let mut player = MoviePlayer::new(Movie::parse_fm2(&to_fm2(&movie)).unwrap());
+        let mut loaded_cpu = CPU::new();
+        let mut loaded_memory = MEM::new(MEMORY_SIZE);
+        player.load_save_state(&mut [&mut loaded_cpu, &mut loaded_memory]).unwrap();
+        assert_eq!(format!("{loaded_cpu:?}"), format!("{cpu:?}"));
+        assert_eq!(loaded_memory.data, memory.data);
+        assert_eq!(player.next_frame(), Some(movie.frames[0]));
+        assert!(player.load_save_state(&mut [&mut loaded_cpu]).is_err());
+    }
+}
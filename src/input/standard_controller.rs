@@ -0,0 +1,112 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::{ controller_port::ControllerPortDevice, Input };
+
+// Regular NES controller with 8 bit shift register
+pub struct StandardController {
+    player: usize,
+    buttons: u8,
+    shift_register: u8,
+    strobe: bool,
+}
+
+impl StandardController {
+    pub fn new(player: usize) -> Self {
+        Self {
+            player,
+            buttons: 0,
+            shift_register: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register = buttons;
+        }
+    }
+}
+
+impl ControllerPortDevice for StandardController {
+    fn update(&mut self, input: &Input) {
+        self.set_buttons(input.get_buttons(self.player));
+    }
+
+    fn write_strobe(&mut self, strobe: bool) {
+        // Register is reloaded all the time while strobe is high and keeps last state once it goes low
+        self.strobe = strobe;
+        if strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0b_0000_0001; // A button while reloading
+        }
+        let value = self.shift_register & 0b_0000_0001;
+        // Official controllers return 1 after all 8 buttons were read
+        self.shift_register = (self.shift_register >> 1) | 0b_1000_0000;
+        value
+    }
+}
+
+// Buttons are saved too, movies and rewind replay frames without calling update first
+impl Snapshot for StandardController {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.buttons);
+        state.write_u8(self.shift_register);
+        state.write_bool(self.strobe);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.buttons = state.read_u8()?;
+        self.shift_register = state.read_u8()?;
+        self.strobe = state.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod standard_controller_tests {
+    use super::*;
+
+    fn read_all(controller: &mut StandardController, count: usize) -> Vec<u8> {
+        (0..count).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn test_read_sequence() {
+        let mut controller = StandardController::new(0);
+        controller.set_buttons(0b_1000_0101); // A, Select, Right
+
+        controller.write_strobe(true);
+        controller.write_strobe(false);
+        assert_eq!(read_all(&mut controller, 10), vec![1, 0, 1, 0, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_high_returns_a() {
+        let mut controller = StandardController::new(0);
+        controller.set_buttons(0b_0000_0001);
+
+        controller.write_strobe(true);
+        assert_eq!(read_all(&mut controller, 3), vec![1, 1, 1]);
+        controller.set_buttons(0b_0000_0010);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn test_latched_state_is_kept() {
+        let mut controller = StandardController::new(0);
+        controller.set_buttons(0b_0000_0001);
+
+        controller.write_strobe(true);
+        controller.write_strobe(false);
+        controller.set_buttons(0b_0000_0010); // new input doesn't get in until next strobe
+        assert_eq!(read_all(&mut controller, 2), vec![1, 0]);
+        controller.write_strobe(false); // writing 0 again doesn't reload
+        assert_eq!(controller.read(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::pixel_processor::PPUPtrWrapper;
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::{ controller_port::ControllerPortDevice, Input };
+
+// Photodiode stays lit for a while after beam passes it
+const LIGHT_SENSE_LINES: usize = 20;
+// Zapper sees an area around where it's aimed, not a single pixel
+const SENSE_RADIUS: usize = 2;
+const LIGHT_THRESHOLD: u32 = 0xA0;
+
+// Light gun, aimed with mouse and fired with left mouse button
+pub struct Zapper {
+    ppu: PPUPtrWrapper,
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new(ppu: PPUPtrWrapper) -> Self {
+        Self {
+            ppu,
+            aim: None,
+            trigger: false,
+        }
+    }
+}
+
+impl ControllerPortDevice for Zapper {
+    fn update(&mut self, input: &Input) {
+        (self.aim, self.trigger) = input.get_mouse();
+    }
+
+    fn write_strobe(&mut self, _strobe: bool) {}
+
+    fn read(&mut self) -> u8 {
+        let light_detected = match self.aim {
+            Some(aim) => unsafe {
+                let ppu = &*self.ppu.0;
+                detect_light(ppu.get_framebuffer(), ppu.get_beam_position(), aim)
+            },
+            None => false, // pointing away from screen
+        };
+        let mut value = 0;
+        if !light_detected { value |= 0b_0000_1000 }
+        if self.trigger { value |= 0b_0001_0000 }
+        value
+    }
+}
+
+// Light sensing comes from PPU, only aim and trigger are zapper's own
+impl Snapshot for Zapper {
+    fn save_state(&self, state: &mut StateWriter) {
+        let (x, y) = self.aim.unwrap_or_default();
+        state.write_bool(self.aim.is_some());
+        state.write_u16(x as u16);
+        state.write_u16(y as u16);
+        state.write_bool(self.trigger);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        let is_aimed = state.read_bool()?;
+        let (x, y) = (state.read_u16()? as usize, state.read_u16()? as usize);
+        self.aim = is_aimed.then_some((x, y));
+        self.trigger = state.read_bool()?;
+        Ok(())
+    }
+}
+
+// Checks if pixels around aim were lit up recently, beam position is (line, dot) of the pixel being drawn
+fn detect_light(framebuffer: &[u32], beam_position: (usize, usize), aim: (usize, usize)) -> bool {
+    let (beam_line, beam_dot) = beam_position;
+    let (aim_x, aim_y) = aim;
+    for y in aim_y.saturating_sub(SENSE_RADIUS)..=(aim_y + SENSE_RADIUS).min(239) {
+        for x in aim_x.saturating_sub(SENSE_RADIUS)..=(aim_x + SENSE_RADIUS).min(255) {
+            let drawn = y < beam_line || (y == beam_line && x < beam_dot);
+            if drawn && beam_line - y < LIGHT_SENSE_LINES && get_brightness(framebuffer[x + y*256]) >= LIGHT_THRESHOLD {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn get_brightness(color: u32) -> u32 {
+    let red = (color >> 16) & 0xFF;
+    let green = (color >> 8) & 0xFF;
+    let blue = color & 0xFF;
+    (red*299 + green*587 + blue*114) / 1000
+}
+
+#[cfg(test)]
+mod zapper_tests {
+    use super::*;
+
+    fn framebuffer_with_target(x: usize, y: usize) -> Vec<u32> {
+        let mut framebuffer = vec![0; 256*240];
+        framebuffer[x + y*256] = 0xFFFFFF;
+        framebuffer
+    }
+
+    #[test]
+    fn test_light_after_beam_passes() {
+        let framebuffer = framebuffer_with_target(100, 100);
+
+        assert!(!detect_light(&framebuffer, (99, 0), (100, 100))); // not drawn yet
+        assert!(!detect_light(&framebuffer, (100, 100), (100, 100)));
+        assert!(detect_light(&framebuffer, (100, 101), (100, 100)));
+        assert!(detect_light(&framebuffer, (110, 0), (100, 100)));
+        assert!(!detect_light(&framebuffer, (130, 0), (100, 100))); // sensor went dark again
+    }
+
+    #[test]
+    fn test_light_around_aim() {
+        let framebuffer = framebuffer_with_target(100, 100);
+
+        assert!(detect_light(&framebuffer, (105, 0), (102, 98)));
+        assert!(!detect_light(&framebuffer, (105, 0), (103, 100)));
+    }
+
+    #[test]
+    fn test_dark_colors_are_ignored() {
+        let mut framebuffer = framebuffer_with_target(100, 100);
+        framebuffer[100 + 100*256] = 0x0000FF;
+
+        assert!(!detect_light(&framebuffer, (105, 0), (100, 100)));
+    }
+}
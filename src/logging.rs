@@ -4,7 +4,7 @@ pub struct Logger;
 
 impl Logger {
     pub fn log_cpu_instruction(cpu: &CPU, instruction: u8, operand1: Option<u8>, operand2: Option<u8>, decoded_instruction: String) {
-        if unsafe { *SHOULD_LOG.get().unwrap_unchecked() } {
+        if *SHOULD_LOG.get().unwrap_or(&false) {
             // TODO: use proper logger
             print!("{:04X}  ", cpu.get_pc());
             print!("{instruction:02X} ");
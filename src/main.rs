@@ -5,12 +5,34 @@ use std::sync::OnceLock;
 
 use crate::processor::*;
 use crate::memory::*;
+use crate::memory::mappers::fds::{ FdsAdapter, FdsNametables };
 use crate::pixel_processor::*;
+use crate::audio_processor::{ parse_channel_list, parse_channel_volumes, APU };
+use crate::region::Region;
+use crate::rewind::Rewind;
+use crate::processor::settings::{ ObserverPtrWrapper, Settings, SettingsObserver, SettingsProvider };
+use crate::control::EmulationControl;
+use crate::frame_pacer::FramePacer;
+use crate::nsf_player::NsfPlayer;
+use crate::recording::{ wav::WavWriter, AvRecorder };
+use crate::screenshot::{ get_free_screenshot_path, save_screenshot, ScreenshotOptions };
+use crate::input::{ create_port_device, ControllerPorts, Hotkey, Input };
+use crate::input::movie::{ Movie, MovieFrame, MoviePlayer, MovieRecorder, COMMAND_POWER, COMMAND_RESET };
 
 mod processor;
 mod memory;
 mod logging;
 mod pixel_processor;
+mod audio_processor;
+mod region;
+mod input;
+mod snapshot;
+mod rewind;
+mod control;
+mod frame_pacer;
+mod screenshot;
+mod recording;
+mod nsf_player;
 
 static SHOULD_LOG: OnceLock<bool> = OnceLock::new();
 
@@ -19,6 +41,25 @@ fn main() {
     let mut entry_point: Option<usize> = None;
     let mut file_path = String::new();
     let mut should_log = false;
+    let mut palette_path = String::new();
+    let mut region_name = String::new();
+    let mut bindings_path = String::new();
+    let mut port_1_device = String::from("controller");
+    let mut port_2_device = String::from("controller");
+    let mut record_movie_path = String::new();
+    let mut play_movie_path = String::new();
+    let mut headless = false;
+    let mut screenshot_options = ScreenshotOptions::default();
+    let mut screenshot_after: Option<u64> = None;
+    let mut screenshot_path = String::from("screenshot.png");
+    let mut record_video_path = String::new();
+    let mut record_audio_path = String::new();
+    let mut dump_audio_path = String::new();
+    let mut dump_frames: u64 = 600;
+    let mut sample_rate: u32 = 44100;
+    let mut muted_channels = String::new();
+    let mut channel_volumes = String::new();
+    let mut fds_bios_path = String::new();
     { // Limits argparse borrows to this scope
         let mut argparser = ArgumentParser::new();
         argparser.refer(&mut is_raw_image)
@@ -28,19 +69,111 @@ fn main() {
             .add_option(&["-e", "--entry-point"], ParseOption, "Manually choose cpu entry point"); // HEX not supported
         argparser.refer(&mut should_log)
             .add_option(&["--enable-logging"], StoreTrue, "Enable logging");
+        argparser.refer(&mut palette_path)
+            .add_option(&["--palette"], Store, "Path to .pal palette file (192 or 1536 bytes)");
+        argparser.refer(&mut region_name)
+            .add_option(&["--region"], Store, "Force console region: ntsc, pal or dendy (Default: from rom header)");
+        argparser.refer(&mut bindings_path)
+            .add_option(&["--bindings"], Store, "Path to key bindings file (Default: built-in bindings)");
+        argparser.refer(&mut port_1_device)
+            .add_option(&["--port1"], Store, "Device in controller port 1: controller, zapper, fourscore, famicom4p or none (Default: controller)");
+        argparser.refer(&mut port_2_device)
+            .add_option(&["--port2"], Store, "Device in controller port 2: controller, zapper, fourscore, famicom4p or none (Default: controller)");
+        argparser.refer(&mut record_movie_path)
+            .add_option(&["--record-movie"], Store, "Record input from power on to FM2 movie file");
+        argparser.refer(&mut play_movie_path)
+            .add_option(&["--play-movie"], Store, "Play input from FM2 movie file instead of keyboard");
+        argparser.refer(&mut headless)
+            .add_option(&["--headless"], StoreTrue, "Run without window and as fast as possible");
+        argparser.refer(&mut screenshot_options.scale)
+            .add_option(&["--screenshot-scale"], Store, "Scale screenshots up by whole number (Default: 1)");
+        argparser.refer(&mut screenshot_options.crop_overscan)
+            .add_option(&["--crop-overscan"], StoreTrue, "Crop 8 overscan pixels from each screenshot edge");
+        argparser.refer(&mut screenshot_after)
+            .add_option(&["--screenshot-after"], ParseOption, "Save screenshot after this many frames and exit");
+        argparser.refer(&mut screenshot_path)
+            .add_option(&["--screenshot-path"], Store, "Where --screenshot-after saves screenshot (Default: screenshot.png)");
+        argparser.refer(&mut record_video_path)
+            .add_option(&["--record-video"], Store, "Record every frame to .y4m video file");
+        argparser.refer(&mut record_audio_path)
+            .add_option(&["--record-audio"], Store, "Record audio to .wav file, in sync with video");
+        argparser.refer(&mut dump_audio_path)
+            .add_option(&["--dump-audio"], Store, "Run headless and write APU output to .wav file");
+        argparser.refer(&mut dump_frames)
+            .add_option(&["--dump-frames"], Store, "How many frames --dump-audio runs for (Default: 600)");
+        argparser.refer(&mut sample_rate)
+            .add_option(&["--sample-rate"], Store, "Audio sample rate in Hz, 8000 to 192000 (Default: 44100)");
+        argparser.refer(&mut muted_channels)
+            .add_option(&["--mute"], Store, "Comma separated audio channels to mute: pulse1, pulse2, triangle, noise, dmc, vrc6, vrc7, fds, mmc5, n163, 5b");
+        argparser.refer(&mut channel_volumes)
+            .add_option(&["--channel-volume"], Store, "Comma separated channel=volume pairs, like noise=0.5 (Default: 1 for all)");
+        argparser.refer(&mut fds_bios_path)
+            .add_option(&["--fds-bios"], Store, "Path to Famicom Disk System BIOS, needed for .fds and .qd disk images");
         argparser.refer(&mut file_path)
             .add_argument("rom image", Store, "Path to rom image").required();
         argparser.parse_args_or_exit();
     }
+    if !dump_audio_path.is_empty() {
+        headless = true;
+    }
+    if screenshot_after == Some(0) {
+        println!("--screenshot-after needs at least 1 frame");
+        return;
+    }
+    if !(8000..=192000).contains(&sample_rate) {
+        println!("Sample rate has to be between 8000 and 192000 Hz");
+        return;
+    }
+    let (muted_channels, channel_volumes) = match (parse_channel_list(&muted_channels), parse_channel_volumes(&channel_volumes)) {
+        (Ok(muted_channels), Ok(channel_volumes)) => (muted_channels, channel_volumes),
+        (Err(error), _) | (_, Err(error)) => {
+            println!("{error}");
+            return;
+        },
+    };
     SHOULD_LOG.get_or_init(||should_log);
+    if !palette_path.is_empty() {
+        if let Err(error) = tile::load_palette_file(&palette_path) {
+            println!("{error}, using built-in palette");
+        }
+    }
+    let mut input = match Input::new(if bindings_path.is_empty() { None } else { Some(bindings_path) }) {
+        Ok(input) => input,
+        Err(error) => {
+            println!("{error}");
+            return;
+        },
+    };
     let mut memory;
-    let ppu_memory;
+    let mut ppu_memory;
+    let console_timing;
+    let nsf_data;
+    let expansion_audio;
+    let disk;
     if is_raw_image {
         memory = MEM::new_from(&file_path);
         unimplemented!();
     } else {
-        (memory, ppu_memory) = MEM::new_from_ines(&file_path);
+        let cartridge = MEM::new_from_ines(&file_path, &fds_bios_path);
+        memory = cartridge.memory;
+        ppu_memory = cartridge.ppu_memory;
+        console_timing = cartridge.console_timing;
+        nsf_data = cartridge.nsf;
+        expansion_audio = cartridge.expansion_audio;
+        disk = cartridge.disk;
     }
+    let region = if region_name.is_empty() {
+        Region::from_console_timing(console_timing)
+    } else {
+        match Region::from_name(&region_name) {
+            Ok(region) => region,
+            Err(error) => {
+                println!("{error}");
+                return;
+            },
+        }
+    };
+    println!("Region: {region:?}");
     let mut cpu: CPU = CPU::new();
 
     // TODO: move to cpu init
@@ -65,30 +198,363 @@ fn main() {
         Err(_) => Ok(println!("No file")),
     };
 
-    // Memory pointer since in rust pointers aren't Sync/Send
-    let memory_pointer = MemPtrWrapper(&mut memory as *mut MEM);
-    let cpu_pointer = CPUPtrWrapper(&mut cpu as *mut CPU);
+    // RAM adapter handles nametables, so it's hooked into PPU memory before PPU gets it
+    let mut fds = disk.map(FdsAdapter::new);
+    if let Some(adapter) = &mut fds {
+        let fds_handler = HandlerPtrWrapper(adapter as *mut FdsAdapter as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4020, 0x0007), fds_handler);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4030, 0x0004), fds_handler);
+        let nametables_handler = HandlerPtrWrapper(&mut adapter.nametables as *mut FdsNametables as *mut dyn MemoryHandler);
+        ppu_memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x2000, 0x1000), nametables_handler);
+        ppu_memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x2000, 0x1000), nametables_handler);
+    }
+
+    let mut ppu = PPU::new(ppu_memory, region, headless);
+    let ppu_handler = HandlerPtrWrapper(&mut ppu as *mut PPU as *mut dyn MemoryHandler);
+    let cpu_handler = HandlerPtrWrapper(&mut cpu as *mut CPU as *mut dyn MemoryHandler);
+    memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x2000, 0x0008), ppu_handler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x2000, 0x0008), ppu_handler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4014, 0x0001), cpu_handler);
+    let mut apu = APU::new(region);
+    let apu_handler = HandlerPtrWrapper(&mut apu as *mut APU as *mut dyn MemoryHandler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4000, 0x0014), apu_handler);
+    memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4015, 0x0001), apu_handler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4015, 0x0001), apu_handler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4017, 0x0001), apu_handler);
+    for chip in expansion_audio {
+        println!("Expansion audio: {chip:?}");
+        apu.add_expansion_audio(chip, region);
+    }
+    for (operation, registers) in apu.get_expansion_hooks() {
+        memory.push_hook(operation, registers, apu_handler);
+    }
+    // NSF player stands in for cartridge, its INIT call needs the rest of hardware hooked up already
+    let mut nsf_player = nsf_data.map(|nsf| NsfPlayer::new(nsf, region));
+    if let Some(player) = &mut nsf_player {
+        let nsf_handler = HandlerPtrWrapper(player as *mut NsfPlayer as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x8000, 0x8000), nsf_handler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x5FF8, 0x0008), nsf_handler);
+        if player.uses_fds_ram() {
+            memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x8000, 0x6000), nsf_handler);
+        }
+        player.restart_song(&mut cpu, &mut memory);
+    }
+    let ppu_pointer = PPUPtrWrapper(&ppu as *const PPU);
+    let (port_1, port_2) = match (create_port_device(&port_1_device, 0, ppu_pointer), create_port_device(&port_2_device, 1, ppu_pointer)) {
+        (Ok(port_1), Ok(port_2)) => (port_1, port_2),
+        (Err(error), _) | (_, Err(error)) => {
+            println!("{error}");
+            return;
+        },
+    };
+    let mut controller_ports = ControllerPorts::new(port_1, port_2);
+    let controller_ports_handler = HandlerPtrWrapper(&mut controller_ports as *mut ControllerPorts as *mut dyn MemoryHandler);
+    memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4016, 0x0002), controller_ports_handler);
+    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4016, 0x0001), controller_ports_handler);
+
+    let state_path = snapshot::get_state_path(&file_path);
+    let rom_name = std::path::Path::new(&file_path).file_stem().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let mut movie_player = None;
+    if !play_movie_path.is_empty() {
+        match Movie::load_file(&play_movie_path) {
+            Ok(movie) => {
+                let player = MoviePlayer::new(movie);
+                if let Err(error) = player.check_region(region) {
+                    println!("{error}");
+                    return;
+                }
+                if let Err(error) = player.load_save_state(&mut [&mut cpu, &mut memory, &mut ppu, &mut apu, &mut fds, &mut controller_ports]) {
+                    println!("{error}");
+                    return;
+                }
+                movie_player = Some(player);
+            },
+            Err(error) => {
+                println!("{error}");
+                return;
+            },
+        }
+    }
+    let mut movie_recorder = None;
+    if !record_movie_path.is_empty() {
+        let movie = Movie {
+            region,
+            four_score: [&port_1_device, &port_2_device].iter().any(|device| ["fourscore", "famicom4p"].contains(&device.as_str())),
+            rom_filename: rom_name.clone(),
+            save_state: None,
+            frames: vec![],
+        };
+        match MovieRecorder::new(&record_movie_path, movie) {
+            Ok(recorder) => movie_recorder = Some(recorder),
+            Err(error) => {
+                println!("{error}");
+                return;
+            },
+        }
+    }
+
+    let mut av_recorder = None;
+    if !record_video_path.is_empty() || !record_audio_path.is_empty() {
+        let video_path = Some(record_video_path.as_str()).filter(|path| !path.is_empty());
+        let audio_path = Some(record_audio_path.as_str()).filter(|path| !path.is_empty());
+        match AvRecorder::new(video_path, audio_path, region, sample_rate) {
+            Ok(recorder) => av_recorder = Some(recorder),
+            Err(error) => {
+                println!("{error}");
+                return;
+            },
+        }
+    }
+
+    let mut audio_dump = None;
+    if !dump_audio_path.is_empty() {
+        let file = std::fs::File::create(&dump_audio_path).map_err(|_| "Couldn't create audio file");
+        match file.and_then(|file| WavWriter::new(std::io::BufWriter::new(file), sample_rate)) {
+            Ok(writer) => audio_dump = Some(writer),
+            Err(error) => {
+                println!("{error}");
+                return;
+            },
+        }
+    }
+
+    let mut settings = SettingsProvider::new(Settings::default());
+    settings.update(|settings| {
+        settings.clock_delta = region.get_cpu_clock_delta();
+        settings.sample_rate = sample_rate;
+        for channel in &muted_channels {
+            settings.muted_channels[*channel as usize] = true;
+        }
+        for (channel, volume) in &channel_volumes {
+            settings.channel_volumes[*channel as usize] = *volume;
+        }
+    });
+    let mut control = EmulationControl::new(settings.get());
+    settings.subscribe(ObserverPtrWrapper(&mut cpu as *mut CPU as *mut dyn SettingsObserver));
+    settings.subscribe(ObserverPtrWrapper(&mut apu as *mut APU as *mut dyn SettingsObserver));
+    let mut pacer = FramePacer::new(region);
+    settings.subscribe(ObserverPtrWrapper(&mut pacer as *mut FramePacer as *mut dyn SettingsObserver));
+    settings.subscribe(ObserverPtrWrapper(&mut control as *mut EmulationControl as *mut dyn SettingsObserver));
+    let mut rewind = Rewind::new(settings.get());
+
+    // PPU isn't always clocked a whole number of times per CPU cycle (3.2 on PAL), so we keep the remainder around
+    let ppu_clock_ratio = region.get_ppu_clock_ratio();
+    let mut ppu_clock_remainder = 0;
+    let mut frame_count = 0;
+    loop {
+        let mut frame_commands = 0;
+        loop { // stays here while paused
+            let (mouse_position, mouse_button) = ppu.get_mouse_state();
+            input.update_mouse(mouse_position, mouse_button);
+            for hotkey in input.update(ppu.get_held_keys()) {
+                match hotkey {
+                    Hotkey::Reset => frame_commands |= COMMAND_RESET,
+                    Hotkey::Pause => control.toggle_pause(),
+                    Hotkey::FrameAdvance => control.advance_frame(),
+                    // NSF player isn't in snapshots, and movies can't jump to other point in time
+                    Hotkey::SaveState => if nsf_player.is_none() {
+                        match snapshot::save_file(&state_path, &[&cpu, &memory, &ppu, &apu, &fds, &controller_ports]) {
+                            Ok(()) => println!("State saved to {state_path}"),
+                            Err(error) => println!("{error}"),
+                        }
+                    },
+                    Hotkey::LoadState => if nsf_player.is_none() && movie_player.is_none() && movie_recorder.is_none() {
+                        match snapshot::load_file(&state_path, &mut [&mut cpu, &mut memory, &mut ppu, &mut apu, &mut fds, &mut controller_ports]) {
+                            Ok(()) => {
+                                rewind = Rewind::new(settings.get()); // history is from before the load
+                                println!("State loaded from {state_path}");
+                            },
+                            Err(error) => println!("{error}"),
+                        }
+                    },
+                    Hotkey::FastForward | Hotkey::SlowMotion | Hotkey::Rewind => (),
+                    Hotkey::SpeedUp | Hotkey::SpeedDown => {
+                        let factor = if hotkey == Hotkey::SpeedUp { 2.0 } else { 0.5 };
+                        settings.update(|settings| settings.emulation_speed = (settings.emulation_speed * factor).clamp(0.125, 8.0));
+                        println!("Emulation speed: {}x", settings.get().emulation_speed);
+                    },
+                    Hotkey::Screenshot => {
+                        let path = get_free_screenshot_path(&rom_name);
+                        match save_screenshot(&path, ppu.get_framebuffer(), screenshot_options) {
+                            Ok(()) => println!("Screenshot saved to {path}"),
+                            Err(error) => println!("{error}"),
+                        }
+                    },
+                    Hotkey::ReloadBindings => match input.reload_bindings() {
+                        Ok(()) => println!("Key bindings reloaded"),
+                        Err(error) => println!("{error}, keeping old bindings"),
+                    },
+                    Hotkey::NextTrack | Hotkey::PreviousTrack => if let Some(player) = &mut nsf_player {
+                        if hotkey == Hotkey::NextTrack {
+                            player.next_track(&mut cpu, &mut memory);
+                        } else {
+                            player.previous_track(&mut cpu, &mut memory);
+                        }
+                    },
+                    Hotkey::SwitchDiskSide => if let Some(adapter) = &mut fds {
+                        adapter.switch_side();
+                        println!("{}", adapter.describe_side());
+                    },
+                }
+            }
+            if control.should_run_frame() { break; }
+            ppu.refresh_window();
+            std::thread::sleep(std::time::Duration::from_millis(16));
+        }
+        control.set_fast_forward(input.is_hotkey_held(Hotkey::FastForward));
+        control.set_slow_motion(input.is_hotkey_held(Hotkey::SlowMotion));
+        pacer.set_speed_multiplier(control.get_speed_multiplier());
+
+        // Movies are written to file as they're played, so they can't be rewound. NSF player isn't in snapshots.
+        if input.is_hotkey_held(Hotkey::Rewind) && movie_player.is_none() && movie_recorder.is_none() && nsf_player.is_none() {
+            match rewind.step_back() {
+                Some((state, frames)) => {
+                    snapshot::load_all(&mut [&mut cpu, &mut memory, &mut ppu, &mut apu, &mut fds, &mut controller_ports], &state).expect("Rewind snapshot doesn't match emulator");
+                    for (i, frame) in frames.iter().enumerate() {
+                        let is_last = i == frames.len() - 1;
+                        ppu.set_display_enabled(is_last);
+                        if frame.commands & (COMMAND_RESET | COMMAND_POWER) != 0 {
+                            cpu.reset(&mut memory);
+                        }
+                        input.override_buttons(Some(frame.buttons));
+                        controller_ports.update(&input);
+                        if run_frame(&mut cpu, &mut memory, &mut ppu, &mut apu, &mut nsf_player, &mut fds, ppu_clock_ratio, &mut ppu_clock_remainder).is_err() {
+                            report_crash(&cpu, &memory);
+                            return;
+                        }
+                    }
+                    input.override_buttons(None);
+                    apu.take_samples(); // replayed frames were already heard
+                },
+                None => { // nothing left to rewind
+                    ppu.refresh_window();
+                    std::thread::sleep(std::time::Duration::from_millis(16));
+                    continue;
+                },
+            }
+        } else {
+            if let Some(player) = &mut movie_player {
+                match player.next_frame() {
+                    Some(frame) => {
+                        frame_commands |= frame.commands;
+                        input.override_buttons(Some(frame.buttons));
+                    },
+                    None => {
+                        println!("Movie finished");
+                        input.override_buttons(None);
+                        movie_player = None;
+                    },
+                }
+            }
+            let frame = MovieFrame { commands: frame_commands, buttons: std::array::from_fn(|player| input.get_buttons(player)) };
+            if rewind.is_snapshot_due() {
+                rewind.push_snapshot(snapshot::save_all(&[&cpu, &memory, &ppu, &apu, &fds, &controller_ports]));
+            }
+            rewind.push_frame(frame);
+            if frame.commands & (COMMAND_RESET | COMMAND_POWER) != 0 {
+                match &mut nsf_player {
+                    Some(player) => player.restart_song(&mut cpu, &mut memory),
+                    None => cpu.reset(&mut memory),
+                }
+            }
+            if let Some(recorder) = &mut movie_recorder {
+                if let Err(error) = recorder.record_frame(&frame) {
+                    println!("{error}, recording stopped");
+                    movie_recorder = None;
+                }
+            }
+            controller_ports.update(&input);
+
+            if run_frame(&mut cpu, &mut memory, &mut ppu, &mut apu, &mut nsf_player, &mut fds, ppu_clock_ratio, &mut ppu_clock_remainder).is_err() {
+                report_crash(&cpu, &memory);
+                break;
+            }
+            frame_count += 1;
+        }
 
-    let (mut ppu, ppu_tx) = PPU::new(memory_pointer, ppu_memory, cpu_pointer);
-    memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x2000, 0x0008), ppu_tx.clone());
-    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x2000, 0x0008), ppu_tx.clone());
-    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4014, 0x0001), ppu_tx.clone());
-    memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4016, 0x0002), ppu_tx.clone());
-    memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4016, 0x0002), ppu_tx.clone());
+        if let Some(adapter) = &mut fds {
+            if adapter.take_finished_writes() {
+                match adapter.save_disk() {
+                    Ok(()) => println!("Disk writes saved to {}", adapter.get_save_path()),
+                    Err(error) => println!("{error}"),
+                }
+            }
+        }
 
+        let track = nsf_player.as_mut().map(|player| (player.take_track_changed(), player.describe_track()));
+        if let Some((true, track)) = &track {
+            println!("{track}");
+            ppu.set_title(&format!("Rusted NES - {track}"));
+        }
+
+        let frame_samples = apu.take_samples();
+        if let Some(recorder) = &mut av_recorder {
+            if let Err(error) = recorder.record_frame(ppu.get_framebuffer(), &frame_samples) {
+                println!("{error}, recording stopped");
+                av_recorder = None;
+            }
+        }
+        if let Some(writer) = &mut audio_dump {
+            if let Err(error) = writer.write_samples(&frame_samples) {
+                println!("{error}");
+                return;
+            }
+            if frame_count >= dump_frames {
+                return;
+            }
+        }
+        if screenshot_after == Some(frame_count) {
+            if let Err(error) = save_screenshot(&screenshot_path, ppu.get_framebuffer(), screenshot_options) {
+                println!("{error}");
+            }
+            return;
+        }
+
+        if headless {
+            continue;
+        }
+        pacer.wait_for_next_frame();
+        if let Some(fps) = pacer.take_average_fps() {
+            match &track {
+                Some((_, track)) => ppu.set_title(&format!("Rusted NES - {fps:.1} FPS - {track}")),
+                None => ppu.set_title(&format!("Rusted NES - {fps:.1} FPS")),
+            }
+        }
+    }
+}
+
+// Runs emulation until PPU finishes a frame
+#[allow(clippy::too_many_arguments)]
+fn run_frame(cpu: &mut CPU, memory: &mut MEM, ppu: &mut PPU, apu: &mut APU, nsf_player: &mut Option<NsfPlayer>, fds: &mut Option<FdsAdapter>, (ppu_dots, cpu_cycles): (u32, u32), ppu_clock_remainder: &mut u32) -> Result<(), ()> {
     loop {
-        ppu.tick();
-        ppu.tick();
-        ppu.tick();
-        if cpu.tick(&mut memory).is_err() { // emulator loop
-            // TODO: use logger instead
-            println!("");
-            println!("-----------------------------");
-            println!("WE CRASHED");
-            println!("{:#04X?}", cpu);
-            println!("{:#04X}", memory.read(cpu.PC.0 as usize, 1));
-            println!("-----------------------------");
-            break;
+        *ppu_clock_remainder += ppu_dots;
+        while *ppu_clock_remainder >= cpu_cycles {
+            ppu.tick();
+            *ppu_clock_remainder -= cpu_cycles;
+        }
+        cpu.tick(memory)?;
+        if let Some(player) = nsf_player.as_mut() {
+            player.tick(cpu, memory);
+        }
+        if let Some(adapter) = fds.as_mut() {
+            adapter.tick();
+        }
+        apu.tick(cpu);
+        // Sampled after CPU so PPUSTATUS read can clear vblank before NMI sees it
+        cpu.set_nmi_line(ppu.get_nmi_output());
+        cpu.set_irq_line(apu.get_irq_output() || fds.as_ref().is_some_and(|adapter| adapter.get_irq_output()));
+        if ppu.take_frame_finished() {
+            return Ok(());
         }
     }
 }
+
+fn report_crash(cpu: &CPU, memory: &MEM) {
+    // TODO: use logger instead
+    println!("");
+    println!("-----------------------------");
+    println!("WE CRASHED");
+    println!("{:#04X?}", cpu);
+    println!("{:#04X}", memory.read(cpu.PC.0 as usize, 1));
+    println!("-----------------------------");
+}
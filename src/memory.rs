@@ -1,9 +1,15 @@
 #![allow(dead_code)]
 
+use std::cell::Cell;
+
 use ppu_memory::PPU_MEM;
 
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+pub mod fds;
 pub mod ines;
 pub mod mappers;
+pub mod nsf;
 mod combinatorics;
 
 pub use combinatorics::combine_operands;
@@ -11,7 +17,7 @@ pub mod ppu_memory;
 
 mod hooks;
 
-pub use hooks::{ MemoryHook, MemoryOperation, MemoryEvent };
+pub use hooks::{ HandlerPtrWrapper, MemoryHandler, MemoryHook, MemoryOperation };
 
 pub const MEMORY_SIZE: usize = 0x10000;
 
@@ -148,27 +154,37 @@ pub struct MEM {
     pub data: Vec<u8>,
     mirroring: Vec<MemoryMirror>,
     write_protection: Vec<WriteProtectedRegion>,
-    hooks: Vec<MemoryHook>
+    hooks: Vec<MemoryHook>,
+    last_read_address: Cell<usize>, // DMA makes halted CPU repeat its last read
 }
 
 // Read/Write
 impl MEM {
-    fn read_internal(&self, address: usize, size: usize, send_hooks: bool) -> usize {
+    fn read_internal(&self, address: usize, size: usize, call_hooks: bool) -> usize {
         let mut result: usize = 0;
         for i in 0..size {
             let mirrored_address = self.get_mirrored_address(address+i);
-            let value = self.data[mirrored_address];
-            if send_hooks {
-                for hook in self.get_hooks(MemoryOperation::Read, mirrored_address) {
-                    hook.send(mirrored_address, value);
-                };
-            }
+            let hook = if call_hooks { self.get_hooks(MemoryOperation::Read, mirrored_address).first().copied() } else { None };
+            let value = match hook {
+                Some(hook) => hook.read(mirrored_address),
+                None => self.data[mirrored_address],
+            };
             result += (value as usize) << 8*i
         }
         return result;
     }
 
     pub fn read(&self, address: usize, size: usize) -> usize {
+        self.last_read_address.set(address + size - 1);
+        self.read_internal(address, size, true)
+    }
+
+    pub fn get_last_read_address(&self) -> usize {
+        self.last_read_address.get()
+    }
+
+    // DMA reads aren't what CPU repeats when it's halted again
+    pub fn read_untracked(&self, address: usize, size: usize) -> usize {
         self.read_internal(address, size, true)
     }
 
@@ -177,17 +193,25 @@ impl MEM {
     }
 
     pub fn write(&mut self, address: usize, data: u8) {
-        for hook in self.get_hooks(MemoryOperation::Write, address) {
-            hook.send(address, data);
-        };
         let mirrored_address = self.get_mirrored_address(address);
+        let hooks = self.get_hooks(MemoryOperation::Write, mirrored_address);
+        if !hooks.is_empty() {
+            // handled writes don't get into memory
+            for hook in hooks {
+                hook.write(mirrored_address, data);
+            };
+            return;
+        }
         if !self.is_protected(mirrored_address) {
             self.data[mirrored_address] = data;
         }
     }
 
     pub fn write_no_hook(&mut self, address: usize, data: u8) {
-        self.write(address, data)
+        let mirrored_address = self.get_mirrored_address(address);
+        if !self.is_protected(mirrored_address) {
+            self.data[mirrored_address] = data;
+        }
     }
 
     pub fn write_bulk(&mut self, address: usize, data: Vec<u8>) {
@@ -197,6 +221,20 @@ impl MEM {
     }
 }
 
+// Mirroring, protection and hooks are set up by mapper and stay the same, only contents change
+impl Snapshot for MEM {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.data);
+        state.write_u64(self.last_read_address.get() as u64);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.data)?;
+        self.last_read_address.set(state.read_u64()? as usize);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod read_write_test {
     use super::*;
@@ -261,6 +299,7 @@ impl MEM {
             mirroring: vec![],
             write_protection: vec![],
             hooks: vec![],
+            last_read_address: Cell::new(0),
         }
     }
 
@@ -277,24 +316,72 @@ impl MEM {
         return memory;
     }
 
-    pub fn new_from_ines(file_path: &String) -> (Self, PPU_MEM) {
+    // NSF files are recognized by their magic and loaded with empty cartridge space instead.
+    // FDS disk images need the BIOS too, disk writes saved next to the image are applied to them.
+    pub fn new_from_ines(file_path: &String, fds_bios_path: &str) -> Cartridge {
         use std::fs;
 
         let data = fs::read(file_path)
         .expect("Should have been able to read the file");
 
+        if fds::is_disk_image(&data) {
+            let disk = fds::load_disk(data, fds::get_save_path(file_path)).unwrap_or_else(|error| panic!("{error}"));
+            println!("FDS disk image: {} sides", disk.sides.len());
+            if fds_bios_path.is_empty() {
+                panic!("FDS disk images need the Disk System BIOS, pass it with --fds-bios");
+            }
+            let bios = fs::read(fds_bios_path).expect("Should have been able to read the FDS BIOS");
+            let (memory, ppu_memory) = mappers::map_fds(&bios);
+            return Cartridge {
+                memory,
+                ppu_memory,
+                console_timing: Some(ines::ConsoleTiming::NTSC),
+                nsf: None,
+                expansion_audio: vec![mappers::ExpansionChip::FDS],
+                disk: Some(disk),
+            };
+        }
+
+        if nsf::is_nsf(&data) {
+            let parsed_nsf = nsf::parse_file(&data).unwrap_or_else(|error| panic!("{error}"));
+            println!("NSF: {} - {} ({}), {} songs", parsed_nsf.title, parsed_nsf.artist, parsed_nsf.copyright, parsed_nsf.song_count);
+            println!("load: {:#06X}, init: {:#06X}, play: {:#06X}, bank switched: {}",
+                parsed_nsf.load_address, parsed_nsf.init_address, parsed_nsf.play_address, parsed_nsf.is_bank_switched());
+            let (memory, ppu_memory) = mappers::map_nsf();
+            return Cartridge {
+                memory,
+                ppu_memory,
+                console_timing: parsed_nsf.console_timing,
+                expansion_audio: parsed_nsf.get_expansion_audio(),
+                nsf: Some(parsed_nsf),
+                disk: None,
+            };
+        }
+
         use ines::*;
         let parsed_ines = parse_file(&data);
         println!("{:#?}", parsed_ines.header);
         println!("prg_rom size: {}, {} blocks", parsed_ines.prg_rom.len(), parsed_ines.prg_rom.len()/(16*1024));
         println!("chr_rom size: {}, {} blocks", parsed_ines.chr_rom.len(), parsed_ines.chr_rom.len()/(8*1024));
 
+        let console_timing = parsed_ines.header.console_timing;
+        let expansion_audio = mappers::get_expansion_audio(parsed_ines.header.mapper_number);
         let (memory, ppu_memory) = mappers::map(parsed_ines);
 
-        return (memory, ppu_memory);
+        return Cartridge { memory, ppu_memory, console_timing, nsf: None, expansion_audio, disk: None };
     }
 }
 
+// Everything loaded from rom file
+pub struct Cartridge {
+    pub memory: MEM,
+    pub ppu_memory: PPU_MEM,
+    pub console_timing: Option<ines::ConsoleTiming>,
+    pub nsf: Option<nsf::NsfData>, // NSF player takes the place of mapper
+    pub expansion_audio: Vec<mappers::ExpansionChip>,
+    pub disk: Option<fds::DiskImage>, // Disk System RAM adapter reads it
+}
+
 // Mirroring
 impl MEM {
     fn push_mirrored_range(&mut self, new_mirror: MemoryMirror) -> Result<(), &'static str> {
@@ -0,0 +1,323 @@
+use nom::bytes::complete::{ tag, take };
+use nom::number::complete::{ be_u16, be_u24, u8 as be_u8 };
+use nom::IResult;
+
+const FDS_MAGIC: &[u8] = b"FDS\x1A";
+const FDS_HEADER_SIZE: usize = 16;
+// Every side starts with disk info block, which starts with this
+const DISK_INFO_START: &[u8] = b"\x01*NINTENDO-HVC*";
+const FDS_SIDE_SIZE: usize = 65500;
+const QD_SIDE_SIZE: usize = 65536; // blocks have their CRCs in .qd files
+// Drive sees zeroes in gaps, gap ends with a mark right before the block
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_MARK: u8 = 0x80;
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_END: &[u8] = b"EOF";
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskFormat {
+    FDS,
+    QuickDisk,
+}
+
+impl DiskFormat {
+    fn get_side_size(&self) -> usize {
+        match self {
+            DiskFormat::FDS => FDS_SIDE_SIZE,
+            DiskFormat::QuickDisk => QD_SIDE_SIZE,
+        }
+    }
+}
+
+// Famicom Disk System disk. Sides are kept the way drive reads them: gaps, marks and blocks with CRCs after them.
+// Writes are saved as IPS patch next to the image, which stays untouched.
+pub struct DiskImage {
+    format: DiskFormat,
+    header: Vec<u8>, // fwNES header, empty if file has none
+    pub sides: Vec<Vec<u8>>,
+    original: Vec<u8>, // file as it was before saved writes got applied
+    save_path: String,
+}
+
+impl DiskImage {
+    // Back to file layout, blocks without gaps
+    pub fn to_file(&self) -> Vec<u8> {
+        let mut file = self.header.clone();
+        for side in &self.sides {
+            file.extend(remove_gaps(side, self.format));
+        }
+        file
+    }
+
+    pub fn save(&self) -> Result<(), &'static str> {
+        std::fs::write(&self.save_path, create_patch(&self.original, &self.to_file())).map_err(|_| "Couldn't save disk writes")
+    }
+
+    pub fn get_save_path(&self) -> &str {
+        &self.save_path
+    }
+}
+
+// Like "disk 1 side B"
+pub fn describe_side(side: usize) -> String {
+    format!("disk {} side {}", side / 2 + 1, if side.is_multiple_of(2) { 'A' } else { 'B' })
+}
+
+pub fn is_disk_image(data: &[u8]) -> bool {
+    data.starts_with(FDS_MAGIC) || data.starts_with(DISK_INFO_START)
+}
+
+// Saved writes are in "<image path>.ips"
+pub fn get_save_path(image_path: &str) -> String {
+    format!("{image_path}.ips")
+}
+
+// Applies saved writes, if there are any
+pub fn load_disk(original: Vec<u8>, save_path: String) -> Result<DiskImage, &'static str> {
+    let data = match std::fs::read(&save_path) {
+        Ok(patch) => apply_patch(&original, &patch)?,
+        Err(_) => original.clone(),
+    };
+    let mut disk = parse_file(&data)?;
+    disk.original = original;
+    disk.save_path = save_path;
+    Ok(disk)
+}
+
+pub fn parse_file(data: &[u8]) -> Result<DiskImage, &'static str> {
+    let (header, data) = if data.starts_with(FDS_MAGIC) {
+        (data.get(..FDS_HEADER_SIZE).ok_or("FDS header is too short")?, &data[FDS_HEADER_SIZE..])
+    } else {
+        (&[][..], data)
+    };
+    let format = match data.len() {
+        0 => return Err("Disk image has no sides"),
+        length if length % FDS_SIDE_SIZE == 0 => DiskFormat::FDS,
+        length if length % QD_SIDE_SIZE == 0 => DiskFormat::QuickDisk,
+        _ => return Err("Disk image size isn't a whole number of sides"),
+    };
+    let sides: Vec<Vec<u8>> = data.chunks(format.get_side_size()).map(|side| add_gaps(side, format)).collect();
+    if sides.len() > u8::MAX as usize {
+        return Err("Disk image has too many sides");
+    }
+    Ok(DiskImage {
+        format,
+        header: header.to_vec(),
+        sides,
+        original: vec![],
+        save_path: String::new(),
+    })
+}
+
+// Block type decides its length, file data length comes from file header block before it
+fn get_block_length(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56), // disk info
+        2 => Some(2), // file count
+        3 => Some(16), // file header
+        4 => Some(1 + file_size), // file data
+        _ => None,
+    }
+}
+
+fn get_file_size(block: &[u8]) -> Option<usize> {
+    (block[0] == 3).then(|| u16::from_le_bytes([block[13], block[14]]) as usize)
+}
+
+fn add_gaps(blocks: &[u8], format: DiskFormat) -> Vec<u8> {
+    let stored_crc_size = if format == DiskFormat::QuickDisk { 2 } else { 0 };
+    let mut side = vec![0; LEAD_IN_GAP];
+    let mut position = 0;
+    let mut file_size = 0;
+    // anything after the last valid block is unused space
+    while let Some(length) = blocks.get(position).and_then(|block_type| get_block_length(*block_type, file_size)) {
+        let Some(block) = blocks.get(position..position + length) else { break };
+        file_size = get_file_size(block).unwrap_or(file_size);
+        side.push(BLOCK_MARK);
+        side.extend_from_slice(block);
+        side.extend_from_slice(&calculate_crc(block).to_le_bytes());
+        side.resize(side.len() + BLOCK_GAP, 0);
+        position += length + stored_crc_size;
+    }
+    // rest of the side is blank, leaving room for new files
+    side.resize(side.len().max(LEAD_IN_GAP + QD_SIDE_SIZE), 0);
+    side
+}
+
+fn remove_gaps(side: &[u8], format: DiskFormat) -> Vec<u8> {
+    let mut blocks = vec![];
+    let mut position = 0;
+    let mut file_size = 0;
+    loop {
+        while side.get(position) == Some(&0) {
+            position += 1;
+        }
+        if side.get(position) != Some(&BLOCK_MARK) {
+            break;
+        }
+        position += 1;
+        let Some(length) = side.get(position).and_then(|block_type| get_block_length(*block_type, file_size)) else { break };
+        let Some(block) = side.get(position..position + length + 2) else { break };
+        file_size = get_file_size(block).unwrap_or(file_size);
+        let stored_length = if format == DiskFormat::QuickDisk { length + 2 } else { length };
+        blocks.extend_from_slice(&block[..stored_length]);
+        position += length + 2;
+    }
+    blocks.resize(format.get_side_size(), 0);
+    blocks
+}
+
+// CRC-16 drive calculates over the mark and block, it's written after the block
+fn calculate_crc(block: &[u8]) -> u16 {
+    [BLOCK_MARK].iter().chain(block).chain(&[0, 0]).fold(0, |crc, byte| update_crc(crc, *byte))
+}
+
+pub fn update_crc(mut crc: u16, byte: u8) -> u16 {
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if byte & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// Records of changed bytes: 24 bit offset, 16 bit length and the bytes
+fn create_patch(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    let is_changed = |position: usize| original.get(position) != Some(&modified[position]);
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut position = 0;
+    while position < modified.len() {
+        if !is_changed(position) {
+            position += 1;
+            continue;
+        }
+        // offset that reads as "EOF" would end the patch
+        let start = if position == 0x454F46 { position - 1 } else { position };
+        let mut end = position;
+        while end < modified.len() && end - start < u16::MAX as usize && is_changed(end) {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        position = end;
+    }
+    patch.extend_from_slice(IPS_END);
+    patch
+}
+
+// Zero length record is a run of one byte
+fn parse_record(input: &[u8]) -> IResult<&[u8], (usize, Vec<u8>)> {
+    let (input, offset) = be_u24(input)?;
+    let (input, length) = be_u16(input)?;
+    if length == 0 {
+        let (input, count) = be_u16(input)?;
+        let (input, value) = be_u8(input)?;
+        return Ok((input, (offset as usize, vec![value; count as usize])));
+    }
+    let (input, bytes) = take(length)(input)?;
+    Ok((input, (offset as usize, bytes.to_vec())))
+}
+
+fn apply_patch(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut data = data.to_vec();
+    let (mut patch, _) = tag::<_, _, nom::error::Error<_>>(IPS_MAGIC)(patch).map_err(|_| "Disk save isn't an IPS patch")?;
+    while !patch.starts_with(IPS_END) {
+        let (rest, (offset, bytes)) = parse_record(patch).map_err(|_| "Disk save is cut short")?;
+        if data.len() < offset + bytes.len() {
+            data.resize(offset + bytes.len(), 0);
+        }
+        data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        patch = rest;
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod fds_tests {
+    use super::*;
+
+    // Disk info, file count and one file with 3 bytes of data
+    fn create_side(with_crcs: bool) -> Vec<u8> {
+        let crc = if with_crcs { vec![0xAA, 0xBB] } else { vec![] };
+        let mut side = DISK_INFO_START.to_vec();
+        side.resize(56, 0);
+        side.extend(&crc);
+        side.extend([2, 1]);
+        side.extend(&crc);
+        side.extend([3, 0, 0, b'F', b'I', b'L', b'E', b' ', b' ', b' ', b' ', 0x00, 0x60, 3, 0, 0]);
+        side.extend(&crc);
+        side.extend([4, 0x11, 0x22, 0x33]);
+        side.extend(&crc);
+        side
+    }
+
+    #[test]
+    fn test_parse_fds() {
+        let mut file = FDS_MAGIC.to_vec();
+        file.extend([2; 12]);
+        for _ in 0..2 {
+            let mut side = create_side(false);
+            side.resize(FDS_SIDE_SIZE, 0);
+            file.extend(side);
+        }
+        let disk = parse_file(&file).unwrap();
+        assert_eq!(disk.format, DiskFormat::FDS);
+        assert_eq!(disk.sides.len(), 2);
+        let side = &disk.sides[0];
+        assert!(side[..LEAD_IN_GAP].iter().all(|byte| *byte == 0));
+        assert_eq!(side[LEAD_IN_GAP], BLOCK_MARK);
+        assert_eq!(&side[LEAD_IN_GAP + 1..LEAD_IN_GAP + 16], DISK_INFO_START);
+        let file_data = LEAD_IN_GAP + (1 + 56 + 2 + BLOCK_GAP) + (1 + 2 + 2 + BLOCK_GAP) + (1 + 16 + 2 + BLOCK_GAP);
+        assert_eq!(&side[file_data..file_data + 5], [BLOCK_MARK, 4, 0x11, 0x22, 0x33]);
+        assert_eq!(disk.to_file(), file);
+    }
+
+    #[test]
+    fn test_parse_quick_disk() {
+        let mut file = create_side(true);
+        file.resize(QD_SIDE_SIZE, 0);
+        let disk = parse_file(&file).unwrap();
+        assert_eq!(disk.format, DiskFormat::QuickDisk);
+        assert_eq!(disk.header, []);
+        // CRCs get recalculated
+        let crc = calculate_crc(&file[..56]).to_le_bytes();
+        assert_eq!(&disk.sides[0][LEAD_IN_GAP + 57..LEAD_IN_GAP + 59], crc);
+        assert_eq!(&disk.to_file()[56..58], crc);
+
+        assert!(parse_file(&file[..1000]).is_err());
+    }
+
+    #[test]
+    fn test_crc() {
+        // CRC of data with its CRC after it is zero
+        let crc = calculate_crc(&[1, 2, 3]);
+        let mut crc_check = [BLOCK_MARK, 1, 2, 3].iter().fold(0, |crc, byte| update_crc(crc, *byte));
+        crc_check = crc.to_le_bytes().iter().fold(crc_check, |crc, byte| update_crc(crc, *byte));
+        assert_eq!(crc_check, 0);
+    }
+
+    #[test]
+    fn test_patch_round_trip() {
+        let original: Vec<u8> = (0..=255).collect();
+        let mut modified = original.clone();
+        modified[10] = 0;
+        modified[11] = 0;
+        modified[200] = 1;
+        modified.extend([7, 7, 7]);
+        let patch = create_patch(&original, &modified);
+        assert_eq!(patch.len(), IPS_MAGIC.len() + (5 + 2) + (5 + 1) + (5 + 3) + IPS_END.len());
+        assert_eq!(apply_patch(&original, &patch), Ok(modified));
+
+        let run_length = [IPS_MAGIC, &[0, 0, 1, 0, 0, 0, 3, 9], IPS_END].concat();
+        assert_eq!(apply_patch(&[0; 5], &run_length), Ok(vec![0, 9, 9, 9, 0]));
+        assert!(apply_patch(&original, b"PATCH\x00").is_err());
+    }
+}
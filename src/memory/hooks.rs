@@ -1,22 +1,30 @@
-use std::sync::mpsc::Sender;
-
 use super::{ MEM, MemoryRegion };
 
+// Devices mapped into address space (like PPU registers) handle accesses synchronously
+pub trait MemoryHandler {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+}
+
+// Handler pointer since handlers usually also hold pointer to memory
+#[derive(Clone, Copy)]
+pub struct HandlerPtrWrapper(pub *mut dyn MemoryHandler);
+unsafe impl Sync for HandlerPtrWrapper {}
+unsafe impl Send for HandlerPtrWrapper {}
+
 pub struct MemoryHook {
     operation: MemoryOperation,
     range: MemoryRegion,
-    tx: Sender<MemoryEvent>,
+    handler: HandlerPtrWrapper,
 }
 
 impl MemoryHook {
-    pub fn send(&self, address: usize, value: u8) {
-        self.tx.send(
-            MemoryEvent {
-                operation: self.operation,
-                address: address as u16,
-                value,
-            }
-        ).unwrap()
+    pub fn read(&self, address: usize) -> u8 {
+        unsafe{(*self.handler.0).read(address as u16)}
+    }
+
+    pub fn write(&self, address: usize, value: u8) {
+        unsafe{(*self.handler.0).write(address as u16, value)}
     }
 }
 
@@ -26,20 +34,13 @@ pub enum MemoryOperation {
     Write,
 }
 
-#[derive(PartialEq, Debug, Clone, Copy)]
-pub struct MemoryEvent {
-    pub operation: MemoryOperation,
-    pub address: u16,
-    pub value: u8,
-}
-
 impl MEM {
-    pub fn push_hook(&mut self, operation: MemoryOperation, range: MemoryRegion, tx: Sender<MemoryEvent>) {
+    pub fn push_hook(&mut self, operation: MemoryOperation, range: MemoryRegion, handler: HandlerPtrWrapper) {
         self.hooks.push(
             MemoryHook {
                 operation,
                 range,
-                tx,
+                handler,
             }
         );
     }
@@ -59,17 +60,34 @@ impl MEM {
 
 #[cfg(test)]
 mod memory_hook_tests {
-    use std::sync::mpsc::{ channel, Receiver, TryRecvError, Sender };
-
     use super::*;
     use super::super::MEMORY_SIZE;
 
+    #[derive(Default)]
+    struct RecordingHandler {
+        reads: Vec<u16>,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl MemoryHandler for RecordingHandler {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            (address & 0xFF) as u8
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.writes.push((address, value));
+        }
+    }
+
+    fn handler_pointer(handler: &mut RecordingHandler) -> HandlerPtrWrapper {
+        HandlerPtrWrapper(handler as *mut RecordingHandler as *mut dyn MemoryHandler)
+    }
+
     #[test]
     fn test_get_hooks() {
         let mut test_memory: MEM = MEM::new(MEMORY_SIZE);
-
-        let (tx1, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx2, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
+        let mut handler = RecordingHandler::default();
 
         test_memory.push_hook(
             MemoryOperation::Read,
@@ -77,7 +95,7 @@ mod memory_hook_tests {
                 region_address: 0x0010,
                 region_size: 0x0010,
             },
-            tx1,
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
             MemoryOperation::Write,
@@ -85,7 +103,7 @@ mod memory_hook_tests {
                 region_address: 0x0010,
                 region_size: 0x0010,
             },
-            tx2,
+            handler_pointer(&mut handler),
         );
 
         assert_eq!(test_memory.get_hooks(MemoryOperation::Read,  0x000F).is_empty(), true);
@@ -102,11 +120,7 @@ mod memory_hook_tests {
     #[test]
     fn test_multiple_hooks() {
         let mut test_memory: MEM = MEM::new(MEMORY_SIZE);
-
-        let (tx1, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx2, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx3, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx4, _): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
+        let mut handler = RecordingHandler::default();
 
         test_memory.push_hook(
             MemoryOperation::Read,
@@ -114,7 +128,7 @@ mod memory_hook_tests {
                 region_address: 0x0010,
                 region_size: 0x0020,
             },
-            tx1,
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
             MemoryOperation::Read,
@@ -122,7 +136,7 @@ mod memory_hook_tests {
                 region_address: 0x0020,
                 region_size: 0x0010,
             },
-            tx2,
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
             MemoryOperation::Write,
@@ -130,7 +144,7 @@ mod memory_hook_tests {
                 region_address: 0x0010,
                 region_size: 0x0020,
             },
-            tx3,
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
             MemoryOperation::Write,
@@ -138,7 +152,7 @@ mod memory_hook_tests {
                 region_address: 0x0020,
                 region_size: 0x0010,
             },
-            tx4,
+            handler_pointer(&mut handler),
         );
 
         assert_eq!(test_memory.get_hooks(MemoryOperation::Read, 0x0007).len(), 0);
@@ -153,114 +167,71 @@ mod memory_hook_tests {
     }
 
     #[test]
-    fn test_multiple_hooks_send() {
+    fn test_hooks_handle_access() {
         let mut test_memory: MEM = MEM::new(MEMORY_SIZE);
-
-        let (tx1, rx1): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx2, rx2): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx3, rx3): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        let (tx4, rx4): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
+        let mut handler = RecordingHandler::default();
 
         test_memory.push_hook(
             MemoryOperation::Read,
             MemoryRegion {
                 region_address: 0x0010,
-                region_size: 0x0020,
+                region_size: 0x0010,
             },
-            tx1
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
-            MemoryOperation::Read,
+            MemoryOperation::Write,
             MemoryRegion {
-                region_address: 0x0020,
+                region_address: 0x0010,
                 region_size: 0x0010,
             },
-            tx2,
+            handler_pointer(&mut handler),
         );
+
+        test_memory.data[0x0017] = 0xDE;
+        test_memory.data[0x0027] = 0xAD;
+        assert_eq!(test_memory.read(0x0017, 1), 0x17); // value comes from handler
+        assert_eq!(test_memory.read(0x0027, 1), 0xAD);
+
+        test_memory.write(0x0017, 0xBE);
+        test_memory.write(0x0027, 0xEF);
+        assert_eq!(test_memory.data[0x0017], 0xDE); // handled writes don't touch memory
+        assert_eq!(test_memory.data[0x0027], 0xEF);
+
+        assert_eq!(handler.reads, vec![0x0017]);
+        assert_eq!(handler.writes, vec![(0x0017, 0xBE)]);
+    }
+
+    #[test]
+    fn test_hooks_on_mirrored_memory() {
+        let mut test_memory: MEM = MEM::new(MEMORY_SIZE);
+        let mut handler = RecordingHandler::default();
+
+        test_memory.push_mirrored_range(super::super::MemoryMirror {
+            physical_memory: MemoryRegion { region_address: 0x0010, region_size: 0x0008 },
+            mirrored_memory: MemoryRegion { region_address: 0x0018, region_size: 0x0008 },
+        }).unwrap();
         test_memory.push_hook(
-            MemoryOperation::Write,
+            MemoryOperation::Read,
             MemoryRegion {
                 region_address: 0x0010,
-                region_size: 0x0020,
+                region_size: 0x0008,
             },
-            tx3
+            handler_pointer(&mut handler),
         );
         test_memory.push_hook(
             MemoryOperation::Write,
             MemoryRegion {
-                region_address: 0x0020,
-                region_size: 0x0010,
+                region_address: 0x0010,
+                region_size: 0x0008,
             },
-            tx4,
+            handler_pointer(&mut handler),
         );
 
-        for hook in test_memory.get_hooks(MemoryOperation::Read, 0x0007) {
-            hook.send(0x0007, 0xDE);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Read, 0x0017) {
-            hook.send(0x0017, 0xAD);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Read, 0x0027) {
-            hook.send(0x0027, 0xBE);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Read, 0x0037) {
-            hook.send(0x0037, 0xEF);
-        }
-
-        for hook in test_memory.get_hooks(MemoryOperation::Write, 0x0007) {
-            hook.send(0x0007, 0xDE);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Write, 0x0017) {
-            hook.send(0x0017, 0xAD);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Write, 0x0027) {
-            hook.send(0x0027, 0xBE);
-        }
-        for hook in test_memory.get_hooks(MemoryOperation::Write, 0x0037) {
-            hook.send(0x0037, 0xEF);
-        }
-
-        assert_eq!(rx1.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Read,
-            address: 0x0017,
-            value: 0xAD,
-        }));
-        assert_eq!(rx1.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Read,
-            address: 0x0027,
-            value: 0xBE,
-        }));
-        assert_eq!(rx1.try_recv(), Err(TryRecvError::Empty));
-
-        assert_eq!(rx2.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Read,
-            address: 0x0027,
-            value: 0xBE,
-        }));
-        assert_eq!(rx2.try_recv(), Err(TryRecvError::Empty));
-
-        assert_eq!(rx3.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Write,
-            address: 0x0017,
-            value: 0xAD,
-        }));
-        assert_eq!(rx3.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Write,
-            address: 0x0027,
-            value: 0xBE,
-        }));
-        assert_eq!(rx3.try_recv(), Err(TryRecvError::Empty));
-
-        assert_eq!(rx4.try_recv(), Ok(MemoryEvent{
-            operation: MemoryOperation::Write,
-            address: 0x0027,
-            value: 0xBE,
-        }));
-        assert_eq!(rx4.try_recv(), Err(TryRecvError::Empty));
-    }
+        assert_eq!(test_memory.read(0x001A, 1), 0x12);
+        test_memory.write(0x001B, 0xAB);
 
-    #[cfg(test)]
-    fn test_write_hook_on_write_protected() {
-        // TODO
+        assert_eq!(handler.reads, vec![0x0012]);
+        assert_eq!(handler.writes, vec![(0x0013, 0xAB)]);
     }
 }
@@ -54,7 +54,7 @@ pub enum ConsoleType {
     ExtendedConsoleType,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum ConsoleTiming {
     NTSC,
     PAL,
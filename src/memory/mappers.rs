@@ -1,16 +1,52 @@
-use super::{ ines::iNESData, ppu_memory::PPU_MEM, MemoryMirror, MemoryRegion, WriteProtectedRegion, MEM };
+use super::{ ines::iNESData, ppu_memory::PPU_MEM, MemoryMirror, MemoryRegion, MEM };
 
+pub mod fds;
 pub mod mapper0;
 
 pub fn map(input: iNESData) -> (MEM, PPU_MEM) {
     add_write_protection_and_mirroring(
         match input.header.mapper_number {
             0 => mapper0::map(input),
-            _ => unimplemented!() // TODO: implement other mappers
+            mapper_number => unimplemented!("Mapper {mapper_number} isn't supported") // TODO: implement other mappers
         }
     )
 }
 
+// Sound chips on some cartridges, mixed into APU output
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExpansionChip {
+    VRC6 { swapped_lines: bool }, // mapper 26 has A0 and A1 swapped
+    VRC7,
+    FDS,
+    MMC5,
+    Namco163,
+    Sunsoft5B,
+}
+
+// Expansion audio hook, mappers with sound chips list them here
+pub fn get_expansion_audio(mapper_number: u16) -> Vec<ExpansionChip> {
+    match mapper_number {
+        5 => vec![ExpansionChip::MMC5],
+        19 => vec![ExpansionChip::Namco163],
+        24 => vec![ExpansionChip::VRC6 { swapped_lines: false }],
+        26 => vec![ExpansionChip::VRC6 { swapped_lines: true }],
+        69 => vec![ExpansionChip::Sunsoft5B],
+        85 => vec![ExpansionChip::VRC7],
+        _ => vec![],
+    }
+}
+
+// Disk System RAM adapter takes the place of cartridge, BIOS is its ROM
+pub fn map_fds(bios: &[u8]) -> (MEM, PPU_MEM) {
+    add_write_protection_and_mirroring(fds::map(bios))
+}
+
+// NSF code isn't in memory, player maps its banks on reads
+pub fn map_nsf() -> (MEM, PPU_MEM) {
+    add_write_protection_and_mirroring((MEM::new(0x10000), MEM::new(0x4000)))
+}
+
 fn add_write_protection_and_mirroring((mut memory, mut ppu_memory): (MEM, PPU_MEM)) -> (MEM, PPU_MEM) {
     // PPU registers mirroring
     memory.push_mirrored_range(MemoryMirror {
@@ -43,12 +79,6 @@ fn add_write_protection_and_mirroring((mut memory, mut ppu_memory): (MEM, PPU_ME
         physical_memory: MemoryRegion { region_address: 0x3F0C, region_size: 0x0001 },
         mirrored_memory: MemoryRegion { region_address: 0x3F1C, region_size: 0x0001 }
     }).unwrap();
-
-    // You shouldn't be able to write to PPU registers directly, it all done via hooks
-    memory.push_write_protected_region(WriteProtectedRegion { protected_memory: MemoryRegion {
-        region_address: 0x2000,
-        region_size: 0x0008
-    }});
     return (memory, ppu_memory);
 }
 
@@ -0,0 +1,525 @@
+use crate::{
+    memory::{ fds::{ describe_side, update_crc, DiskImage }, ppu_memory::PPU_MEM, MemoryHandler, MemoryRegion, WriteProtectedRegion, MEM },
+    snapshot::{ Snapshot, StateReader, StateWriter },
+};
+
+const BIOS_SIZE: usize = 0x2000;
+// Drive timing in CPU cycles
+const BYTE_DELAY: u32 = 150;
+const HEAD_RETURN_DELAY: u32 = 50000;
+// Disk stays out for about a second when switching sides, so BIOS sees it was ejected
+const INSERT_DELAY: u32 = 1_800_000;
+
+// RAM adapter has 32K of PRG RAM at $6000-$DFFF and 8K of CHR RAM, BIOS is at $E000-$FFFF.
+// Nametables are handled by the adapter, since it switches their mirroring.
+pub fn map(bios: &[u8]) -> (MEM, PPU_MEM) {
+    if bios.len() != BIOS_SIZE {
+        panic!("FDS BIOS should be 8192 bytes");
+    }
+    let mut memory = MEM::new(0x10000);
+    memory.write_bulk(0xE000, bios.to_vec());
+    memory.push_write_protected_region(WriteProtectedRegion {
+        protected_memory: MemoryRegion { region_address: 0xE000, region_size: BIOS_SIZE },
+    });
+    (memory, MEM::new(0x4000))
+}
+
+// 2K of nametable RAM, mirrored vertically or horizontally. Hooked into PPU memory at $2000-$2FFF.
+pub struct FdsNametables {
+    ram: [u8; 0x800],
+    horizontal_mirroring: bool,
+}
+
+impl FdsNametables {
+    fn get_index(&self, address: u16) -> usize {
+        let address = address as usize;
+        if self.horizontal_mirroring {
+            (address & 0x03FF) | ((address & 0x0800) >> 1)
+        } else {
+            address & 0x07FF
+        }
+    }
+}
+
+impl MemoryHandler for FdsNametables {
+    fn read(&mut self, address: u16) -> u8 {
+        self.ram[self.get_index(address)]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.ram[self.get_index(address)] = value;
+    }
+}
+
+// Famicom Disk System RAM adapter: timer IRQ at $4020-$4022 and disk drive at $4023-$4026 and $4030-$4033.
+// Drive moves one byte under the head every 150 cycles, from start of the side to its end, where it stops.
+pub struct FdsAdapter {
+    disk: DiskImage,
+    pub nametables: FdsNametables,
+    inserted_side: Option<usize>,
+    next_side: Option<usize>, // goes in when insert timer runs out
+    insert_timer: u32,
+    disk_registers_enabled: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    // $4025 bits
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    write_data: u8,
+    read_data: u8,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    previous_crc_control: bool,
+    crc: u16,
+    position: usize,
+    delay: u32,
+    unsaved_writes: bool,
+}
+
+impl FdsAdapter {
+    pub fn new(disk: DiskImage) -> Self {
+        Self {
+            disk,
+            nametables: FdsNametables { ram: [0; 0x800], horizontal_mirroring: false },
+            inserted_side: Some(0),
+            next_side: None,
+            insert_timer: 0,
+            disk_registers_enabled: true,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            write_data: 0,
+            read_data: 0,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            previous_crc_control: false,
+            crc: 0,
+            position: 0,
+            delay: 0,
+            unsaved_writes: false,
+        }
+    }
+
+    pub fn get_irq_output(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    // Ejects disk, next side goes in after a while. Switching again while disk is out skips to the side after.
+    pub fn switch_side(&mut self) {
+        let side = self.inserted_side.or(self.next_side).map_or(0, |side| (side + 1) % self.disk.sides.len());
+        self.inserted_side = None;
+        self.next_side = Some(side);
+        self.insert_timer = INSERT_DELAY;
+    }
+
+    // Like "Inserted disk 1 side B"
+    pub fn describe_side(&self) -> String {
+        match (self.inserted_side, self.next_side) {
+            (Some(side), _) => format!("Inserted {}", describe_side(side)),
+            (None, Some(side)) => format!("Ejected disk, inserting {}", describe_side(side)),
+            (None, None) => String::from("No disk inserted"),
+        }
+    }
+
+    // True once after disk was written and drive stopped, that's when it's worth saving
+    pub fn take_finished_writes(&mut self) -> bool {
+        if self.unsaved_writes && !self.scanning {
+            self.unsaved_writes = false;
+            return true;
+        }
+        false
+    }
+
+    pub fn save_disk(&self) -> Result<(), &'static str> {
+        self.disk.save()
+    }
+
+    pub fn get_save_path(&self) -> &str {
+        self.disk.get_save_path()
+    }
+
+    // Clocked every CPU cycle
+    pub fn tick(&mut self) {
+        self.clock_timer();
+        if self.insert_timer > 0 {
+            self.insert_timer -= 1;
+            if self.insert_timer == 0 {
+                self.inserted_side = self.next_side.take();
+            }
+        }
+        self.clock_drive();
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            self.timer_enabled = self.timer_repeat;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let Some(side) = self.inserted_side.filter(|_| self.motor_on) else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.end_of_head = false;
+            self.delay = HEAD_RETURN_DELAY;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+        if self.read_mode {
+            self.read_byte(side);
+        } else {
+            self.write_byte(side);
+        }
+        self.previous_crc_control = self.crc_control;
+        self.position += 1;
+        if self.position >= self.disk.sides[side].len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_DELAY;
+        }
+    }
+
+    // Bytes are only transferred after the gap ends, the mark that ends it doesn't raise IRQ
+    fn read_byte(&mut self, side: usize) {
+        let value = self.disk.sides[side][self.position];
+        if !self.disk_ready {
+            self.gap_ended = false;
+        } else if value != 0 && !self.gap_ended {
+            self.gap_ended = true;
+            self.read_data = value;
+            self.transfer_complete = true;
+            return;
+        }
+        if self.gap_ended {
+            self.read_data = value;
+            self.transfer_complete = true;
+            self.disk_irq |= self.disk_irq_enabled;
+        }
+    }
+
+    // Gap is written as zeroes. With CRC control set drive writes the CRC it calculated since the gap.
+    fn write_byte(&mut self, side: usize) {
+        let value = if !self.disk_ready {
+            self.crc = 0;
+            0
+        } else if self.crc_control {
+            if !self.previous_crc_control {
+                self.crc = update_crc(update_crc(self.crc, 0), 0);
+            }
+            let value = self.crc as u8;
+            self.crc >>= 8;
+            value
+        } else {
+            self.crc = update_crc(self.crc, self.write_data);
+            self.write_data
+        };
+        if !self.crc_control {
+            self.transfer_complete = true;
+            self.disk_irq |= self.disk_irq_enabled;
+        }
+        self.disk.sides[side][self.position] = value;
+        self.gap_ended = false;
+        self.unsaved_writes = true;
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let value = self.timer_irq as u8
+            | (self.transfer_complete as u8) << 1
+            | (self.end_of_head as u8) << 6
+            | (self.disk_registers_enabled as u8) << 7;
+        self.transfer_complete = false;
+        self.timer_irq = false;
+        self.disk_irq = false;
+        value
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.motor_on = value & 0x01 != 0;
+        self.reset_transfer = value & 0x02 != 0;
+        self.read_mode = value & 0x04 != 0;
+        self.nametables.horizontal_mirroring = value & 0x08 != 0;
+        self.crc_control = value & 0x10 != 0;
+        self.disk_ready = value & 0x40 != 0;
+        self.disk_irq_enabled = value & 0x80 != 0;
+        self.disk_irq = false;
+    }
+}
+
+impl MemoryHandler for FdsAdapter {
+    fn read(&mut self, address: u16) -> u8 {
+        let no_disk = self.inserted_side.is_none();
+        match address {
+            0x4030 => self.read_status(),
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            },
+            // inserted disk is never write protected
+            0x4032 => 0x40 | no_disk as u8 | ((no_disk || !self.scanning) as u8) << 1 | (no_disk as u8) << 2,
+            0x4033 => 0x80, // battery is good
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if !self.disk_registers_enabled && address != 0x4023 {
+            return;
+        }
+        match address {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = value & 0x01 != 0;
+                self.timer_enabled = value & 0x02 != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            },
+            0x4023 => {
+                self.disk_registers_enabled = value & 0x01 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            },
+            0x4024 => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            0x4025 => self.write_control(value),
+            _ => (), // $4026 is expansion port output
+        }
+    }
+}
+
+// Disk contents aren't in snapshots, like save data on cartridges they stay as they are
+impl Snapshot for FdsAdapter {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.nametables.ram);
+        state.write_bool(self.nametables.horizontal_mirroring);
+        state.write_option_u8(self.inserted_side.map(|side| side as u8));
+        state.write_option_u8(self.next_side.map(|side| side as u8));
+        state.write_u64(self.insert_timer as u64);
+        state.write_bool(self.disk_registers_enabled);
+        state.write_u16(self.timer_reload);
+        state.write_u16(self.timer_counter);
+        state.write_bool(self.timer_repeat);
+        state.write_bool(self.timer_enabled);
+        state.write_bool(self.timer_irq);
+        state.write_bool(self.motor_on);
+        state.write_bool(self.reset_transfer);
+        state.write_bool(self.read_mode);
+        state.write_bool(self.crc_control);
+        state.write_bool(self.disk_ready);
+        state.write_bool(self.disk_irq_enabled);
+        state.write_bool(self.disk_irq);
+        state.write_u8(self.write_data);
+        state.write_u8(self.read_data);
+        state.write_bool(self.transfer_complete);
+        state.write_bool(self.end_of_head);
+        state.write_bool(self.scanning);
+        state.write_bool(self.gap_ended);
+        state.write_bool(self.previous_crc_control);
+        state.write_u16(self.crc);
+        state.write_u64(self.position as u64);
+        state.write_u64(self.delay as u64);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        state.read_bytes(&mut self.nametables.ram)?;
+        self.nametables.horizontal_mirroring = state.read_bool()?;
+        self.inserted_side = state.read_option_u8()?.map(|side| side as usize);
+        self.next_side = state.read_option_u8()?.map(|side| side as usize);
+        self.insert_timer = state.read_u64()? as u32;
+        self.disk_registers_enabled = state.read_bool()?;
+        self.timer_reload = state.read_u16()?;
+        self.timer_counter = state.read_u16()?;
+        self.timer_repeat = state.read_bool()?;
+        self.timer_enabled = state.read_bool()?;
+        self.timer_irq = state.read_bool()?;
+        self.motor_on = state.read_bool()?;
+        self.reset_transfer = state.read_bool()?;
+        self.read_mode = state.read_bool()?;
+        self.crc_control = state.read_bool()?;
+        self.disk_ready = state.read_bool()?;
+        self.disk_irq_enabled = state.read_bool()?;
+        self.disk_irq = state.read_bool()?;
+        self.write_data = state.read_u8()?;
+        self.read_data = state.read_u8()?;
+        self.transfer_complete = state.read_bool()?;
+        self.end_of_head = state.read_bool()?;
+        self.scanning = state.read_bool()?;
+        self.gap_ended = state.read_bool()?;
+        self.previous_crc_control = state.read_bool()?;
+        self.crc = state.read_u16()?;
+        self.position = state.read_u64()? as usize;
+        self.delay = state.read_u64()? as u32;
+        if self.inserted_side.is_some_and(|side| side >= self.disk.sides.len()) {
+            return Err("Snapshot has disk side that isn't in the image");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod fds_adapter_tests {
+    use crate::memory::fds::parse_file;
+
+    use super::*;
+
+    fn create_adapter() -> FdsAdapter {
+        let mut image = b"\x01*NINTENDO-HVC*".to_vec();
+        image.resize(56, 0);
+        image.extend([2, 0]);
+        image.resize(65500 * 2, 0);
+        FdsAdapter::new(parse_file(&image).unwrap())
+    }
+
+    // Runs until drive transfers a byte, returns the byte and how many cycles it took
+    fn wait_for_byte(adapter: &mut FdsAdapter) -> (u8, u32) {
+        for cycle in 1..=1_000_000 {
+            adapter.tick();
+            if adapter.get_irq_output() {
+                assert_eq!(adapter.read(0x4030) & 0x02, 0x02);
+                return (adapter.read(0x4031), cycle);
+            }
+        }
+        panic!("Drive didn't transfer a byte");
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut adapter = create_adapter();
+        adapter.write(0x4020, 0x02);
+        adapter.write(0x4021, 0x00);
+        adapter.write(0x4022, 0x03); // repeat
+        let irq_cycles: Vec<u32> = (1..=9).filter(|_| {
+            adapter.tick();
+            let irq = adapter.get_irq_output();
+            if irq {
+                assert_eq!(adapter.read(0x4030) & 0x01, 0x01);
+            }
+            irq
+        }).collect();
+        assert_eq!(irq_cycles, [3, 6, 9]);
+
+        adapter.write(0x4022, 0x02); // once
+        for _ in 0..10 {
+            adapter.tick();
+        }
+        assert_eq!(adapter.read(0x4030) & 0x01, 0x01);
+        assert_eq!(adapter.read(0x4030) & 0x01, 0x00);
+        assert!(!adapter.timer_enabled);
+    }
+
+    #[test]
+    fn test_read_blocks() {
+        let mut adapter = create_adapter();
+        assert_eq!(adapter.read(0x4032) & 0x07, 0x02); // inserted, not ready yet
+        adapter.write(0x4025, 0x25); // motor on, read mode
+        adapter.write(0x4025, 0xE5); // wait for the gap to end, with IRQ
+        assert_eq!(wait_for_byte(&mut adapter).0, 0x01); // mark is skipped
+        assert_eq!(adapter.read(0x4032) & 0x07, 0x00);
+        let (value, cycles) = wait_for_byte(&mut adapter);
+        assert_eq!((value, cycles), (b'*', BYTE_DELAY + 1));
+    }
+
+    #[test]
+    fn test_write_block() {
+        let mut adapter = create_adapter();
+        adapter.write(0x4025, 0x25);
+        adapter.write(0x4025, 0xE5);
+        wait_for_byte(&mut adapter);
+        adapter.write(0x4025, 0xA1); // write mode, gap
+        adapter.write(0x4024, 0x00);
+        wait_for_byte(&mut adapter);
+        adapter.write(0x4025, 0xE1); // mark and block
+        for value in [0x80, 0x02, 0x05] {
+            adapter.write(0x4024, value);
+            wait_for_byte(&mut adapter);
+        }
+        adapter.write(0x4025, 0xF1); // CRC
+        for _ in 0..BYTE_DELAY * 3 {
+            adapter.tick();
+        }
+        adapter.write(0x4025, 0x00); // motor off
+        adapter.tick();
+        assert!(adapter.take_finished_writes());
+        assert!(!adapter.take_finished_writes());
+        // disk info block was written over after its first byte
+        let crc = [0x80, 0x02, 0x05, 0x00, 0x00].iter().fold(0, |crc, byte| update_crc(crc, *byte)).to_le_bytes();
+        let file = adapter.disk.to_file();
+        assert_eq!(&file[..7], [0x01, 0x00, 0x80, 0x02, 0x05, crc[0], crc[1]]);
+    }
+
+    #[test]
+    fn test_switch_side() {
+        let mut adapter = create_adapter();
+        adapter.switch_side();
+        assert_eq!(adapter.read(0x4032) & 0x07, 0x07);
+        assert_eq!(adapter.describe_side(), "Ejected disk, inserting disk 1 side B");
+        for _ in 0..INSERT_DELAY {
+            adapter.tick();
+        }
+        assert_eq!(adapter.inserted_side, Some(1));
+        adapter.switch_side();
+        adapter.switch_side();
+        assert_eq!(adapter.next_side, Some(1)); // wraps around
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut adapter = create_adapter();
+        adapter.nametables.write(0x2400, 1);
+        assert_eq!(adapter.nametables.read(0x2C00), 1);
+        adapter.write(0x4025, 0x08);
+        adapter.nametables.write(0x2400, 2);
+        assert_eq!(adapter.nametables.read(0x2000), 2);
+        assert_eq!(adapter.nametables.read(0x2800), 1);
+    }
+}
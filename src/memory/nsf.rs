@@ -0,0 +1,347 @@
+use nom::bytes::complete::{ tag, take };
+use nom::number::complete::{ le_i32, le_u16, le_u32, u8 as le_u8 };
+use nom::IResult;
+
+use super::{ ines::ConsoleTiming, mappers::ExpansionChip };
+
+const NSF_MAGIC: &[u8] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8] = b"NSFE";
+// Play routine periods in microseconds, used when NSFe file has no RATE chunk
+const DEFAULT_NTSC_PERIOD: u16 = 16639;
+const DEFAULT_PAL_PERIOD: u16 = 19997;
+
+// Music ripped from a game: code and data to be loaded at load address, with INIT and PLAY routines
+#[derive(Debug, Default)]
+pub struct NsfData {
+    pub song_count: u8,
+    pub starting_song: u8, // counted from 1
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ntsc_play_period: u16, // in microseconds
+    pub pal_play_period: u16,
+    pub bank_init: [u8; 8], // all zeroes means no bank switching
+    pub console_timing: Option<ConsoleTiming>,
+    pub expansion_chips: u8,
+    pub data: Vec<u8>,
+    // NSFe only, empty for plain NSF
+    pub track_names: Vec<String>,
+    pub track_lengths: Vec<Option<u32>>, // in milliseconds
+}
+
+impl NsfData {
+    pub fn is_bank_switched(&self) -> bool {
+        self.bank_init.iter().any(|bank| *bank != 0)
+    }
+
+    // FDS has RAM at $6000-$DFFF, tunes for it can load below $8000 and write over their own code and data
+    pub fn uses_fds(&self) -> bool {
+        self.expansion_chips & 0x04 != 0
+    }
+
+    // Tracks are counted from 1, there's nothing for track 0
+    pub fn get_track_name(&self, track: u8) -> Option<&str> {
+        let index = (track as usize).checked_sub(1)?;
+        self.track_names.get(index).map(|name| name.as_str()).filter(|name| !name.is_empty())
+    }
+
+    pub fn get_track_length(&self, track: u8) -> Option<u32> {
+        let index = (track as usize).checked_sub(1)?;
+        self.track_lengths.get(index).copied().flatten()
+    }
+
+    pub fn get_expansion_audio(&self) -> Vec<ExpansionChip> {
+        [
+            (0x01, ExpansionChip::VRC6 { swapped_lines: false }),
+            (0x02, ExpansionChip::VRC7),
+            (0x04, ExpansionChip::FDS),
+            (0x08, ExpansionChip::MMC5),
+            (0x10, ExpansionChip::Namco163),
+            (0x20, ExpansionChip::Sunsoft5B),
+        ].into_iter().filter(|(bit, _)| self.expansion_chips & bit != 0).map(|(_, chip)| chip).collect()
+    }
+}
+
+pub fn is_nsf(data: &[u8]) -> bool {
+    data.starts_with(NSF_MAGIC) || data.starts_with(NSFE_MAGIC)
+}
+
+pub fn parse_file(data: &[u8]) -> Result<NsfData, &'static str> {
+    let nsf = if data.starts_with(NSFE_MAGIC) {
+        parse_nsfe(&data[NSFE_MAGIC.len()..])?
+    } else {
+        parse_nsf(data).map(|(_, nsf)| nsf).map_err(|_| "NSF header is too short or has wrong magic")?
+    };
+    // Bank switched data is placed by banks, load address only says where it starts inside the first one
+    if !nsf.is_bank_switched() && nsf.load_address < 0x8000 && !(nsf.uses_fds() && nsf.load_address >= 0x6000) {
+        return Err("NSF load address has to be at least $8000, or $6000 for FDS tunes");
+    }
+    Ok(nsf)
+}
+
+fn get_console_timing(flags: u8) -> ConsoleTiming {
+    match flags & 0b11 {
+        0b00 => ConsoleTiming::NTSC,
+        0b01 => ConsoleTiming::PAL,
+        _ => ConsoleTiming::MultiRegion,
+    }
+}
+
+// Fixed size string padded with zeroes
+fn parse_padded_string(length: usize) -> impl Fn(&[u8]) -> IResult<&[u8], String> {
+    move |input| {
+        let (input, bytes) = take(length)(input)?;
+        let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(length);
+        Ok((input, String::from_utf8_lossy(&bytes[..end]).to_string()))
+    }
+}
+
+fn parse_nsf(input: &[u8]) -> IResult<&[u8], NsfData> {
+    let (input, _) = tag(NSF_MAGIC)(input)?;
+    let (input, _version) = le_u8(input)?;
+    let (input, song_count) = le_u8(input)?;
+    let (input, starting_song) = le_u8(input)?;
+    let (input, load_address) = le_u16(input)?;
+    let (input, init_address) = le_u16(input)?;
+    let (input, play_address) = le_u16(input)?;
+    let (input, title) = parse_padded_string(32)(input)?;
+    let (input, artist) = parse_padded_string(32)(input)?;
+    let (input, copyright) = parse_padded_string(32)(input)?;
+    let (input, ntsc_play_period) = le_u16(input)?;
+    let (input, bank_init) = take(8usize)(input)?;
+    let (input, pal_play_period) = le_u16(input)?;
+    let (input, timing_flags) = le_u8(input)?;
+    let (input, expansion_chips) = le_u8(input)?;
+    let (input, _reserved) = take(4usize)(input)?;
+    Ok((&[], NsfData {
+        song_count,
+        starting_song,
+        load_address,
+        init_address,
+        play_address,
+        title,
+        artist,
+        copyright,
+        ntsc_play_period,
+        pal_play_period,
+        bank_init: bank_init.try_into().unwrap(),
+        console_timing: Some(get_console_timing(timing_flags)),
+        expansion_chips,
+        data: input.to_vec(),
+        track_names: vec![],
+        track_lengths: vec![],
+    }))
+}
+
+fn parse_chunk(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (input, length) = le_u32(input)?;
+    let (input, id) = take(4usize)(input)?;
+    let (input, data) = take(length as usize)(input)?;
+    Ok((input, (id, data)))
+}
+
+// Zero terminated strings one after another
+fn parse_string_list(data: &[u8]) -> Vec<String> {
+    data.split(|byte| *byte == 0).map(|string| String::from_utf8_lossy(string).to_string()).collect()
+}
+
+// NSFe is a list of chunks. Uppercase chunks are required to play the file, lowercase ones are optional metadata.
+fn parse_nsfe(mut input: &[u8]) -> Result<NsfData, &'static str> {
+    let mut nsf = NsfData {
+        song_count: 1,
+        starting_song: 1,
+        ntsc_play_period: DEFAULT_NTSC_PERIOD,
+        pal_play_period: DEFAULT_PAL_PERIOD,
+        ..NsfData::default()
+    };
+    let mut has_info = false;
+    loop {
+        let (rest, (id, data)) = parse_chunk(input).map_err(|_| "NSFe chunk is cut short")?;
+        input = rest;
+        match id {
+            b"INFO" => {
+                let info = |data| -> IResult<&[u8], (u16, u16, u16, u8, u8)> {
+                    let (data, load_address) = le_u16(data)?;
+                    let (data, init_address) = le_u16(data)?;
+                    let (data, play_address) = le_u16(data)?;
+                    let (data, timing_flags) = le_u8(data)?;
+                    let (data, expansion_chips) = le_u8(data)?;
+                    Ok((data, (load_address, init_address, play_address, timing_flags, expansion_chips)))
+                };
+                let (rest, (load_address, init_address, play_address, timing_flags, expansion_chips)) = info(data).map_err(|_| "NSFe INFO chunk is too short")?;
+                nsf.load_address = load_address;
+                nsf.init_address = init_address;
+                nsf.play_address = play_address;
+                nsf.console_timing = Some(get_console_timing(timing_flags));
+                nsf.expansion_chips = expansion_chips;
+                nsf.song_count = rest.first().copied().unwrap_or(1);
+                // counted from 0 in NSFe
+                nsf.starting_song = rest.get(1).map(|song| song.saturating_add(1)).unwrap_or(1).clamp(1, nsf.song_count.max(1));
+                has_info = true;
+            },
+            b"DATA" => nsf.data = data.to_vec(),
+            b"BANK" => {
+                for (bank, value) in nsf.bank_init.iter_mut().zip(data) {
+                    *bank = *value;
+                }
+            },
+            b"RATE" => {
+                let (rest, ntsc_play_period) = le_u16::<_, nom::error::Error<_>>(data).map_err(|_| "NSFe RATE chunk is too short")?;
+                nsf.ntsc_play_period = ntsc_play_period;
+                if let Ok((_, pal_play_period)) = le_u16::<_, nom::error::Error<_>>(rest) {
+                    nsf.pal_play_period = pal_play_period;
+                }
+            },
+            b"auth" => {
+                let mut strings = parse_string_list(data).into_iter();
+                nsf.title = strings.next().unwrap_or_default();
+                nsf.artist = strings.next().unwrap_or_default();
+                nsf.copyright = strings.next().unwrap_or_default();
+            },
+            b"tlbl" => nsf.track_names = parse_string_list(data),
+            b"time" => {
+                // zero or negative length means unknown
+                nsf.track_lengths = data.chunks_exact(4)
+                    .map(|length| le_i32::<_, nom::error::Error<_>>(length).map(|(_, length)| u32::try_from(length).ok().filter(|length| *length > 0)).unwrap_or(None))
+                    .collect();
+            },
+            b"NEND" => break,
+            id if id[0].is_ascii_uppercase() => return Err("NSFe file needs a feature that isn't supported"),
+            _ => (), // unknown optional chunk
+        }
+    }
+    if !has_info || nsf.data.is_empty() {
+        return Err("NSFe file is missing INFO or DATA chunk");
+    }
+    Ok(nsf)
+}
+
+#[cfg(test)]
+mod nsf_tests {
+    use super::*;
+
+    fn create_nsf_header() -> Vec<u8> {
+        let mut header = NSF_MAGIC.to_vec();
+        header.extend_from_slice(&[1, 12, 3]); // version, songs, starting song
+        header.extend_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        let mut title = b"Title".to_vec();
+        title.resize(32, 0);
+        header.extend_from_slice(&title);
+        header.extend_from_slice(&[b'A'; 32]); // not terminated
+        header.extend_from_slice(&[0; 32]);
+        header.extend_from_slice(&DEFAULT_NTSC_PERIOD.to_le_bytes());
+        header.extend_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        header.extend_from_slice(&DEFAULT_PAL_PERIOD.to_le_bytes());
+        header.extend_from_slice(&[0b01, 0x00, 0, 0, 0, 0]);
+        header
+    }
+
+    fn push_chunk(file: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+        file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        file.extend_from_slice(id);
+        file.extend_from_slice(data);
+    }
+
+    #[test]
+    fn test_nsf_header() {
+        let mut file = create_nsf_header();
+        assert_eq!(file.len(), 0x80);
+        file.extend_from_slice(&[0x60, 0x60]);
+        let nsf = parse_file(&file).unwrap();
+
+        assert_eq!((nsf.song_count, nsf.starting_song), (12, 3));
+        assert_eq!((nsf.load_address, nsf.init_address, nsf.play_address), (0x8000, 0x8003, 0x8006));
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "A".repeat(32));
+        assert_eq!(nsf.copyright, "");
+        assert!(nsf.is_bank_switched());
+        assert!(matches!(nsf.console_timing, Some(ConsoleTiming::PAL)));
+        assert_eq!(nsf.data, [0x60, 0x60]);
+        assert_eq!(nsf.get_expansion_audio(), []);
+
+        file[0x7B] = 0x37; // VRC6, VRC7, FDS, N163 and 5B
+        let nsf = parse_file(&file).unwrap();
+        assert_eq!(nsf.get_expansion_audio(), [
+            ExpansionChip::VRC6 { swapped_lines: false },
+            ExpansionChip::VRC7,
+            ExpansionChip::FDS,
+            ExpansionChip::Namco163,
+            ExpansionChip::Sunsoft5B,
+        ]);
+        assert_eq!(nsf.get_track_name(1), None);
+    }
+
+    #[test]
+    fn test_low_load_address() {
+        let mut file = create_nsf_header();
+        file[0x09] = 0x60; // load at $6000
+        file[0x70..0x78].fill(0); // no bank switching
+        assert!(parse_file(&file).is_err());
+
+        file[0x7B] = 0x04; // FDS
+        assert_eq!(parse_file(&file).unwrap().load_address, 0x6000);
+        file[0x09] = 0x5F;
+        assert!(parse_file(&file).is_err());
+    }
+
+    #[test]
+    fn test_nsfe_chunks() {
+        let mut file = NSFE_MAGIC.to_vec();
+        push_chunk(&mut file, b"INFO", &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0b10, 0x00, 3, 1]);
+        push_chunk(&mut file, b"DATA", &[0x60]);
+        push_chunk(&mut file, b"auth", b"Game\0Composer\0Company\0Ripper\0");
+        push_chunk(&mut file, b"tlbl", b"Intro\0\0Ending\0");
+        push_chunk(&mut file, b"time", &[1000i32.to_le_bytes(), (-1i32).to_le_bytes()].concat());
+        push_chunk(&mut file, b"xtra", b"ignored");
+        push_chunk(&mut file, b"NEND", &[]);
+        let nsf = parse_file(&file).unwrap();
+
+        assert!(is_nsf(&file));
+        assert_eq!((nsf.song_count, nsf.starting_song), (3, 2));
+        assert_eq!(nsf.play_address, 0x8006);
+        assert!(matches!(nsf.console_timing, Some(ConsoleTiming::MultiRegion)));
+        assert!(!nsf.is_bank_switched());
+        assert!(nsf.get_expansion_audio().is_empty());
+        assert_eq!(nsf.ntsc_play_period, DEFAULT_NTSC_PERIOD);
+        assert_eq!((nsf.title.as_str(), nsf.artist.as_str()), ("Game", "Composer"));
+        assert_eq!(nsf.get_track_name(1), Some("Intro"));
+        assert_eq!(nsf.get_track_name(2), None);
+        assert_eq!(nsf.get_track_name(3), Some("Ending"));
+        assert_eq!(nsf.get_track_length(1), Some(1000));
+        assert_eq!(nsf.get_track_length(2), None);
+        assert_eq!(nsf.get_track_length(3), None);
+        assert_eq!(nsf.get_track_name(0), None);
+        assert_eq!(nsf.get_track_length(0), None);
+    }
+
+    #[test]
+    fn test_nsfe_out_of_range_values() {
+        let mut file = NSFE_MAGIC.to_vec();
+        push_chunk(&mut file, b"INFO", &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0b00, 0x00, 4, 255]);
+        push_chunk(&mut file, b"DATA", &[0x60]);
+        push_chunk(&mut file, b"time", &[0i32.to_le_bytes(), 500i32.to_le_bytes()].concat());
+        push_chunk(&mut file, b"NEND", &[]);
+        let nsf = parse_file(&file).unwrap();
+
+        assert_eq!(nsf.starting_song, 4);
+        assert_eq!(nsf.get_track_length(1), None);
+        assert_eq!(nsf.get_track_length(2), Some(500));
+    }
+
+    #[test]
+    fn test_nsfe_errors() {
+        let mut file = NSFE_MAGIC.to_vec();
+        push_chunk(&mut file, b"DATA", &[0x60]);
+        assert_eq!(parse_file(&file).unwrap_err(), "NSFe chunk is cut short");
+        push_chunk(&mut file, b"NEND", &[]);
+        assert_eq!(parse_file(&file).unwrap_err(), "NSFe file is missing INFO or DATA chunk");
+
+        let mut file = NSFE_MAGIC.to_vec();
+        push_chunk(&mut file, b"NSF2", &[0]);
+        assert_eq!(parse_file(&file).unwrap_err(), "NSFe file needs a feature that isn't supported");
+        assert!(parse_file(b"NESM\x1A\x01").is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use crate::{ memory::nsf::NsfData, processor::CPU, region::Region, MemoryHandler, MEM };
+
+// Unused address space, holds loop the CPU spins in between routine calls
+const IDLE_ADDRESS: u16 = 0x4100;
+const IDLE_LOOP: [u8; 3] = [0x4C, 0x00, 0x41]; // JMP $4100
+const BANK_SIZE: usize = 0x1000;
+
+// Plays NSF music: maps its banks into $8000-$FFFF, calls INIT when song starts and PLAY at rate from header.
+// Routines return into idle loop, so the CPU runs freely and PLAY is only called when it's back there.
+pub struct NsfPlayer {
+    nsf: NsfData,
+    banks: Vec<[u8; BANK_SIZE]>,
+    ram_data: Vec<u8>, // FDS tunes loaded below $8000 have part of their data in RAM at $6000-$7FFF
+    initial_banks: [u8; 8],
+    selected_banks: [u8; 8],
+    is_pal: bool,
+    cpu_clock_rate: f64,
+    play_period: f64, // in CPU cycles
+    play_timer: f64,
+    current_song: u8, // counted from 1
+    song_cycles: u64,
+    track_changed: bool,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: NsfData, region: Region) -> Self {
+        let (banks, initial_banks) = create_banks(&nsf);
+        let ram_data = create_ram_data(&nsf);
+        let is_pal = region == Region::PAL;
+        let period = if is_pal { nsf.pal_play_period } else { nsf.ntsc_play_period };
+        let cpu_clock_rate = region.get_cpu_clock_rate();
+        Self {
+            current_song: nsf.starting_song.clamp(1, nsf.song_count.max(1)),
+            nsf,
+            banks,
+            ram_data,
+            initial_banks,
+            selected_banks: initial_banks,
+            is_pal,
+            cpu_clock_rate,
+            play_period: period.max(1) as f64 / 1_000_000.0 * cpu_clock_rate,
+            play_timer: 0.0,
+            song_cycles: 0,
+            track_changed: true,
+        }
+    }
+
+    // Song starts from clean RAM and silent APU, with song number in A and region in X
+    pub fn start_song(&mut self, song: u8, cpu: &mut CPU, memory: &mut MEM) {
+        self.current_song = song;
+        self.song_cycles = 0;
+        self.play_timer = 0.0;
+        self.track_changed = true;
+        self.selected_banks = self.initial_banks;
+        if self.uses_fds_ram() {
+            self.banks = create_banks(&self.nsf).0;
+        }
+        for address in (0x0000..0x0800).chain(0x6000..0x8000) {
+            memory.write_no_hook(address, 0);
+        }
+        for (i, byte) in self.ram_data.iter().enumerate() {
+            memory.write_no_hook(0x6000 + i, *byte);
+        }
+        for (i, byte) in IDLE_LOOP.iter().enumerate() {
+            memory.write_no_hook(IDLE_ADDRESS as usize + i, *byte);
+        }
+        for address in 0x4000..0x4014 {
+            memory.write(address, 0);
+        }
+        memory.write(0x4015, 0x00);
+        memory.write(0x4015, 0x0F);
+        memory.write(0x4017, 0x40);
+
+        cpu.store_s(0xFD);
+        cpu.I = true;
+        cpu.store_a(song - 1);
+        cpu.store_x(self.is_pal as u8);
+        self.call_routine(self.nsf.init_address, cpu, memory);
+    }
+
+    pub fn restart_song(&mut self, cpu: &mut CPU, memory: &mut MEM) {
+        self.start_song(self.current_song, cpu, memory);
+    }
+
+    pub fn next_track(&mut self, cpu: &mut CPU, memory: &mut MEM) {
+        let song = self.current_song % self.get_song_count() + 1;
+        self.start_song(song, cpu, memory);
+    }
+
+    pub fn previous_track(&mut self, cpu: &mut CPU, memory: &mut MEM) {
+        let song = if self.current_song <= 1 { self.get_song_count() } else { self.current_song - 1 };
+        self.start_song(song, cpu, memory);
+    }
+
+    pub fn uses_fds_ram(&self) -> bool {
+        self.nsf.uses_fds()
+    }
+
+    fn get_song_count(&self) -> u8 {
+        self.nsf.song_count.max(1)
+    }
+
+    // Routine returns with RTS into idle loop
+    fn call_routine(&self, address: u16, cpu: &mut CPU, memory: &mut MEM) {
+        let return_address = IDLE_ADDRESS - 1;
+        cpu.push_stack((return_address >> 8) as u8, memory);
+        cpu.push_stack(return_address as u8, memory);
+        cpu.store_pc(address);
+    }
+
+    // Clocked every CPU cycle, after the CPU
+    pub fn tick(&mut self, cpu: &mut CPU, memory: &mut MEM) {
+        self.song_cycles += 1;
+        self.play_timer += 1.0;
+        // PLAY that ran too long makes following call late, but calls don't pile up
+        if self.play_timer >= self.play_period && cpu.is_ready() && cpu.get_pc() == IDLE_ADDRESS {
+            self.play_timer = (self.play_timer - self.play_period) % self.play_period;
+            self.call_routine(self.nsf.play_address, cpu, memory);
+        }
+        if let Some(length) = self.nsf.get_track_length(self.current_song) {
+            if self.song_cycles as f64 >= length as f64 / 1000.0 * self.cpu_clock_rate {
+                self.next_track(cpu, memory);
+            }
+        }
+    }
+
+    // True once after song changes, also right after player is created
+    pub fn take_track_changed(&mut self) -> bool {
+        std::mem::take(&mut self.track_changed)
+    }
+
+    // Like "Track 2/12: Boss (1:30) - Game by Composer"
+    pub fn describe_track(&self) -> String {
+        let mut description = format!("Track {}/{}", self.current_song, self.get_song_count());
+        if let Some(name) = self.nsf.get_track_name(self.current_song) {
+            description += &format!(": {name}");
+        }
+        if let Some(length) = self.nsf.get_track_length(self.current_song) {
+            let seconds = length / 1000;
+            description += &format!(" ({}:{:02})", seconds / 60, seconds % 60);
+        }
+        if !self.nsf.title.is_empty() {
+            description += &format!(" - {}", self.nsf.title);
+        }
+        if !self.nsf.artist.is_empty() {
+            description += &format!(" by {}", self.nsf.artist);
+        }
+        description
+    }
+}
+
+// Without bank switching data is just loaded at load address, from $6000 for FDS tunes and from $8000 for the rest.
+// Parser makes sure load address isn't lower than that.
+fn create_fixed_image(nsf: &NsfData) -> Vec<u8> {
+    let mut image = vec![0; 0xA000];
+    let start = (nsf.load_address as usize).saturating_sub(0x6000);
+    let length = nsf.data.len().min(0xA000 - start);
+    image[start..start + length].copy_from_slice(&nsf.data[..length]);
+    image
+}
+
+fn create_ram_data(nsf: &NsfData) -> Vec<u8> {
+    if nsf.is_bank_switched() {
+        return vec![];
+    }
+    create_fixed_image(nsf)[..0x2000].to_vec()
+}
+
+// Fixed image at $8000-$FFFF is the same as 8 fixed banks
+fn create_banks(nsf: &NsfData) -> (Vec<[u8; BANK_SIZE]>, [u8; 8]) {
+    let (image, initial_banks) = if nsf.is_bank_switched() {
+        // load address says where data starts inside the first bank
+        let mut image = vec![0; nsf.load_address as usize % BANK_SIZE];
+        image.extend_from_slice(&nsf.data);
+        (image, nsf.bank_init)
+    } else {
+        (create_fixed_image(nsf).split_off(0x2000), [0, 1, 2, 3, 4, 5, 6, 7])
+    };
+    let banks = image.chunks(BANK_SIZE).map(|chunk| {
+        let mut bank = [0; BANK_SIZE];
+        bank[..chunk.len()].copy_from_slice(chunk);
+        bank
+    }).collect();
+    (banks, initial_banks)
+}
+
+// Reads from $8000-$FFFF, bank switching writes to $5FF8-$5FFF and FDS RAM writes to $8000-$DFFF
+impl MemoryHandler for NsfPlayer {
+    fn read(&mut self, address: u16) -> u8 {
+        let slot = (address as usize - 0x8000) / BANK_SIZE;
+        match self.banks.get(self.selected_banks[slot] as usize) {
+            Some(bank) => bank[address as usize % BANK_SIZE],
+            None => 0, // past the end of file
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x5FF8..=0x5FFF => if self.nsf.is_bank_switched() {
+                self.selected_banks[address as usize - 0x5FF8] = value;
+            },
+            _ => {
+                let slot = (address as usize - 0x8000) / BANK_SIZE;
+                if let Some(bank) = self.banks.get_mut(self.selected_banks[slot] as usize) {
+                    bank[address as usize % BANK_SIZE] = value;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod nsf_player_tests {
+    use crate::memory::{ HandlerPtrWrapper, MemoryOperation, MemoryRegion };
+
+    use super::*;
+
+    fn create_nsf(code: &[u8]) -> NsfData {
+        NsfData {
+            song_count: 3,
+            starting_song: 2,
+            load_address: 0x8000,
+            init_address: 0x8000,
+            play_address: 0x8003,
+            ntsc_play_period: 1000,
+            data: code.to_vec(),
+            ..NsfData::default()
+        }
+    }
+
+    fn hook_player(player: &mut NsfPlayer, memory: &mut MEM) {
+        let handler = HandlerPtrWrapper(player as *mut NsfPlayer as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x8000, 0x8000), handler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x5FF8, 0x0008), handler);
+    }
+
+    #[test]
+    fn test_bank_switching() {
+        let mut nsf = create_nsf(&[]);
+        nsf.load_address = 0x8010;
+        nsf.data = [vec![0xAA; BANK_SIZE - 0x10], vec![0xBB; BANK_SIZE], vec![0xCC; 1]].concat();
+        nsf.bank_init = [1, 0, 0, 0, 0, 0, 0, 2];
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        hook_player(&mut player, &mut memory);
+
+        assert_eq!(memory.read(0x8000, 1), 0xBB);
+        assert_eq!(memory.read(0x900F, 1), 0x00); // before load address
+        assert_eq!(memory.read(0x9010, 1), 0xAA);
+        assert_eq!(memory.read(0xF000, 1), 0xCC);
+        assert_eq!(memory.read(0xF001, 1), 0x00);
+        memory.write(0x5FFF, 1);
+        assert_eq!(memory.read(0xF000, 1), 0xBB);
+        memory.write(0x5FFF, 9); // no such bank
+        assert_eq!(memory.read(0xF000, 1), 0x00);
+    }
+
+    #[test]
+    fn test_fixed_load_address() {
+        let mut nsf = create_nsf(&[0x11, 0x22]);
+        nsf.load_address = 0xC000;
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        hook_player(&mut player, &mut memory);
+
+        assert_eq!(memory.read(0xC000, 2), 0x2211);
+        memory.write(0x5FFC, 3); // ignored without bank switching
+        assert_eq!(memory.read(0xC000, 2), 0x2211);
+    }
+
+    #[test]
+    fn test_fds_load_below_rom() {
+        let mut nsf = create_nsf(&[]);
+        nsf.expansion_chips = 0x04;
+        nsf.load_address = 0x7FFE;
+        nsf.data = vec![0x11, 0x22, 0x33, 0x44];
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        let mut cpu = CPU::new();
+        hook_player(&mut player, &mut memory);
+        player.restart_song(&mut cpu, &mut memory);
+
+        assert_eq!(memory.read(0x7FFE, 2), 0x2211);
+        assert_eq!(memory.read(0x8000, 2), 0x4433);
+        assert_eq!(memory.read(0x6000, 1), 0x00);
+        memory.write(0x7FFE, 0x55); // RAM
+        player.restart_song(&mut cpu, &mut memory);
+        assert_eq!(memory.read(0x7FFE, 1), 0x11);
+    }
+
+    #[test]
+    fn test_fds_ram_writes() {
+        let mut nsf = create_nsf(&[0x11, 0x22]);
+        nsf.expansion_chips = 0x04;
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        let mut cpu = CPU::new();
+        hook_player(&mut player, &mut memory);
+        let handler = HandlerPtrWrapper(&mut player as *mut NsfPlayer as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x8000, 0x6000), handler);
+        assert!(player.uses_fds_ram());
+
+        memory.write(0x8000, 0x33);
+        memory.write(0xA000, 0x44);
+        assert_eq!(memory.read(0x8000, 2), 0x2233);
+        assert_eq!(memory.read(0xA000, 1), 0x44);
+        player.restart_song(&mut cpu, &mut memory); // reloads data
+        assert_eq!(memory.read(0x8000, 2), 0x2211);
+        assert_eq!(memory.read(0xA000, 1), 0x00);
+    }
+
+    #[test]
+    fn test_init_and_play_calls() {
+        // PLAY: INC $12, RTS; INIT: STA $10, STX $11, RTS
+        let mut nsf = create_nsf(&[0xE6, 0x12, 0x60, 0x85, 0x10, 0x86, 0x11, 0x60]);
+        (nsf.init_address, nsf.play_address) = (0x8003, 0x8000);
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        let mut cpu = CPU::new();
+        hook_player(&mut player, &mut memory);
+        player.start_song(3, &mut cpu, &mut memory);
+
+        let play_period = 1000.0 / 1_000_000.0 * Region::NTSC.get_cpu_clock_rate();
+        for _ in 0..(play_period * 10.5) as usize {
+            cpu.tick(&mut memory).unwrap();
+            player.tick(&mut cpu, &mut memory);
+        }
+        assert_eq!(memory.read(0x10, 2), 0x0002); // song 3, NTSC
+        assert_eq!(memory.read(0x12, 1), 10);
+        assert_eq!(cpu.get_s(), 0xFD); // every routine returned
+
+        player.previous_track(&mut cpu, &mut memory);
+        assert_eq!(memory.read(0x12, 1), 0); // RAM is cleared
+        assert_eq!(player.current_song, 2);
+    }
+
+    #[test]
+    fn test_tracks_wrap_around_and_advance() {
+        let mut nsf = create_nsf(&[0x60, 0x00, 0x00, 0x60]);
+        nsf.track_lengths = vec![None, None, Some(1)];
+        nsf.track_names = vec![String::new(), String::new(), String::from("Ending")];
+        nsf.title = String::from("Game");
+        let mut player = NsfPlayer::new(nsf, Region::NTSC);
+        let mut memory = MEM::new(0x10000);
+        let mut cpu = CPU::new();
+        hook_player(&mut player, &mut memory);
+        assert!(player.take_track_changed());
+        assert!(!player.take_track_changed());
+
+        player.previous_track(&mut cpu, &mut memory);
+        player.previous_track(&mut cpu, &mut memory);
+        assert_eq!(player.current_song, 3);
+        assert_eq!(player.describe_track(), "Track 3/3: Ending (0:00) - Game");
+        for _ in 0..1790 {
+            cpu.tick(&mut memory).unwrap();
+            player.tick(&mut cpu, &mut memory);
+        }
+        assert_eq!(player.current_song, 1);
+        assert!(player.take_track_changed());
+    }
+}
@@ -1,23 +1,22 @@
-use std::{ process::exit, sync::mpsc::{Receiver, Sender, channel}, time::Instant };
+use std::process::exit;
 
-use minifb::{ Window, Key };
+use minifb::{ Window, Key, MouseButton, MouseMode };
 
-use crate::{memory::*, pixel_processor::tile::PixelPaletteColorIndex};
+use crate::{memory::*, pixel_processor::tile::ColorMode, region::Region, snapshot::{ Snapshot, StateReader, StateWriter }};
+use background_fetcher::{ BackgroundShifters, BackgroundTileData };
 use ppu_memory::PPU_MEM;
 
 pub mod tile;
 pub mod rendering;
 pub mod helper;
-mod memory_events_processor;
+mod background_fetcher;
+mod registers;
 
+// PPU pointer for devices that look at the picture (like Zapper)
 #[derive(Clone, Copy)]
-pub struct MemPtrWrapper(pub *mut MEM);
-unsafe impl Sync for MemPtrWrapper {}
-unsafe impl Send for MemPtrWrapper {}
-#[derive(Clone, Copy)]
-pub struct CPUPtrWrapper(pub *mut crate::CPU);
-unsafe impl Sync for CPUPtrWrapper {}
-unsafe impl Send for CPUPtrWrapper {}
+pub struct PPUPtrWrapper(pub *const PPU);
+unsafe impl Sync for PPUPtrWrapper {}
+unsafe impl Send for PPUPtrWrapper {}
 
 #[derive(Clone, Copy)]
 pub struct PPUVramAddr(u16);
@@ -107,14 +106,11 @@ impl PPUVramAddr {
 }
 
 pub struct PPU {
-    memory_pointer: MemPtrWrapper,
     #[allow(unused)]
     framebuffer: Vec<u32>,
-    main_window: Window,
-    pattern_table_window: Window,
+    main_window: Option<Window>, // no windows when headless
+    pattern_table_window: Option<Window>,
     ppu_memory: PPU_MEM,
-    memory_events_rx: Receiver<MemoryEvent>,
-    cpu_pointer: CPUPtrWrapper,
     dot: u64,
     odd_frame: bool,
     is_closed: bool,
@@ -127,26 +123,36 @@ pub struct PPU {
     vram_v: PPUVramAddr,
     vram_t: PPUVramAddr,
     fine_x: u8,
-    controller_state: u8,
     oam_data: [u8; 256],
     oam_addr: usize,
     fg_plane: bool,
-    frame_start: Instant,
     fg_rendering: bool,
     bg_rendering: bool,
+    fg_left_rendering: bool,
+    bg_left_rendering: bool,
+    color_mode: ColorMode,
+    ppudata_read_buffer: u8,
+    io_latch: u8,
+    io_latch_age: u8, // in frames
+    vblank_suppressed: bool,
+    bg_next_tile: BackgroundTileData,
+    bg_shifters: BackgroundShifters,
+    scanline_sprites: Vec<usize>,
+    vblank: bool,
+    sprite_0_hit: bool,
+    sprite_overflow: bool,
+    region: Region,
+    frame_finished: bool,
+    display_enabled: bool,
 }
 
 impl PPU {
-    pub fn new(memory_pointer: MemPtrWrapper, ppu_memory: PPU_MEM, cpu_pointer: CPUPtrWrapper) -> (Self, Sender<MemoryEvent>) {
-        let (tx, memory_events_rx): (Sender<MemoryEvent>, Receiver<MemoryEvent>) = channel();
-        return (Self {
-            memory_pointer,
+    pub fn new(ppu_memory: PPU_MEM, region: Region, headless: bool) -> Self {
+        return Self {
             framebuffer: vec![0; 256*240],
-            main_window: Self::create_main_window(),
-            pattern_table_window: Self::create_pattern_window(),
+            main_window: (!headless).then(Self::create_main_window),
+            pattern_table_window: (!headless).then(Self::create_pattern_window),
             ppu_memory,
-            memory_events_rx,
-            cpu_pointer,
             dot: 0,
             odd_frame: false,
             is_closed: false,
@@ -159,15 +165,28 @@ impl PPU {
             vram_v: PPUVramAddr(0),
             vram_t: PPUVramAddr(0),
             fine_x: 0,
-            controller_state: 0,
             oam_data: [0; 256],
             oam_addr: 0,
             fg_plane: false,
-            frame_start: Instant::now(),
             fg_rendering: false,
             bg_rendering: false,
-        },
-        tx)
+            fg_left_rendering: false,
+            bg_left_rendering: false,
+            color_mode: ColorMode::default(),
+            ppudata_read_buffer: 0,
+            io_latch: 0,
+            io_latch_age: 0,
+            vblank_suppressed: false,
+            bg_next_tile: BackgroundTileData::default(),
+            bg_shifters: BackgroundShifters::default(),
+            scanline_sprites: Vec::with_capacity(64),
+            vblank: false,
+            sprite_0_hit: false,
+            sprite_overflow: false,
+            region,
+            frame_finished: false,
+            display_enabled: true,
+        }
     }
 
     fn get_line_dot(&self) -> (usize, usize) {
@@ -176,48 +195,36 @@ impl PPU {
 
     pub fn tick(&mut self) {
         if !self.is_closed {
-            if self.main_window.is_key_down(Key::Escape) { self.is_closed = true; exit(0); };
-            if self.pattern_table_window.is_key_down(Key::Escape) { self.is_closed = true; exit(0); };
-
-            self.process_memory_events();
+            if self.is_escape_down() { self.is_closed = true; exit(0); };
 
-            if (!self.odd_frame && self.dot >= 89342) || (self.odd_frame && self.dot >= 89341) {
+            if self.dot >= self.region.get_frame_length(self.odd_frame, self.is_rendering_enabled()) {
                 self.dot = 0;
                 self.odd_frame = !self.odd_frame;
-                if self.fg_rendering {
-                    self.render_oam();
+                self.decay_io_latch();
+                if self.display_enabled {
+                    self.display_frame();
+                    if self.pattern_table_window.as_ref().is_some_and(|window| window.is_open()) { self.render_pattern_table(); } // If pattern table is open - we also render it
                 }
-                self.display_frame();
-                self.wait_for_next_frame();
-                if self.pattern_table_window.is_open() { self.render_pattern_table(); } // If pattern table is open - we also render it
+                self.frame_finished = true;
             }
 
-            if self.bg_rendering {
+            let pre_render_line = self.region.get_pre_render_line();
+            if self.is_rendering_enabled() {
                 let (line, dot) = self.get_line_dot();
-                if (line < 240 || line == 261) && 0 < dot && dot <= 256 {
-                    if dot.rem_euclid(8) == 0 /* last dot of 8 long slice starting from 1 */ {
-                            if line != 261 { // line -1/261 is not used
-                                self.render_current_vram_slice();
-                            }
-                            self.vram_v.increment_x();
-                            if dot == 256 {
-                                self.vram_v.increment_y();
-                            }
-                    }
+                if line < 240 && dot == 0 {
+                    self.evaluate_scanline_sprites(line);
                 }
-            }
-
-            if self.bg_rendering && self.fg_rendering {
-                let (line, dot) = self.get_line_dot();
-                if 0 < line && line < 241 && dot < 256 {
-                    let actual_line = line - 1; // sprite 0 hit logic (and oam rendering) is delayed by 1 scanline
-                    self.check_sprite_0_hit_at(dot, actual_line);
+                if line < 240 || line == pre_render_line {
+                    self.fetch_background(dot);
+                }
+                if line < 240 && 0 < dot && dot <= 256 { // pre-render line is not rendered
+                    self.render_pixel(line, dot - 1);
                 }
             }
 
-            if self.bg_rendering || self.fg_rendering {
+            if self.is_rendering_enabled() {
                 match self.get_line_dot() {
-                    (0..240 | 261, 257) => {
+                    (line, 257) if line < 240 || line == pre_render_line => {
                         // Copying horizontal vram_t to vram_v
                         self.vram_v.set_coarse_x(self.vram_t.get_coarse_x());
                         self.vram_v.set_nametable_h(self.vram_t.get_nametable_h());
@@ -226,9 +233,9 @@ impl PPU {
                 }
             }
 
-            if self.bg_rendering || self.fg_rendering {
+            if self.is_rendering_enabled() {
                 match self.get_line_dot() {
-                    (261, 280..=304) => {
+                    (line, 280..=304) if line == pre_render_line => {
                         // Copying vertical vram_t to vram_v
                         self.vram_v.set_coarse_y(self.vram_t.get_coarse_y());
                         self.vram_v.set_nametable_v(self.vram_t.get_nametable_v());
@@ -238,92 +245,151 @@ impl PPU {
                 }
             }
 
-            if self.get_line_dot() == (241, 1) {
-                self.set_vblank();
-                if self.nmi_enabled {
-                    self.call_nmi();
+            if self.get_line_dot() == (self.region.get_vblank_line(), 1) {
+                if !self.vblank_suppressed {
+                    self.vblank = true;
                 }
+                self.vblank_suppressed = false;
             }
 
-            if self.get_line_dot() == (261, 1) {
-                self.clear_vblank();
-                self.clear_sprite_0_hit();
-                self.clear_sprite_overflow();
+            if self.get_line_dot() == (pre_render_line, 1) {
+                self.vblank = false;
+                self.sprite_0_hit = false;
+                self.sprite_overflow = false;
             }
 
             self.dot += 1;
         }
     }
 
-    fn check_sprite_0_hit_at(&mut self, dot: usize, line: usize) -> () {
-        let (bg_pixel_index, _) = self.get_bg_pixel_at(dot, line);
-        match bg_pixel_index {
-            PixelPaletteColorIndex::Background => return,
-            PixelPaletteColorIndex::Color1 => (),
-            PixelPaletteColorIndex::Color2 => (),
-            PixelPaletteColorIndex::Color3 => (),
-        };
-
-        let sprite_pixel = self.get_sprite_0_color_at(dot, line);
-        match sprite_pixel {
-            PixelPaletteColorIndex::Background => (),
-            _ => {
-                self.set_sprite_0_hit();
-            }
-        }
+    fn is_escape_down(&self) -> bool {
+        [&self.main_window, &self.pattern_table_window].into_iter().flatten().any(|window| window.is_key_down(Key::Escape))
+    }
+
+    fn is_rendering_enabled(&self) -> bool {
+        self.bg_rendering || self.fg_rendering
     }
 
-    fn get_sprite_0_color_at(&self, x: usize, y: usize) -> PixelPaletteColorIndex {
-        let sprite_x = self.oam_data[3] as usize;
-        let sprite_y = self.oam_data[0] as usize;
-        if x < sprite_x || x > (sprite_x+7) { return PixelPaletteColorIndex::Background };
-        if y < sprite_y || y > (sprite_y+7) { return PixelPaletteColorIndex::Background };
-        let tile_id = self.oam_data[1];
-        let reverse_h = self.oam_data[2] & 0b_0100_0000 != 0;
-        let reverse_v = self.oam_data[2] & 0b_1000_0000 != 0;
-        let tile = tile::Tile::get(&self.ppu_memory, tile_id as usize, self.fg_plane, reverse_h, reverse_v);
-        return tile.data[(x-sprite_x) + ((y-sprite_y)*8)];
+    // NMI line is low while both vblank flag and NMI enable are set
+    pub fn get_nmi_output(&self) -> bool {
+        self.nmi_enabled && self.vblank
     }
 
-    fn call_nmi(&self) {
-        unsafe{(*self.cpu_pointer.0).nmi(&mut *self.memory_pointer.0);};
+    // Set once frame is displayed, so main loop can do per-frame work
+    pub fn take_frame_finished(&mut self) -> bool {
+        std::mem::take(&mut self.frame_finished)
     }
 
-    fn set_vblank(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] |= 0b_1000_0000};
+    pub fn get_held_keys(&self) -> Vec<Key> {
+        self.main_window.as_ref().map(|window| window.get_keys()).unwrap_or_default()
     }
 
-    fn clear_vblank(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] &= 0b_0111_1111};
+    // Mouse position is in NES pixels
+    pub fn get_mouse_state(&self) -> (Option<(f32, f32)>, bool) {
+        match &self.main_window {
+            Some(window) => (window.get_mouse_pos(MouseMode::Discard), window.get_mouse_down(MouseButton::Left)),
+            None => (None, false),
+        }
     }
 
-    fn set_sprite_0_hit(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] |= 0b_0100_0000};
+    pub fn get_framebuffer(&self) -> &Vec<u32> {
+        &self.main_framebuffer
     }
 
-    fn clear_sprite_0_hit(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] &= 0b_1011_1111};
+    // (line, dot) that will be drawn next
+    pub fn get_beam_position(&self) -> (usize, usize) {
+        self.get_line_dot()
     }
 
-    fn set_sprite_overflow(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] |= 0b_0010_0000};
+    // Keeps window responsive when emulation isn't running
+    pub fn refresh_window(&mut self) {
+        if let Some(window) = &mut self.main_window {
+            window.update();
+        }
+        if self.is_escape_down() { self.is_closed = true; exit(0); };
     }
 
-    fn clear_sprite_overflow(&self) {
-        unsafe{(&mut *self.memory_pointer.0).data[0x2002] &= 0b_1101_1111};
+    pub fn set_title(&mut self, title: &str) {
+        if let Some(window) = &mut self.main_window {
+            window.set_title(title);
+        }
     }
 
-    fn get_controller_state(&self) -> u8 {
-        let mut value = 0x00;
-        if self.main_window.is_key_down(Key::Right) { value += 0b_1000_0000 } // DPAD
-        if self.main_window.is_key_down(Key::Left) { value += 0b_0100_0000 }
-        if self.main_window.is_key_down(Key::Down) { value += 0b_0010_0000 }
-        if self.main_window.is_key_down(Key::Up) { value += 0b_0001_0000 }
-
-        if self.main_window.is_key_down(Key::V) { value += 0b_0000_1000 } // Start
-        if self.main_window.is_key_down(Key::C) { value += 0b_0000_0100 } // Select
-        if self.main_window.is_key_down(Key::X) { value += 0b_0000_0010 } // B
-        if self.main_window.is_key_down(Key::Z) { value += 0b_0000_0001 } // A
-        return value;
+    // Frames replayed to catch up after rewind aren't shown
+    pub fn set_display_enabled(&mut self, enabled: bool) {
+        self.display_enabled = enabled;
+    }
+}
+
+// Windows, framebuffers and frame pacing aren't part of emulated state, picture is redrawn on the next frame
+impl Snapshot for PPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u64(self.dot);
+        state.write_bool(self.odd_frame);
+        state.write_bool(self.nmi_enabled);
+        state.write_bool(self.bg_plane);
+        state.write_bool(self.ppudata_write_down);
+        state.write_bool(self.ppu_addr_high_byte);
+        state.write_u16(self.vram_v.get_all());
+        state.write_u16(self.vram_t.get_all());
+        state.write_u8(self.fine_x);
+        state.write_bytes(&self.oam_data);
+        state.write_u16(self.oam_addr as u16);
+        state.write_bool(self.fg_plane);
+        state.write_bool(self.fg_rendering);
+        state.write_bool(self.bg_rendering);
+        state.write_bool(self.fg_left_rendering);
+        state.write_bool(self.bg_left_rendering);
+        state.write_bool(self.color_mode.greyscale);
+        state.write_u8(self.color_mode.emphasis);
+        state.write_u8(self.ppudata_read_buffer);
+        state.write_u8(self.io_latch);
+        state.write_u8(self.io_latch_age);
+        state.write_bool(self.vblank_suppressed);
+        self.bg_next_tile.save_state(state);
+        self.bg_shifters.save_state(state);
+        state.write_u8(self.scanline_sprites.len() as u8);
+        for sprite in &self.scanline_sprites {
+            state.write_u8(*sprite as u8);
+        }
+        state.write_bool(self.vblank);
+        state.write_bool(self.sprite_0_hit);
+        state.write_bool(self.sprite_overflow);
+        self.ppu_memory.save_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.dot = state.read_u64()?;
+        self.odd_frame = state.read_bool()?;
+        self.nmi_enabled = state.read_bool()?;
+        self.bg_plane = state.read_bool()?;
+        self.ppudata_write_down = state.read_bool()?;
+        self.ppu_addr_high_byte = state.read_bool()?;
+        self.vram_v.set_all(state.read_u16()?);
+        self.vram_t.set_all(state.read_u16()?);
+        self.fine_x = state.read_u8()?;
+        state.read_bytes(&mut self.oam_data)?;
+        self.oam_addr = state.read_u16()? as usize;
+        self.fg_plane = state.read_bool()?;
+        self.fg_rendering = state.read_bool()?;
+        self.bg_rendering = state.read_bool()?;
+        self.fg_left_rendering = state.read_bool()?;
+        self.bg_left_rendering = state.read_bool()?;
+        self.color_mode.greyscale = state.read_bool()?;
+        self.color_mode.emphasis = state.read_u8()?;
+        self.ppudata_read_buffer = state.read_u8()?;
+        self.io_latch = state.read_u8()?;
+        self.io_latch_age = state.read_u8()?;
+        self.vblank_suppressed = state.read_bool()?;
+        self.bg_next_tile.load_state(state)?;
+        self.bg_shifters.load_state(state)?;
+        self.scanline_sprites.clear();
+        for _ in 0..state.read_u8()? {
+            self.scanline_sprites.push(state.read_u8()? as usize);
+        }
+        self.vblank = state.read_bool()?;
+        self.sprite_0_hit = state.read_bool()?;
+        self.sprite_overflow = state.read_bool()?;
+        self.ppu_memory.load_state(state)
     }
 }
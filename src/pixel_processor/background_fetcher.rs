@@ -0,0 +1,155 @@
+use crate::snapshot::{ Snapshot, StateReader, StateWriter };
+
+use super::{ tile::PixelPaletteColorIndex, PPU };
+
+// Data fetched during one 8 dot long tile fetch
+#[derive(Clone, Copy, Default)]
+pub struct BackgroundTileData {
+    pub tile_id: u8,
+    pub attribute: u8, // 2 bit palette id
+    pub pattern_lsb: u8,
+    pub pattern_msb: u8,
+}
+
+// Two 16 bit pattern shifters and two attribute shifters, upper byte is current tile and lower is the next one
+#[derive(Clone, Copy, Default)]
+pub struct BackgroundShifters {
+    pattern_lsb: u16,
+    pattern_msb: u16,
+    attribute_lsb: u16,
+    attribute_msb: u16,
+}
+
+impl BackgroundShifters {
+    pub fn load(&mut self, tile: BackgroundTileData) {
+        self.pattern_lsb = (self.pattern_lsb & 0xFF00) | tile.pattern_lsb as u16;
+        self.pattern_msb = (self.pattern_msb & 0xFF00) | tile.pattern_msb as u16;
+        self.attribute_lsb = (self.attribute_lsb & 0xFF00) | if tile.attribute & 0b_01 != 0 { 0xFF } else { 0x00 };
+        self.attribute_msb = (self.attribute_msb & 0xFF00) | if tile.attribute & 0b_10 != 0 { 0xFF } else { 0x00 };
+    }
+
+    pub fn shift(&mut self) {
+        self.pattern_lsb <<= 1;
+        self.pattern_msb <<= 1;
+        self.attribute_lsb <<= 1;
+        self.attribute_msb <<= 1;
+    }
+
+    // Returns pixel color index and palette id
+    pub fn get_pixel(&self, fine_x: u8) -> (PixelPaletteColorIndex, usize) {
+        let bit_mux = 0x8000 >> (fine_x & 0b_0000_0111);
+        let pixel_index = match (self.pattern_msb & bit_mux != 0, self.pattern_lsb & bit_mux != 0) {
+            (false, false) => PixelPaletteColorIndex::Background,
+            (false,  true) => PixelPaletteColorIndex::Color1,
+            ( true, false) => PixelPaletteColorIndex::Color2,
+            ( true,  true) => PixelPaletteColorIndex::Color3,
+        };
+        let palette_id = ((self.attribute_msb & bit_mux != 0) as usize) << 1 | (self.attribute_lsb & bit_mux != 0) as usize;
+        (pixel_index, palette_id)
+    }
+}
+
+impl Snapshot for BackgroundTileData {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.tile_id);
+        state.write_u8(self.attribute);
+        state.write_u8(self.pattern_lsb);
+        state.write_u8(self.pattern_msb);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.tile_id = state.read_u8()?;
+        self.attribute = state.read_u8()?;
+        self.pattern_lsb = state.read_u8()?;
+        self.pattern_msb = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Snapshot for BackgroundShifters {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.pattern_lsb);
+        state.write_u16(self.pattern_msb);
+        state.write_u16(self.attribute_lsb);
+        state.write_u16(self.attribute_msb);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.pattern_lsb = state.read_u16()?;
+        self.pattern_msb = state.read_u16()?;
+        self.attribute_lsb = state.read_u16()?;
+        self.attribute_msb = state.read_u16()?;
+        Ok(())
+    }
+}
+
+impl PPU {
+    // Should be called every dot of visible and pre-render scanlines while rendering is enabled
+    pub(super) fn fetch_background(&mut self, dot: usize) {
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.bg_shifters.shift();
+        }
+
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.bg_shifters.load(self.bg_next_tile);
+                    let tile_address = 0x2000 | (self.vram_v.get_all() as usize & 0x0FFF);
+                    self.bg_next_tile.tile_id = self.ppu_memory.read(tile_address, 1) as u8;
+                },
+                2 => {
+                    let vram_v = self.vram_v.get_all() as usize;
+                    let attribute_address = 0x23C0 | (vram_v & 0x0C00) | ((vram_v >> 4) & 0x38) | ((vram_v >> 2) & 0x07);
+                    let mut attribute = self.ppu_memory.read(attribute_address, 1) as u8;
+                    if self.vram_v.get_coarse_y() & 0b_10 != 0 { attribute >>= 4 };
+                    if self.vram_v.get_coarse_x() & 0b_10 != 0 { attribute >>= 2 };
+                    self.bg_next_tile.attribute = attribute & 0b_11;
+                },
+                4 => {
+                    let strip_address = self.get_bg_strip_address();
+                    self.bg_next_tile.pattern_lsb = self.ppu_memory.read(strip_address, 1) as u8;
+                },
+                6 => {
+                    let strip_address = self.get_bg_strip_address();
+                    self.bg_next_tile.pattern_msb = self.ppu_memory.read(strip_address + 0b_1000, 1) as u8;
+                },
+                7 => self.vram_v.increment_x(),
+                _ => (),
+            }
+        }
+
+        if dot == 256 {
+            self.vram_v.increment_y();
+        }
+    }
+
+    fn get_bg_strip_address(&self) -> usize {
+        (if self.bg_plane {0b_0001_0000_0000_0000} else {0}) +
+        ((self.bg_next_tile.tile_id as usize) << 4) +
+        self.vram_v.get_fine_y() as usize
+    }
+}
+
+#[cfg(test)]
+mod background_shifters_tests {
+    use super::*;
+
+    #[test]
+    fn test_pixel_order() {
+        let mut shifters = BackgroundShifters::default();
+        shifters.load(BackgroundTileData { tile_id: 0, attribute: 0b_10, pattern_lsb: 0b_1010_0000, pattern_msb: 0b_0110_0000 });
+        for _ in 0..8 { shifters.shift() };
+        shifters.load(BackgroundTileData { tile_id: 0, attribute: 0b_01, pattern_lsb: 0b_1000_0000, pattern_msb: 0b_0000_0000 });
+
+        assert_eq!(shifters.get_pixel(0), (PixelPaletteColorIndex::Color1, 0b_10));
+        assert_eq!(shifters.get_pixel(1), (PixelPaletteColorIndex::Color2, 0b_10));
+        assert_eq!(shifters.get_pixel(2), (PixelPaletteColorIndex::Color3, 0b_10));
+        assert_eq!(shifters.get_pixel(3), (PixelPaletteColorIndex::Background, 0b_10));
+
+        for _ in 0..7 { shifters.shift() };
+        assert_eq!(shifters.get_pixel(0), (PixelPaletteColorIndex::Background, 0b_10));
+        assert_eq!(shifters.get_pixel(1), (PixelPaletteColorIndex::Color1, 0b_01)); // fine x reaches into next tile
+        shifters.shift();
+        assert_eq!(shifters.get_pixel(0), (PixelPaletteColorIndex::Color1, 0b_01));
+    }
+}
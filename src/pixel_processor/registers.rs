@@ -0,0 +1,144 @@
+use super::{ tile::ColorMode, MemoryHandler, PPU };
+
+const IO_LATCH_DECAY_FRAMES: u8 = 36;
+
+// CPU accesses to PPU registers are handled right when they happen
+impl MemoryHandler for PPU {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = match address {
+            0x2002 => { // PPUSTATUS
+                let (line, dot) = self.get_line_dot();
+                if line == self.region.get_vblank_line() && dot <= 1 {
+                    // Reading right before vblank is set returns it cleared and suppresses it (and NMI) for whole frame
+                    self.vblank_suppressed = true;
+                }
+                let status = self.get_status() | (self.io_latch & 0b_0001_1111);
+                self.vblank = false;
+                self.ppu_addr_high_byte = true;
+                status
+            },
+            0x2004 => self.oam_data[self.oam_addr], // OAMDATA
+            0x2007 => { // PPUDATA
+                let value = self.get_ppudata_read_value();
+                let vram_address = (self.vram_v.get_all() & 0x3FFF) as usize;
+                // palette reads are returned right away, but buffer is still filled with nametable data "under" the palette
+                let buffered_address = if vram_address >= 0x3F00 { vram_address - 0x1000 } else { vram_address };
+                self.ppudata_read_buffer = self.ppu_memory.read(buffered_address, 1) as u8;
+                self.increment_vram_address();
+                value
+            },
+            _ => self.io_latch, // reading write only registers returns latch without refreshing it
+        };
+        let refreshed_bits = match address {
+            0x2002 => 0b_1110_0000,
+            0x2004 | 0x2007 => 0b_1111_1111,
+            _ => 0b_0000_0000,
+        };
+        self.refresh_io_latch(value, refreshed_bits);
+        return value;
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x2000 => { // PPUCTRL
+                self.nmi_enabled = value & 0b_1000_0000 != 0;
+                // let ppu_master = value & 0b_0100_0000 != 0;
+                // let two_high_sprites = value & 0b_0010_0000 != 0;
+                self.bg_plane = value & 0b_0001_0000 != 0;
+                self.fg_plane = value & 0b_0000_1000 != 0;
+                self.ppudata_write_down = value & 0b_0000_0100 != 0;
+                self.vram_t.set_nametable_v((value & 0b_0000_0010) != 0);
+                self.vram_t.set_nametable_h((value & 0b_0000_0001) != 0);
+            },
+            0x2001 => { // PPUMASK
+                self.color_mode = ColorMode::from_ppumask(value, self.region); // emphasis and greyscale
+                self.fg_rendering = value & 0b_0001_0000 != 0;
+                self.bg_rendering = value & 0b_0000_1000 != 0;
+                self.fg_left_rendering = value & 0b_0000_0100 != 0;
+                self.bg_left_rendering = value & 0b_0000_0010 != 0;
+            },
+            0x2003 => { // OAMADDR
+                self.oam_addr = value as usize;
+            },
+            0x2004 => { // OAMDATA
+                self.oam_data[self.oam_addr] = value;
+                self.oam_addr = (self.oam_addr + 1) & 0xFF;
+            },
+            0x2005 => { // PPUSCROLL
+                if self.ppu_addr_high_byte {
+                    self.vram_t.set_coarse_x((value & 0b_1111_1000) >> 3);
+                    self.fine_x = value & 0b_0000_0111;
+                } else {
+                    self.vram_t.set_coarse_y((value & 0b_1111_1000) >> 3);
+                    self.vram_t.set_fine_y(value & 0b_0000_0111);
+                };
+                self.ppu_addr_high_byte = !self.ppu_addr_high_byte;
+            },
+            0x2006 => { // PPUADDR
+                if self.ppu_addr_high_byte {
+                    let mut new_vram_t = self.vram_t.get_all();
+                    new_vram_t &= 0b_0000_0000_1111_1111;
+                    new_vram_t += ((value & 0b_0011_1111) as u16) << 8;
+                    self.vram_t.set_all(new_vram_t);
+                } else {
+                    let mut new_vram_t = self.vram_t.get_all();
+                    new_vram_t &= 0b_0111_1111_0000_0000;
+                    new_vram_t += value as u16;
+                    self.vram_t.set_all(new_vram_t);
+                    self.vram_v = self.vram_t;
+                };
+                self.ppu_addr_high_byte = !self.ppu_addr_high_byte;
+            },
+            0x2007 => { // PPUDATA
+                self.ppu_memory.write((self.vram_v.get_all() & 0x3FFF) as usize, value);
+                self.increment_vram_address();
+            },
+            _ => (),
+        }
+        self.refresh_io_latch(value, 0b_1111_1111); // any write fills the whole latch
+    }
+}
+
+impl PPU {
+    fn refresh_io_latch(&mut self, value: u8, refreshed_bits: u8) {
+        if refreshed_bits != 0 {
+            self.io_latch = (self.io_latch & !refreshed_bits) | (value & refreshed_bits);
+            self.io_latch_age = 0;
+        }
+    }
+
+    pub(super) fn decay_io_latch(&mut self) {
+        // Latch decays in ~600ms if it isn't refreshed
+        if self.io_latch_age < IO_LATCH_DECAY_FRAMES {
+            self.io_latch_age += 1;
+            if self.io_latch_age == IO_LATCH_DECAY_FRAMES {
+                self.io_latch = 0;
+            }
+        }
+    }
+
+    fn get_status(&self) -> u8 {
+        (if self.vblank { 0b_1000_0000 } else { 0 }) |
+        (if self.sprite_0_hit { 0b_0100_0000 } else { 0 }) |
+        (if self.sprite_overflow { 0b_0010_0000 } else { 0 })
+    }
+
+    fn get_ppudata_read_value(&self) -> u8 {
+        let vram_address = (self.vram_v.get_all() & 0x3FFF) as usize;
+        if vram_address >= 0x3F00 {
+            // palette reads are returned right away, that doesn't depend on region
+            // palette is only 6 bits wide, top bits come from open bus
+            (self.ppu_memory.read(vram_address, 1) as u8 & 0b_0011_1111) | (self.io_latch & 0b_1100_0000)
+        } else {
+            self.ppudata_read_buffer
+        }
+    }
+
+    fn increment_vram_address(&mut self) {
+        if self.ppudata_write_down {
+            self.vram_v.set_all(self.vram_v.get_all() + 32);
+        } else {
+            self.vram_v.set_all(self.vram_v.get_all() + 1);
+        }
+    }
+}
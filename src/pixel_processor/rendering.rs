@@ -1,5 +1,3 @@
-use std::time::Instant;
-
 use minifb::{ Window, WindowOptions };
 
 use crate::pixel_processor::tile::{PixelPalette, PixelPaletteColorIndex};
@@ -7,85 +5,116 @@ use crate::pixel_processor::helper::get_actual_nametable_addr_and_tile_offset;
 
 use super::{ helper::overlay_sprite, tile::{self, Tile}, PPU };
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpritePixel {
+    pub color_index: PixelPaletteColorIndex,
+    pub palette_id: usize,
+    pub behind_background: bool,
+    pub is_sprite_0: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompositedPixel {
+    Backdrop,
+    Background,
+    Sprite(SpritePixel),
+}
+
+// Both pixels should already be masked, i.e. hidden layers and clipped left column are passed as transparent
+pub fn composite_pixel(bg_pixel: PixelPaletteColorIndex, sprite_pixel: Option<SpritePixel>) -> CompositedPixel {
+    let bg_opaque = !matches!(bg_pixel, PixelPaletteColorIndex::Background);
+    match sprite_pixel {
+        Some(sprite) if !matches!(sprite.color_index, PixelPaletteColorIndex::Background) => {
+            if sprite.behind_background && bg_opaque {
+                CompositedPixel::Background
+            } else {
+                CompositedPixel::Sprite(sprite)
+            }
+        },
+        _ if bg_opaque => CompositedPixel::Background,
+        _ => CompositedPixel::Backdrop,
+    }
+}
+
+// Sprite 0 hit ignores priority, but never happens at x=255
+pub fn is_sprite_0_hit(x: usize, bg_pixel: PixelPaletteColorIndex, sprite_pixel: Option<SpritePixel>) -> bool {
+    if x == 255 { return false };
+    let bg_opaque = !matches!(bg_pixel, PixelPaletteColorIndex::Background);
+    match sprite_pixel {
+        Some(sprite) => sprite.is_sprite_0 && bg_opaque && !matches!(sprite.color_index, PixelPaletteColorIndex::Background),
+        None => false,
+    }
+}
+
 impl PPU {
     pub(super) fn display_frame(&mut self) {
         // For now there's only minifb rendering
         // TODO: implement ImGUI rendering
-        self.main_window
-            .update_with_buffer(&self.main_framebuffer, 256, 240)
-            .unwrap();
+        if let Some(window) = &mut self.main_window {
+            window.update_with_buffer(&self.main_framebuffer, 256, 240).unwrap();
+        }
     }
 
-    pub(super) fn render_oam(&mut self) {
+    pub(super) fn evaluate_scanline_sprites(&mut self, line: usize) {
+        self.scanline_sprites.clear();
         for oam_sprite_id in 0..64 {
-            let oam_sprite_x = self.oam_data[oam_sprite_id*4+3] as usize;
-            let oam_sprite_y = self.oam_data[oam_sprite_id*4+0] as usize;
-            // let oam_tile_plane = oam_data[oam_sprite_id*4+1] & 0x01 == 0; // only in 8x16
-            // let oam_tile_id = oam_data[oam_sprite_id*4+1] >> 1;
-            let oam_tile_id = self.oam_data[oam_sprite_id*4+1];
-            let reverse_h = self.oam_data[oam_sprite_id*4+2] & 0b_0100_0000 != 0;
-            let reverse_v = self.oam_data[oam_sprite_id*4+2] & 0b_1000_0000 != 0;
-            let oam_palette_id = 4 + (self.oam_data[oam_sprite_id*4+2] & 0b_0000_0011) as usize;
-            let palette = PixelPalette::get_by_id(&self.ppu_memory, oam_palette_id);
-            let tile = Tile::get(&self.ppu_memory, oam_tile_id as usize, self.fg_plane, reverse_h, reverse_v);
-            for y in 0..8 {     // assume sprite is always 8x8
-                let y_offset = oam_sprite_y+y+1;
-                if y_offset < 240 {
-                    for x in 0..8 {
-                        let x_offset = oam_sprite_x+x;
-                        if x_offset < 256 {
-                            let screen_offset = y_offset*256 + x_offset;
-                            match tile.data[x + y*8] {
-                                tile::PixelPaletteColorIndex::Background => (),
-                                tile::PixelPaletteColorIndex::Color1 => { self.main_framebuffer[screen_offset] = palette.color1 }
-                                tile::PixelPaletteColorIndex::Color2 => { self.main_framebuffer[screen_offset] = palette.color2 }
-                                tile::PixelPaletteColorIndex::Color3 => { self.main_framebuffer[screen_offset] = palette.color3 }
-                            }
-                        }
-                    }
-                }
+            let oam_sprite_y = self.oam_data[oam_sprite_id*4] as usize;
+            // sprites are delayed by 1 scanline
+            if oam_sprite_y < line && line <= oam_sprite_y + 8 { // assume sprite is always 8x8
+                self.scanline_sprites.push(oam_sprite_id);
             }
         }
     }
 
-    pub(super) fn render_current_vram_slice(&mut self) {
-        let nametable_h_changed = self.vram_v.get_nametable_h() != self.vram_t.get_nametable_h();
-        let current_slice = (self.vram_v.get_coarse_x() as usize) + { if nametable_h_changed { 1 << 5 } else { 0 } };
-        let starting_slice = self.vram_t.get_coarse_x() as usize;
-        let fine_y = self.vram_v.get_fine_y() as usize;
-        for i in 0..8 {
-            let x = ((current_slice - starting_slice) * 8 + i) % 256;
-            let (pixel_index, color_palette) = self.get_bg_pixel_at(i, fine_y);
-
-            let (draw_y, _) = self.get_line_dot();
-            self.main_framebuffer[x + draw_y*256] = match pixel_index {
-                PixelPaletteColorIndex::Background => color_palette.background,
-                PixelPaletteColorIndex::Color1 => color_palette.color1,
-                PixelPaletteColorIndex::Color2 => color_palette.color2,
-                PixelPaletteColorIndex::Color3 => color_palette.color3,
-            };
+    pub(super) fn get_sprite_pixel_at(&self, x: usize, line: usize) -> Option<SpritePixel> {
+        for &oam_sprite_id in &self.scanline_sprites {
+            let oam_sprite_x = self.oam_data[oam_sprite_id*4+3] as usize;
+            if x < oam_sprite_x || x > oam_sprite_x + 7 { continue };
+            let oam_sprite_y = self.oam_data[oam_sprite_id*4] as usize;
+            let oam_tile_id = self.oam_data[oam_sprite_id*4+1];
+            let attributes = self.oam_data[oam_sprite_id*4+2];
+            let reverse_h = attributes & 0b_0100_0000 != 0;
+            let reverse_v = attributes & 0b_1000_0000 != 0;
+            let color_index = Tile::get_at(&self.ppu_memory, x - oam_sprite_x, line - oam_sprite_y - 1, oam_tile_id as usize, self.fg_plane, reverse_h, reverse_v);
+            match color_index {
+                PixelPaletteColorIndex::Background => continue, // transparent pixels let lower priority sprites through
+                _ => return Some(SpritePixel {
+                    color_index,
+                    palette_id: 4 + (attributes & 0b_0000_0011) as usize,
+                    behind_background: attributes & 0b_0010_0000 != 0,
+                    is_sprite_0: oam_sprite_id == 0,
+                }),
+            }
         }
+        None
     }
 
-    pub(super) fn get_bg_pixel_at(&self, fine_x: usize, fine_y: usize) -> (PixelPaletteColorIndex, PixelPalette) {
-        let fine_dot = fine_x & 0b_0000_0111;
-        let fine_line = fine_y & 0b_0000_0111;
+    pub(super) fn render_pixel(&mut self, line: usize, x: usize) {
+        let bg_visible = self.bg_rendering && (x >= 8 || self.bg_left_rendering);
+        let fg_visible = self.fg_rendering && (x >= 8 || self.fg_left_rendering);
+        let (bg_pixel, bg_palette_id) = if bg_visible {
+            self.bg_shifters.get_pixel(self.fine_x)
+        } else {
+            (PixelPaletteColorIndex::Background, 0)
+        };
+        let sprite_pixel = if fg_visible { self.get_sprite_pixel_at(x, line) } else { None };
 
-        let mut incremented_vram = self.vram_v;
-        let should_fetch_next = (self.fine_x as usize) + fine_dot > 7;
-        if should_fetch_next { // because coarse x and nametable x are noncontiguous
-            incremented_vram.increment_x();
+        if is_sprite_0_hit(x, bg_pixel, sprite_pixel) {
+            self.sprite_0_hit = true;
         }
 
-        let tile_address = 0x2000 + ((incremented_vram.get_all() as usize) & 0x0FFF);
-        let tile_pattern_id = self.ppu_memory.read(tile_address, 1);
-        let pixel_index = Tile::get_at(&self.ppu_memory, (fine_dot + (self.fine_x as usize)) & 0b_0000_0111, fine_line, tile_pattern_id, self.bg_plane, false, false);
-
-        let palette_base = tile_address & 0b_1111_11_00000_00000;
-        let palette_offset = tile_address & 0b_0000_00_11111_11111;
-        let color_palette = PixelPalette::get_from_addr_and_offset(&self.ppu_memory, palette_base, palette_offset);
-
-        return (pixel_index, color_palette);
+        let (color_index, palette_id) = match composite_pixel(bg_pixel, sprite_pixel) {
+            CompositedPixel::Backdrop => (PixelPaletteColorIndex::Background, 0),
+            CompositedPixel::Background => (bg_pixel, bg_palette_id),
+            CompositedPixel::Sprite(sprite) => (sprite.color_index, sprite.palette_id),
+        };
+        let palette = PixelPalette::get_by_id(&self.ppu_memory, palette_id, self.color_mode);
+        self.main_framebuffer[x + line*256] = match color_index {
+            PixelPaletteColorIndex::Background => palette.background, // always mirrors universal background color
+            PixelPaletteColorIndex::Color1 => palette.color1,
+            PixelPaletteColorIndex::Color2 => palette.color2,
+            PixelPaletteColorIndex::Color3 => palette.color3,
+        };
     }
 
     pub(super) fn render_pattern_table(&mut self) {
@@ -98,7 +127,9 @@ impl PPU {
                 }
             }
         }
-        self.pattern_table_window.update_with_buffer(&self.pattern_table_framebuffer, 256, 128).unwrap();
+        if let Some(window) = &mut self.pattern_table_window {
+            window.update_with_buffer(&self.pattern_table_framebuffer, 256, 128).unwrap();
+        }
     }
 
     pub(super) fn create_main_window() -> Window {
@@ -140,13 +171,46 @@ impl PPU {
         ).unwrap();
         return pattern_table_window;
     }
+}
 
-    pub(super) fn wait_for_next_frame(&mut self) {
-        loop { // calling sleep() is not guaranteed to sleep exactly specified time, only AT LEAST specified time or more
-            if self.frame_start.elapsed().as_nanos() > 16_666_666 {
-                self.frame_start = Instant::now();
-                break;
-            }
-        }
+#[cfg(test)]
+mod compositing_tests {
+    use super::*;
+
+    fn sprite(color_index: PixelPaletteColorIndex, behind_background: bool, is_sprite_0: bool) -> SpritePixel {
+        SpritePixel { color_index, palette_id: 4, behind_background, is_sprite_0 }
+    }
+
+    #[test]
+    fn transparent_pixels_show_backdrop() {
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Background, None), CompositedPixel::Backdrop);
+        let transparent_sprite = sprite(PixelPaletteColorIndex::Background, false, false);
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Background, Some(transparent_sprite)), CompositedPixel::Backdrop);
+    }
+
+    #[test]
+    fn front_sprite_over_background() {
+        let front_sprite = sprite(PixelPaletteColorIndex::Color1, false, false);
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Color2, Some(front_sprite)), CompositedPixel::Sprite(front_sprite));
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Background, Some(front_sprite)), CompositedPixel::Sprite(front_sprite));
+    }
+
+    #[test]
+    fn back_sprite_behind_background() {
+        let back_sprite = sprite(PixelPaletteColorIndex::Color3, true, false);
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Color2, Some(back_sprite)), CompositedPixel::Background);
+        assert_eq!(composite_pixel(PixelPaletteColorIndex::Background, Some(back_sprite)), CompositedPixel::Sprite(back_sprite));
+    }
+
+    #[test]
+    fn sprite_0_hit() {
+        let sprite_0 = sprite(PixelPaletteColorIndex::Color1, true, true);
+        assert!(is_sprite_0_hit(0, PixelPaletteColorIndex::Color1, Some(sprite_0)));
+        assert!(is_sprite_0_hit(254, PixelPaletteColorIndex::Color3, Some(sprite_0)));
+        assert!(!is_sprite_0_hit(255, PixelPaletteColorIndex::Color1, Some(sprite_0)));
+        assert!(!is_sprite_0_hit(100, PixelPaletteColorIndex::Background, Some(sprite_0)));
+        assert!(!is_sprite_0_hit(100, PixelPaletteColorIndex::Color1, None));
+        let other_sprite = sprite(PixelPaletteColorIndex::Color1, false, false);
+        assert!(!is_sprite_0_hit(100, PixelPaletteColorIndex::Color1, Some(other_sprite)));
     }
 }
@@ -1,8 +1,11 @@
+use std::sync::OnceLock;
+
 use crate::pixel_processor::helper::reverse_bits;
+use crate::region::Region;
 
 use super::PPU_MEM;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PixelPaletteColorIndex {
     Background,
     Color1,
@@ -19,39 +22,13 @@ pub struct PixelPalette {
 }
 
 impl PixelPalette {
-    pub fn get_by_id(ppu_memory: &PPU_MEM, palette_id: usize) -> Self {
+    pub fn get_by_id(ppu_memory: &PPU_MEM, palette_id: usize, color_mode: ColorMode) -> Self {
         let palette_addr = ppu_memory.read(0x3F00 + palette_id*4, 4);
         return Self {
-            background: get_color(palette_addr as u8),
-            color1: get_color((palette_addr>>8) as u8),
-            color2: get_color((palette_addr>>16) as u8),
-            color3: get_color((palette_addr>>24) as u8),
-        };
-    }
-
-    pub fn get_from_addr_and_offset(ppu_memory: &PPU_MEM, nametable_address: usize, tile_offset: usize) -> Self {
-        let tile_attribute_x = (tile_offset & 0b_0000_0010) >> 1;
-        let tile_attribute_y = (tile_offset & 0b_0100_0000) >> 6;
-
-        let attribute_byte_x_part = (tile_offset & 0b_0000_0001_1100) >> 2;
-        let attribute_byte_y_part = (tile_offset & 0b_0011_1000_0000) >> 4;
-        let attribute_byte_offset = attribute_byte_x_part + attribute_byte_y_part;
-
-        let attribute_byte = ppu_memory.read(nametable_address + 0x3C0 + attribute_byte_offset, 1);
-
-        let palette_index = match (tile_attribute_x, tile_attribute_y) {
-            (0, 0) => attribute_byte & 0b_0000_0011,
-            (1, 0) => (attribute_byte & 0b_0000_1100) >> 2,
-            (0, 1) => (attribute_byte & 0b_0011_0000) >> 4,
-            (1, 1) => (attribute_byte & 0b_1100_0000) >> 6,
-            _ => panic!()
-        };
-        let palette = ppu_memory.read(0x3F00 + palette_index*4, 4);
-        return Self {
-            background: get_color(ppu_memory.read(0x3F00, 1) as u8),
-            color1: get_color((palette>>8) as u8),
-            color2: get_color((palette>>16) as u8),
-            color3: get_color((palette>>24) as u8),
+            background: get_color(palette_addr as u8, color_mode),
+            color1: get_color((palette_addr>>8) as u8, color_mode),
+            color2: get_color((palette_addr>>16) as u8, color_mode),
+            color3: get_color((palette_addr>>24) as u8, color_mode),
         };
     }
 
@@ -65,15 +42,83 @@ impl PixelPalette {
     }
 }
 
-fn get_color(index: u8) -> u32 {
-    if index >= 64 {panic!()}
-    let color = [ // TODO: allow to change it/set it with .pal files. Currently it is recommended palette from https://www.nesdev.org/wiki/PPU_palettes
-        0xFF626262, 0xFF002E98, 0xFF0C11C2, 0xFF3B00C2, 0xFF650098, 0xFF7D004E, 0xFF7D0000, 0xFF651900, 0xFF3B3600, 0xFF0C4F00, 0xFF005B00, 0xFF005900, 0xFF00494E, 0xFF000000, 0xFF000000, 0xFF000000,
-        0xFFABABAB, 0xFF0064F4, 0xFF353CFF, 0xFF761BFF, 0xFFAE0AF4, 0xFFCF0C8F, 0xFFCF231C, 0xFFAE4700, 0xFF766F00, 0xFF359000, 0xFF00A100, 0xFF009E1C, 0xFF00888F, 0xFF000000, 0xFF000000, 0xFF000000,
-        0xFFFFFFFF, 0xFF4AB5FF, 0xFF858CFF, 0xFFC86AFF, 0xFFFF58FF, 0xFFFF5BE2, 0xFFFF726A, 0xFFFF9702, 0xFFC8C100, 0xFF85E300, 0xFF4AF502, 0xFF29F26A, 0xFF29DBE2, 0xFF4E4E4E, 0xFF000000, 0xFF000000,
-        0xFFFFFFFF, 0xFFB6E1FF, 0xFFCED1FF, 0xFFE9C3FF, 0xFFFFBCFF, 0xFFFFBDF4, 0xFFFFC6C3, 0xFFFFD59A, 0xFFE9E681, 0xFFCEF481, 0xFFB6FB9A, 0xFFA9FAC3, 0xFFA9F0F4, 0xFFB8B8B8, 0xFF000000, 0xFF000000
-    ];
-    return color[index as usize];
+// PPUMASK bits that affect final pixel color
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ColorMode {
+    pub greyscale: bool,
+    pub emphasis: u8, // bit 0 - red, bit 1 - green, bit 2 - blue
+}
+
+impl ColorMode {
+    pub fn from_ppumask(value: u8, region: Region) -> Self {
+        let emphasis = (value & 0b_1110_0000) >> 5;
+        Self {
+            greyscale: value & 0b_0000_0001 != 0,
+            emphasis: match region {
+                Region::NTSC => emphasis,
+                // PAL and Dendy PPUs have red and green emphasis bits the other way around
+                Region::PAL | Region::Dendy => (emphasis & 0b_100) | ((emphasis & 0b_001) << 1) | ((emphasis & 0b_010) >> 1),
+            },
+        }
+    }
+}
+
+// Recommended palette from https://www.nesdev.org/wiki/PPU_palettes, used when no .pal file is loaded
+const DEFAULT_PALETTE: [u32; 64] = [
+    0xFF626262, 0xFF002E98, 0xFF0C11C2, 0xFF3B00C2, 0xFF650098, 0xFF7D004E, 0xFF7D0000, 0xFF651900, 0xFF3B3600, 0xFF0C4F00, 0xFF005B00, 0xFF005900, 0xFF00494E, 0xFF000000, 0xFF000000, 0xFF000000,
+    0xFFABABAB, 0xFF0064F4, 0xFF353CFF, 0xFF761BFF, 0xFFAE0AF4, 0xFFCF0C8F, 0xFFCF231C, 0xFFAE4700, 0xFF766F00, 0xFF359000, 0xFF00A100, 0xFF009E1C, 0xFF00888F, 0xFF000000, 0xFF000000, 0xFF000000,
+    0xFFFFFFFF, 0xFF4AB5FF, 0xFF858CFF, 0xFFC86AFF, 0xFFFF58FF, 0xFFFF5BE2, 0xFFFF726A, 0xFFFF9702, 0xFFC8C100, 0xFF85E300, 0xFF4AF502, 0xFF29F26A, 0xFF29DBE2, 0xFF4E4E4E, 0xFF000000, 0xFF000000,
+    0xFFFFFFFF, 0xFFB6E1FF, 0xFFCED1FF, 0xFFE9C3FF, 0xFFFFBCFF, 0xFFFFBDF4, 0xFFFFC6C3, 0xFFFFD59A, 0xFFE9E681, 0xFFCEF481, 0xFFB6FB9A, 0xFFA9FAC3, 0xFFA9F0F4, 0xFFB8B8B8, 0xFF000000, 0xFF000000,
+];
+
+// How much non emphasized channels are dimmed
+const EMPHASIS_ATTENUATION: f64 = 0.816328;
+
+static EMPHASIS_PALETTE: OnceLock<[u32; 512]> = OnceLock::new();
+
+pub fn build_emphasis_palette(palette: &[u32; 64]) -> [u32; 512] {
+    let mut emphasis_palette = [0u32; 512];
+    for emphasis in 0..8 {
+        for index in 0..64 {
+            let color = palette[index];
+            let mut red = ((color >> 16) & 0xFF) as f64;
+            let mut green = ((color >> 8) & 0xFF) as f64;
+            let mut blue = (color & 0xFF) as f64;
+            // emphasizing one channel actually darkens other two
+            if emphasis & 0b_110 != 0 { red *= EMPHASIS_ATTENUATION };
+            if emphasis & 0b_101 != 0 { green *= EMPHASIS_ATTENUATION };
+            if emphasis & 0b_011 != 0 { blue *= EMPHASIS_ATTENUATION };
+            emphasis_palette[(emphasis << 6) | index] = 0xFF000000 | ((red as u32) << 16) | ((green as u32) << 8) | (blue as u32);
+        }
+    }
+    emphasis_palette
+}
+
+// Accepts both 64 color (192 bytes) and 512 color emphasis aware (1536 bytes) .pal files
+pub fn parse_palette(data: &[u8]) -> Result<[u32; 512], &'static str> {
+    let colors: Vec<u32> = data
+        .chunks_exact(3)
+        .map(|rgb| 0xFF000000 | ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | (rgb[2] as u32))
+        .collect();
+    match data.len() {
+        192 => Ok(build_emphasis_palette(&colors.try_into().unwrap())),
+        1536 => Ok(colors.try_into().unwrap()),
+        _ => Err("Palette file should be either 192 or 1536 bytes long"),
+    }
+}
+
+pub fn load_palette_file(file_path: &str) -> Result<(), &'static str> {
+    use std::fs;
+
+    let data = fs::read(file_path).map_err(|_| "Couldn't read palette file")?;
+    let palette = parse_palette(&data)?;
+    EMPHASIS_PALETTE.set(palette).map_err(|_| "Palette is already loaded")
+}
+
+pub fn get_color(index: u8, color_mode: ColorMode) -> u32 {
+    let palette = EMPHASIS_PALETTE.get_or_init(|| build_emphasis_palette(&DEFAULT_PALETTE));
+    let index = if color_mode.greyscale { index & 0x30 } else { index & 0x3F };
+    palette[((color_mode.emphasis as usize & 0b_111) << 6) | index as usize]
 }
 
 pub struct Tile {
@@ -168,3 +213,75 @@ impl Tile {
         return rendered;
     }
 }
+
+#[cfg(test)]
+mod color_tests {
+    use super::*;
+
+    #[test]
+    fn test_no_emphasis_matches_palette() {
+        let palette = build_emphasis_palette(&DEFAULT_PALETTE);
+        assert_eq!(palette[0..64], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_emphasis_attenuation() {
+        let palette = build_emphasis_palette(&DEFAULT_PALETTE);
+        let white = 0x20;
+        assert_eq!(DEFAULT_PALETTE[white], 0xFFFFFFFF);
+        assert_eq!(palette[(0b_001 << 6) | white], 0xFFFFD0D0); // red
+        assert_eq!(palette[(0b_010 << 6) | white], 0xFFD0FFD0); // green
+        assert_eq!(palette[(0b_100 << 6) | white], 0xFFD0D0FF); // blue
+        assert_eq!(palette[(0b_111 << 6) | white], 0xFFD0D0D0);
+    }
+
+    #[test]
+    fn test_parse_palette() {
+        let mut data = vec![0u8; 192];
+        data[0x20*3..0x20*3+3].copy_from_slice(&[0x12, 0x34, 0x56]);
+        let palette = parse_palette(&data).unwrap();
+        assert_eq!(palette[0x20], 0xFF123456);
+        assert_eq!(palette[0x00], 0xFF000000);
+
+        let mut data = vec![0u8; 1536];
+        data[0x1FF*3..0x1FF*3+3].copy_from_slice(&[0xAB, 0xCD, 0xEF]);
+        let palette = parse_palette(&data).unwrap();
+        assert_eq!(palette[0x1FF], 0xFFABCDEF);
+        assert_eq!(palette[0x03F], 0xFF000000); // emphasis is not generated for full palettes
+
+        assert!(parse_palette(&[0u8; 191]).is_err());
+        assert!(parse_palette(&[0u8; 0]).is_err());
+    }
+
+    #[test]
+    fn test_parse_bundled_palette() {
+        let data = std::fs::read("Composite_wiki.pal").unwrap();
+        let palette = parse_palette(&data).unwrap();
+        assert_eq!(palette[0..64], DEFAULT_PALETTE);
+    }
+
+    #[test]
+    fn test_greyscale() {
+        let greyscale = ColorMode { greyscale: true, emphasis: 0 };
+        assert_eq!(get_color(0x16, greyscale), get_color(0x10, ColorMode::default()));
+        assert_eq!(get_color(0x2C, greyscale), get_color(0x20, ColorMode::default()));
+        assert_eq!(get_color(0x0F, greyscale), get_color(0x00, ColorMode::default()));
+    }
+
+    #[test]
+    fn test_from_ppumask() {
+        assert_eq!(ColorMode::from_ppumask(0b_0001_1110, Region::NTSC), ColorMode { greyscale: false, emphasis: 0 });
+        assert_eq!(ColorMode::from_ppumask(0b_0010_0001, Region::NTSC), ColorMode { greyscale: true, emphasis: 0b_001 });
+        assert_eq!(ColorMode::from_ppumask(0b_1100_0000, Region::NTSC), ColorMode { greyscale: false, emphasis: 0b_110 });
+    }
+
+    #[test]
+    fn test_pal_emphasis_swap() {
+        for region in [Region::PAL, Region::Dendy] {
+            assert_eq!(ColorMode::from_ppumask(0b_0010_0000, region).emphasis, 0b_010); // green
+            assert_eq!(ColorMode::from_ppumask(0b_0100_0000, region).emphasis, 0b_001); // red
+            assert_eq!(ColorMode::from_ppumask(0b_1010_0000, region).emphasis, 0b_110);
+            assert_eq!(ColorMode::from_ppumask(0b_1110_0001, region), ColorMode { greyscale: true, emphasis: 0b_111 });
+        }
+    }
+}
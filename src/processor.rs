@@ -2,13 +2,15 @@ use std::num::Wrapping;
 
 use derive_new::new;
 
-use crate::MEM;
+use crate::{ snapshot::{ Snapshot, StateReader, StateWriter }, MEM };
 
-use self::settings::Settings;
+use self::settings::{ Settings, SettingsObserver };
+use self::dma::DMA;
 
 pub mod execution;
 pub mod settings;
 pub mod instruction;
+mod dma;
 
 #[allow(non_snake_case, clippy::upper_case_acronyms)]
 #[derive(Debug)]
@@ -44,11 +46,23 @@ pub struct CPU {
     #[allow(dead_code)] // for future use
     settings: Settings,
     #[new(default)]
-    cycle_count: u64,
-    #[new(default)]
-    odd_frame: bool,
+    cycle_count: u64, // total cycles since power on
     #[new(default)]
     cpu_state: CpuState,
+    #[new(default)]
+    nmi_line: bool,
+    #[new(default)]
+    nmi_detected: bool,
+    #[new(default)]
+    nmi_delayed: bool,
+    #[new(default)]
+    nmi_pending: bool,
+    #[new(default)]
+    irq_line: bool,
+    #[new(default)]
+    irq_pending: bool,
+    #[new(default)]
+    dma: DMA,
 }
 
 #[derive(Debug)]
@@ -334,11 +348,17 @@ impl CPU {
             let ptr = &self.cpu_state as *const CpuState as *mut CpuState;
             *ptr = match self.cpu_state {
                 CpuState::Waiting(left) => CpuState::Waiting(cycles + left),
-                CpuState::Ready => CpuState::Waiting(1),
+                CpuState::Ready if cycles == 0 => CpuState::Ready,
+                CpuState::Ready => CpuState::Waiting(cycles),
             }
         }
     }
 
+    // Between instructions, next tick starts a new one
+    pub fn is_ready(&self) -> bool {
+        self.cpu_state == CpuState::Ready
+    }
+
     pub fn push_stack(&mut self, data: u8, memory: &mut MEM) {
         memory.write(0x0100 + self.S.0 as usize, data);
         self.decrement_s();
@@ -377,6 +397,28 @@ impl CPU {
         (Wrapping::<u16>(self.get_pc()) + Wrapping::<u16>(1)).0 as usize
     }
 
+    // NMI is edge sensitive, so it is latched on rising edge and serviced at instruction boundary
+    pub fn set_nmi_line(&mut self, level: bool) {
+        if level && !self.nmi_line {
+            self.nmi_detected = true;
+        }
+        self.nmi_line = level;
+    }
+
+    // IRQ is level sensitive, devices keep line asserted until it gets acknowledged
+    pub fn set_irq_line(&mut self, level: bool) {
+        self.irq_line = level;
+    }
+
+    // Edge has to be detected before the last cycle of instruction to be serviced right after it,
+    // so detected edge becomes pending only after one more cycle
+    pub(crate) fn poll_interrupts(&mut self) {
+        self.nmi_pending |= self.nmi_delayed;
+        self.nmi_delayed = self.nmi_detected;
+        self.nmi_detected = false;
+        self.irq_pending = self.irq_line && !self.I;
+    }
+
     pub fn nmi(&mut self, memory: &mut MEM) {
         let return_address = self.get_pc();
         let high_byte = (return_address >> 8) as u8;
@@ -385,10 +427,22 @@ impl CPU {
         self.push_stack(low_byte, memory);
         self.B = false;
         self.push_stack(self.store_status(), memory);
+        self.I = true;
         let vector = memory.read(0xFFFA, 2);
         self.store_pc(vector as u16);
     }
 
+    pub fn irq(&mut self, memory: &mut MEM) {
+        let return_address = self.get_pc();
+        self.push_stack((return_address >> 8) as u8, memory);
+        self.push_stack(return_address as u8, memory);
+        self.B = false;
+        self.push_stack(self.store_status(), memory);
+        self.I = true;
+        let vector = memory.read(0xFFFE, 2);
+        self.store_pc(vector as u16);
+    }
+
     #[allow(dead_code)]
     pub fn reset(&mut self, memory: &mut MEM) {
         let vector = memory.read(0xFFFC, 2);
@@ -435,7 +489,58 @@ impl CPU {
     pub fn store_s(&mut self, value: u8) {self.S = Wrapping(value)}
     pub fn increment_s(&mut self) {self.S += 1}
     pub fn decrement_s(&mut self) {self.S -= 1}
-    pub fn is_odd_frame(&self) -> bool {self.odd_frame}
+}
+
+impl SettingsObserver for CPU {
+    fn settings_updated(&mut self, settings: &Settings) {
+        self.settings = settings.clone();
+    }
+}
+
+impl Snapshot for CPU {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.PC.0);
+        state.write_u8(self.A.0);
+        state.write_u8(self.X.0);
+        state.write_u8(self.Y.0);
+        state.write_u8(self.S.0);
+        state.write_u8(self.store_status());
+        state.write_bool(self.B);
+        state.write_u64(self.cycle_count);
+        state.write_u64(match self.cpu_state { // Waiting(0) never happens, so it stands for Ready
+            CpuState::Waiting(cycles) => cycles as u64,
+            CpuState::Ready => 0,
+        });
+        state.write_bool(self.nmi_line);
+        state.write_bool(self.nmi_detected);
+        state.write_bool(self.nmi_delayed);
+        state.write_bool(self.nmi_pending);
+        state.write_bool(self.irq_line);
+        state.write_bool(self.irq_pending);
+        self.save_dma_state(state);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.PC = Wrapping(state.read_u16()?);
+        self.A = Wrapping(state.read_u8()?);
+        self.X = Wrapping(state.read_u8()?);
+        self.Y = Wrapping(state.read_u8()?);
+        self.S = Wrapping(state.read_u8()?);
+        self.load_status(state.read_u8()?);
+        self.B = state.read_bool()?;
+        self.cycle_count = state.read_u64()?;
+        self.cpu_state = match state.read_u64()? {
+            0 => CpuState::Ready,
+            cycles => CpuState::Waiting(cycles as usize),
+        };
+        self.nmi_line = state.read_bool()?;
+        self.nmi_detected = state.read_bool()?;
+        self.nmi_delayed = state.read_bool()?;
+        self.nmi_pending = state.read_bool()?;
+        self.irq_line = state.read_bool()?;
+        self.irq_pending = state.read_bool()?;
+        self.load_dma_state(state)
+    }
 }
 
 #[cfg(test)]
@@ -530,3 +635,32 @@ mod test_stack {
         assert_eq!(test_cpu.pull_stack(&mut memory), 69, "Wrong number on underflow");
     }
 }
+
+#[cfg(test)]
+mod snapshot_tests {
+    use crate::memory::{ MemoryHandler, MEMORY_SIZE };
+    use crate::snapshot::{ load_all, save_all };
+
+    use super::*;
+
+    #[test]
+    fn test_cpu_round_trip() {
+        let mut test_cpu = CPU::new();
+        let mut memory = MEM::new(MEMORY_SIZE);
+        memory.data[0x0200..0x0210].fill(0xEA); // NOP
+        test_cpu.store_pc(0x0200);
+        test_cpu.store_a(0x12);
+        test_cpu.store_x(0x34);
+        test_cpu.store_s(0xFD);
+        test_cpu.C = true;
+        test_cpu.N = true;
+        test_cpu.write(0x4014, 0x03);
+        test_cpu.tick(&mut memory).unwrap();
+        test_cpu.set_nmi_line(true);
+        let data = save_all(&[&test_cpu]);
+
+        let mut loaded_cpu = CPU::new();
+        load_all(&mut [&mut loaded_cpu], &data).unwrap();
+        assert_eq!(format!("{loaded_cpu:?}"), format!("{test_cpu:?}"));
+    }
+}
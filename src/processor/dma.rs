@@ -0,0 +1,304 @@
+use crate::{ memory::{ MemoryHandler, MEM }, snapshot::{ StateReader, StateWriter } };
+
+use super::{ CpuState, CPU };
+
+// OAM and DMC DMA units share the bus and take it over from CPU while halted.
+// Reads happen on get (even) cycles and writes on put (odd) cycles.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Default)]
+pub struct DMA {
+    halted: bool,
+    oam_page: Option<u8>,
+    oam_offset: usize,
+    oam_value: Option<u8>,
+    dmc_address: Option<u16>,
+    dmc_dummy_pending: bool,
+    dmc_sample: Option<u8>,
+    halted_read_address: u16, // CPU read that was halted, repeated on halt, dummy and alignment cycles
+    repeating_read: bool, // previous cycle was a repeated read
+}
+
+// $4014 writes come through memory hooks
+impl MemoryHandler for CPU {
+    fn read(&mut self, _address: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address == 0x4014 { // OAMDMA
+            self.dma.oam_page = Some(value);
+            self.dma.oam_offset = 0;
+        }
+    }
+}
+
+impl CPU {
+    pub fn request_dmc_dma(&mut self, address: u16) {
+        self.dma.dmc_address = Some(address);
+        self.dma.dmc_dummy_pending = true;
+    }
+
+    pub fn take_dmc_sample(&mut self) -> Option<u8> {
+        self.dma.dmc_sample.take()
+    }
+
+    fn is_get_cycle(&self) -> bool {
+        self.cycle_count.is_multiple_of(2)
+    }
+
+    // Returns true if DMA had the bus this cycle
+    pub(super) fn tick_dma(&mut self, memory: &mut MEM) -> bool {
+        // OAM DMA waits for instruction that started it to end, DMC DMA can halt in the middle of one
+        let oam_running = self.dma.oam_page.is_some() && self.cpu_state == CpuState::Ready;
+        let dmc_running = self.dma.dmc_address.is_some();
+        if !oam_running && !dmc_running {
+            return false;
+        }
+
+        if !self.dma.halted {
+            // CPU is halted on its read cycle, which is still performed
+            self.dma.halted = true;
+            self.dma.halted_read_address = memory.get_last_read_address() as u16;
+            self.repeat_halted_read(memory);
+            return true;
+        }
+
+        if dmc_running && self.dma.dmc_dummy_pending {
+            self.dma.dmc_dummy_pending = false;
+            if !oam_running { // running OAM DMA hides dummy cycle
+                self.repeat_halted_read(memory);
+                return true;
+            }
+        }
+
+        if self.is_get_cycle() {
+            if let (Some(address), false) = (self.dma.dmc_address, self.dma.dmc_dummy_pending) {
+                // DMC has priority and delays OAM DMA
+                self.dma.dmc_sample = Some(memory.read_untracked(address as usize, 1) as u8);
+                self.dma.dmc_address = None;
+                self.dma.repeating_read = false;
+            } else if let (true, Some(page), None) = (oam_running, self.dma.oam_page, self.dma.oam_value) {
+                let address = ((page as usize) << 8) | self.dma.oam_offset;
+                self.dma.oam_value = Some(memory.read_untracked(address, 1) as u8);
+                self.dma.repeating_read = false;
+            }
+        } else if let Some(value) = self.dma.oam_value.take() {
+            memory.write(0x2004, value);
+            self.dma.repeating_read = false;
+            self.dma.oam_offset += 1;
+            if self.dma.oam_offset == 0x100 {
+                self.dma.oam_page = None;
+            }
+        } else if !oam_running {
+            self.repeat_halted_read(memory); // DMC alignment cycle
+        }
+
+        if self.dma.oam_page.is_none() && self.dma.dmc_address.is_none() {
+            self.dma.halted = false;
+            self.dma.repeating_read = false;
+        }
+        true
+    }
+
+    pub(super) fn save_dma_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.dma.halted);
+        state.write_option_u8(self.dma.oam_page);
+        state.write_u16(self.dma.oam_offset as u16);
+        state.write_option_u8(self.dma.oam_value);
+        state.write_option_u16(self.dma.dmc_address);
+        state.write_bool(self.dma.dmc_dummy_pending);
+        state.write_option_u8(self.dma.dmc_sample);
+        state.write_u16(self.dma.halted_read_address);
+        state.write_bool(self.dma.repeating_read);
+    }
+
+    pub(super) fn load_dma_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        self.dma.halted = state.read_bool()?;
+        self.dma.oam_page = state.read_option_u8()?;
+        self.dma.oam_offset = state.read_u16()? as usize;
+        self.dma.oam_value = state.read_option_u8()?;
+        self.dma.dmc_address = state.read_option_u16()?;
+        self.dma.dmc_dummy_pending = state.read_bool()?;
+        self.dma.dmc_sample = state.read_option_u8()?;
+        self.dma.halted_read_address = state.read_u16()?;
+        self.dma.repeating_read = state.read_bool()?;
+        Ok(())
+    }
+
+    // Halted CPU keeps reading the same address, which has side effects like incrementing PPUDATA address.
+    // Controllers are clocked when read ends, so back to back reads of $4016/$4017 clock them only once.
+    fn repeat_halted_read(&mut self, memory: &mut MEM) {
+        let address = self.dma.halted_read_address;
+        if !(self.dma.repeating_read && (0x4016..=0x4017).contains(&address)) {
+            memory.read(address as usize, 1);
+        }
+        self.dma.repeating_read = true;
+    }
+}
+
+#[cfg(test)]
+mod dma_tests {
+    use crate::input::{ ControllerPorts, EmptyPort, Input, StandardController };
+    use crate::memory::{ HandlerPtrWrapper, MemoryOperation, MemoryRegion, MEMORY_SIZE };
+    use super::*;
+
+    #[derive(Default)]
+    struct BusRecorder {
+        reads: Vec<u16>,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl MemoryHandler for BusRecorder {
+        fn read(&mut self, address: u16) -> u8 {
+            self.reads.push(address);
+            0x40
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.writes.push((address, value));
+        }
+    }
+
+    fn prepare_dma_test(recorder: &mut BusRecorder) -> (CPU, MEM) {
+        let mut test_cpu: CPU = CPU::new();
+        let mut memory: MEM = MEM::new(MEMORY_SIZE);
+        memory.data[0x0200..0x0300].fill(0xEA); // NOP
+        for (i, byte) in memory.data[0x0300..0x0400].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let recorder = HandlerPtrWrapper(recorder as *mut BusRecorder as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x2004, 1), recorder);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4016, 1), recorder);
+        test_cpu.store_pc(0x0200);
+        (test_cpu, memory)
+    }
+
+    // Number of cycles until CPU gets to execute next instruction
+    fn count_stolen_cycles(test_cpu: &mut CPU, memory: &mut MEM) -> usize {
+        let mut cycles = 0;
+        while test_cpu.tick_dma(memory) {
+            test_cpu.cycle_count += 1;
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_oam_dma_alignment() {
+        for (start_cycle, expected_cycles) in [(0, 513), (1, 514)] {
+            let mut recorder = BusRecorder::default();
+            let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+            test_cpu.cycle_count = start_cycle + 1; // halt happens on the cycle after $4014 write
+
+            test_cpu.write(0x4014, 0x03);
+            assert_eq!(count_stolen_cycles(&mut test_cpu, &mut memory), expected_cycles);
+            assert_eq!(recorder.writes.len(), 256);
+            assert!(recorder.writes.iter().enumerate().all(|(i, write)| *write == (0x2004, i as u8)));
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_waits_for_instruction() {
+        let mut recorder = BusRecorder::default();
+        let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+
+        test_cpu.write(0x4014, 0x03);
+        test_cpu.add_sleep_cycles(2);
+        assert!(!test_cpu.tick_dma(&mut memory));
+        test_cpu.tick(&mut memory).unwrap();
+        test_cpu.tick(&mut memory).unwrap();
+        assert!(test_cpu.tick_dma(&mut memory));
+    }
+
+    #[test]
+    fn test_oam_dma_reads_through_bus() {
+        let mut recorder = BusRecorder::default();
+        let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+
+        test_cpu.write(0x4014, 0x40);
+        count_stolen_cycles(&mut test_cpu, &mut memory);
+        assert_eq!(recorder.reads, vec![0x4016]); // only first byte of page is mapped
+        assert_eq!(recorder.writes[0x16], (0x2004, 0x40));
+    }
+
+    #[test]
+    fn test_dmc_dma_alignment() {
+        for (start_cycle, expected_cycles) in [(0, 3), (1, 4)] {
+            let mut recorder = BusRecorder::default();
+            let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+            test_cpu.cycle_count = start_cycle;
+
+            test_cpu.request_dmc_dma(0x0342);
+            assert_eq!(count_stolen_cycles(&mut test_cpu, &mut memory), expected_cycles);
+            assert_eq!(test_cpu.take_dmc_sample(), Some(0x42));
+            assert_eq!(test_cpu.take_dmc_sample(), None);
+        }
+    }
+
+    #[test]
+    fn test_dmc_dma_during_oam_dma() {
+        let mut recorder = BusRecorder::default();
+        let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+        test_cpu.cycle_count = 1;
+
+        test_cpu.write(0x4014, 0x03);
+        for _ in 0..100 {
+            test_cpu.tick_dma(&mut memory);
+            test_cpu.cycle_count += 1;
+        }
+        test_cpu.request_dmc_dma(0x0342);
+        assert_eq!(count_stolen_cycles(&mut test_cpu, &mut memory), 513 - 100 + 2);
+        assert_eq!(test_cpu.take_dmc_sample(), Some(0x42));
+        assert!(recorder.writes.iter().enumerate().all(|(i, write)| *write == (0x2004, i as u8)));
+    }
+
+    #[test]
+    fn test_dmc_dma_rereads_controller() {
+        let mut recorder = BusRecorder::default();
+        let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+        test_cpu.cycle_count = 1;
+
+        memory.read(0x4016, 1); // LDA $4016 got halted on its read
+        test_cpu.request_dmc_dma(0x0342);
+        count_stolen_cycles(&mut test_cpu, &mut memory);
+        assert_eq!(recorder.reads.len(), 1 + 1); // halt, dummy and alignment cycles are one long read
+    }
+
+    #[test]
+    fn test_dmc_dma_repeats_cpu_read() {
+        let mut recorder = BusRecorder::default();
+        let (mut test_cpu, mut memory) = prepare_dma_test(&mut recorder);
+        let recorder_handler = HandlerPtrWrapper(&mut recorder as *mut BusRecorder as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x0342, 2), recorder_handler);
+
+        memory.read(0x4016, 1);
+        test_cpu.request_dmc_dma(0x0342);
+        count_stolen_cycles(&mut test_cpu, &mut memory);
+        test_cpu.request_dmc_dma(0x0343); // CPU didn't get to read anything in between
+        count_stolen_cycles(&mut test_cpu, &mut memory);
+        assert_eq!(recorder.reads, vec![0x4016, 0x4016, 0x0342, 0x4016, 0x0343]);
+    }
+
+    #[test]
+    fn test_dmc_dma_during_controller_read() {
+        let mut test_cpu: CPU = CPU::new();
+        let mut memory: MEM = MEM::new(MEMORY_SIZE);
+        memory.write_bulk(0x0200, vec![0xAD, 0x16, 0x40]); // LDA $4016
+        test_cpu.store_pc(0x0200);
+        let mut ports = ControllerPorts::new(Box::new(StandardController::new(0)), Box::new(EmptyPort));
+        let ports_handler = HandlerPtrWrapper(&mut ports as *mut ControllerPorts as *mut dyn MemoryHandler);
+        memory.push_hook(MemoryOperation::Read, MemoryRegion::new(0x4016, 2), ports_handler);
+        memory.push_hook(MemoryOperation::Write, MemoryRegion::new(0x4016, 1), ports_handler);
+        let mut input = Input::new_headless();
+        input.set_buttons(0, 0b_0000_0101); // A, Select
+        ports.update(&input);
+        memory.write(0x4016, 1);
+        memory.write(0x4016, 0);
+
+        test_cpu.tick(&mut memory).unwrap();
+        assert_eq!(test_cpu.get_a() & 1, 1);
+        test_cpu.request_dmc_dma(0x0342);
+        count_stolen_cycles(&mut test_cpu, &mut memory);
+        assert_eq!(memory.read(0x4016, 1) & 1, 1); // Select, B was skipped by the DMA
+    }
+}
@@ -4,9 +4,6 @@ use crate::memory::MEM;
 #[allow(unused_imports)] // It is used...
 use crate::logging::Logger;
 
-use std::time::Duration;
-use std::thread;
-
 mod load;
 mod store;
 mod transfers;
@@ -20,12 +17,6 @@ mod jumps;
 mod branches;
 
 impl CPU {
-    pub fn sleep(&mut self, ticks: u32) {
-        // FIXME: right now it sleeps n ticks + time it took to execute instruction
-        let sleep_timer = Duration::from_nanos((self.settings.clock_delta / self.settings.emulation_speed * ticks as f64) as u64);
-        thread::sleep(sleep_timer);
-    }
-
     pub fn fetch_mem_address(&mut self, address: u16, memory: &mut MEM) -> u16 {
         let least_significant_byte = memory.data[address as usize];
         let most_significant_byte = memory.data[address as usize + 1];
@@ -34,17 +25,28 @@ impl CPU {
 
     pub fn tick(&mut self, memory: &mut MEM) -> Result<(), ()> {
         self.cycle_count += 1;
-        if (!self.odd_frame && self.cycle_count >= 29781) || (self.odd_frame && self.cycle_count >= 29780) {
-            self.cycle_count = 0;
-            self.odd_frame = !self.odd_frame;
-        };
-        match self.cpu_state {
+        if self.tick_dma(memory) {
+            self.poll_interrupts();
+            return Ok(());
+        }
+        let result = match self.cpu_state {
+            CpuState::Ready if self.nmi_pending => {
+                self.nmi_pending = false;
+                self.add_sleep_cycles(7 - 1); // current cycle is the first one
+                self.nmi(memory);
+                Ok(())
+            },
+            CpuState::Ready if self.irq_pending => {
+                self.irq_pending = false;
+                self.add_sleep_cycles(7 - 1);
+                self.irq(memory);
+                Ok(())
+            },
             CpuState::Ready => {
                 let opcode = self.get_instr(memory);
                 let wait_time = Instruction::get_base_execution_time(opcode);
-                self.add_sleep_cycles(wait_time);
-                let result = self.execute(memory);
-                return result;
+                self.add_sleep_cycles(wait_time - 1); // current cycle is the first one
+                self.execute(memory)
             },
             CpuState::Waiting(cycles_left) => {
                 if cycles_left > 1 {
@@ -54,7 +56,9 @@ impl CPU {
                 };
                 Ok(())
             },
-        }
+        };
+        self.poll_interrupts();
+        return result;
     }
 
     pub fn execute(&mut self, memory: &mut MEM) -> Result<(), ()> {
@@ -200,6 +204,87 @@ impl CPU {
     }
 }
 
+#[cfg(test)]
+mod nmi_tests {
+    use crate::memory::MEMORY_SIZE;
+    use super::*;
+
+    fn prepare_nop_slide() -> (CPU, MEM) {
+        let mut test_cpu: CPU = CPU::new();
+        let mut memory: MEM = MEM::new(MEMORY_SIZE);
+        memory.data[0x0200..0x0300].fill(0xEA); // NOP
+        memory.data[0xFFFA..0xFFFC].copy_from_slice(&[0x00, 0x80]);
+        test_cpu.store_pc(0x0200);
+        test_cpu.store_s(0xFD);
+        (test_cpu, memory)
+    }
+
+    #[test]
+    fn test_nmi_serviced_after_instruction() {
+        let (mut test_cpu, mut memory) = prepare_nop_slide();
+
+        test_cpu.set_nmi_line(true);
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 1
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 2
+        assert_eq!(test_cpu.get_pc(), 0x0201);
+
+        test_cpu.tick(&mut memory).unwrap(); // NMI
+        assert_eq!(test_cpu.get_pc(), 0x8000);
+        assert!(test_cpu.I);
+        assert_eq!(memory.read(0x01FD, 1), 0x02);
+        assert_eq!(memory.read(0x01FC, 1), 0x01);
+    }
+
+    #[test]
+    fn test_nmi_on_last_cycle_is_delayed() {
+        let (mut test_cpu, mut memory) = prepare_nop_slide();
+
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 1
+        test_cpu.set_nmi_line(true);
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 2, edge is detected too late
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 1
+        assert_eq!(test_cpu.get_pc(), 0x0202);
+        test_cpu.tick(&mut memory).unwrap(); // NOP cycle 2
+
+        test_cpu.tick(&mut memory).unwrap(); // NMI
+        assert_eq!(test_cpu.get_pc(), 0x8000);
+    }
+
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let (mut test_cpu, mut memory) = prepare_nop_slide();
+        memory.data[0x8000..0x8100].fill(0xEA); // NOP
+
+        for _ in 0..2+7+2*4 { // NOP, NMI, 4 NOPs
+            test_cpu.set_nmi_line(true); // line stays high, but that's not a new edge
+            test_cpu.tick(&mut memory).unwrap();
+        }
+        assert_eq!(test_cpu.get_pc(), 0x8004);
+    }
+
+    #[test]
+    fn test_irq_respects_interrupt_flag() {
+        let (mut test_cpu, mut memory) = prepare_nop_slide();
+        memory.data[0xFFFE..0x10000].copy_from_slice(&[0x00, 0x90]);
+
+        test_cpu.I = true;
+        test_cpu.set_irq_line(true);
+        for _ in 0..4 { // 2 NOPs
+            test_cpu.tick(&mut memory).unwrap();
+        }
+        assert_eq!(test_cpu.get_pc(), 0x0202);
+
+        test_cpu.I = false;
+        for _ in 0..2 { // NOP, IRQ polled on its last cycle
+            test_cpu.tick(&mut memory).unwrap();
+        }
+        test_cpu.tick(&mut memory).unwrap(); // IRQ
+        assert_eq!(test_cpu.get_pc(), 0x9000);
+        assert!(test_cpu.I);
+        assert_eq!(memory.read(0x01FB, 1) & 0b_0001_0000, 0); // B flag clear
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -201,6 +201,8 @@ mod asl_tests {
             assert_eq!(test_cpu.get_x(), x_value);
             let absolute_address = ((high_byte as u16) << 8) + low_byte as u16;
             let result_address = (Wrapping::<u16>(absolute_address) + Wrapping::<u16>(x_value as u16)).0;
+            let instruction_address = if high_byte == 0x00 { 0x0100 } else { 0x0000 };
+            prop_assume!(!(instruction_address..instruction_address + 3).contains(&result_address)); // so we don't overwrite the instruction itself
             assert_eq!(memory.read(result_address as usize, 1) as u8, 0);
             memory.write(result_address as usize, value);
             assert_eq!(memory.read(result_address as usize, 1) as u8, value);
@@ -1,16 +1,18 @@
 #![allow(dead_code)] // FIXME
 
-#[derive(Debug)]
+use crate::audio_processor::CHANNEL_COUNT;
+
+#[derive(Debug, Clone)]
 pub struct Settings {
     pub clock_delta: f64, // clock delta in nanosecs
     pub emulation_speed: f64,
-}
-
-impl Settings {
-    #[allow(dead_code)]
-    pub fn update() {
-        todo!(); // TODO: impl observer pattern from centralised settings provider
-    }
+    pub fast_forward_speed: f64, // multiplies emulation speed while fast forwarding
+    pub slow_motion_speed: f64, // multiplies emulation speed in slow motion
+    pub rewind_depth: usize, // in frames
+    pub rewind_interval: usize, // frames between rewind snapshots
+    pub sample_rate: u32, // audio output in Hz
+    pub channel_volumes: [f32; CHANNEL_COUNT], // by AudioChannel: APU channels, then expansion chips
+    pub muted_channels: [bool; CHANNEL_COUNT],
 }
 
 impl Default for Settings {
@@ -18,6 +20,81 @@ impl Default for Settings {
         Settings{
             emulation_speed: 1.0,
             clock_delta: 558.73, // ~1.789773 MHz NTSC NES
+            fast_forward_speed: 4.0,
+            slow_motion_speed: 0.25,
+            rewind_depth: 600, // 10 seconds at 60 fps
+            rewind_interval: 4,
+            sample_rate: 44100,
+            channel_volumes: [1.0; CHANNEL_COUNT],
+            muted_channels: [false; CHANNEL_COUNT],
         }
     }
 }
+
+// Components that depend on settings get notified when they change
+pub trait SettingsObserver {
+    fn settings_updated(&mut self, settings: &Settings);
+}
+
+#[derive(Clone, Copy)]
+pub struct ObserverPtrWrapper(pub *mut dyn SettingsObserver);
+unsafe impl Sync for ObserverPtrWrapper {}
+unsafe impl Send for ObserverPtrWrapper {}
+
+// Centralised settings, every change goes through update() so observers always see current values
+#[derive(Default)]
+pub struct SettingsProvider {
+    settings: Settings,
+    observers: Vec<ObserverPtrWrapper>,
+}
+
+impl SettingsProvider {
+    pub fn new(settings: Settings) -> Self {
+        Self { settings, observers: vec![] }
+    }
+
+    pub fn get(&self) -> &Settings {
+        &self.settings
+    }
+
+    // New observer gets current settings right away
+    pub fn subscribe(&mut self, observer: ObserverPtrWrapper) {
+        unsafe { (*observer.0).settings_updated(&self.settings) };
+        self.observers.push(observer);
+    }
+
+    pub fn update(&mut self, change: impl FnOnce(&mut Settings)) {
+        change(&mut self.settings);
+        for observer in &self.observers {
+            unsafe { (*observer.0).settings_updated(&self.settings) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod settings_tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct SpeedObserver {
+        speeds: Vec<f64>,
+    }
+
+    impl SettingsObserver for SpeedObserver {
+        fn settings_updated(&mut self, settings: &Settings) {
+            self.speeds.push(settings.emulation_speed);
+        }
+    }
+
+    #[test]
+    fn test_observers_get_updates() {
+        let mut observer = SpeedObserver::default();
+        let mut provider = SettingsProvider::default();
+        provider.update(|settings| settings.emulation_speed = 0.5);
+        provider.subscribe(ObserverPtrWrapper(&mut observer as *mut SpeedObserver as *mut dyn SettingsObserver));
+        provider.update(|settings| settings.emulation_speed = 2.0);
+
+        assert_eq!(observer.speeds, vec![0.5, 2.0]);
+        assert_eq!(provider.get().emulation_speed, 2.0);
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Duration;
+
+use crate::region::Region;
+use self::wav::WavWriter;
+use self::y4m::Y4mWriter;
+
+pub mod wav;
+pub mod y4m;
+
+// Writes every emulated frame to Y4M video and WAV audio. Audio length is derived from frame count,
+// not wall clock, so both streams stay in sync and headless runs give identical files.
+pub struct AvRecorder {
+    video: Option<Y4mWriter<BufWriter<File>>>,
+    audio: Option<WavWriter<BufWriter<File>>>,
+    sample_rate: u32,
+    frame_duration: Duration,
+    frame_count: u64,
+    samples_written: u64,
+    pending_audio: Vec<i16>, // APU frames aren't exactly the average length, so some audio waits for next frame
+}
+
+impl AvRecorder {
+    pub fn new(video_path: Option<&str>, audio_path: Option<&str>, region: Region, sample_rate: u32) -> Result<Self, &'static str> {
+        let frame_duration = region.get_frame_duration();
+        let video = match video_path {
+            Some(path) => {
+                let file = File::create(path).map_err(|_| "Couldn't create video file")?;
+                Some(Y4mWriter::new(BufWriter::new(file), 256, 240, (1_000_000_000, frame_duration.as_nanos() as u64))?)
+            },
+            None => None,
+        };
+        let audio = match audio_path {
+            Some(path) => {
+                let file = File::create(path).map_err(|_| "Couldn't create audio file")?;
+                Some(WavWriter::new(BufWriter::new(file), sample_rate)?)
+            },
+            None => None,
+        };
+        Ok(Self { video, audio, sample_rate, frame_duration, frame_count: 0, samples_written: 0, pending_audio: vec![] })
+    }
+
+    // Exactly one frame worth of audio is written, missing samples are filled with silence
+    pub fn record_frame(&mut self, framebuffer: &[u32], audio: &[i16]) -> Result<(), &'static str> {
+        self.frame_count += 1;
+        if let Some(video) = &mut self.video {
+            video.write_frame(framebuffer)?;
+        }
+        let sample_count = (get_total_samples(self.frame_count, self.frame_duration, self.sample_rate) - self.samples_written) as usize;
+        self.samples_written += sample_count as u64;
+        self.pending_audio.extend_from_slice(audio);
+        let mut samples: Vec<i16> = self.pending_audio.drain(..sample_count.min(self.pending_audio.len())).collect();
+        samples.resize(sample_count, 0);
+        // Backlog stays under a frame, so audio can't drift behind video
+        let excess = self.pending_audio.len().saturating_sub(sample_count);
+        self.pending_audio.drain(..excess);
+        if let Some(writer) = &mut self.audio {
+            writer.write_samples(&samples)?;
+        }
+        Ok(())
+    }
+}
+
+// Samples that should be written after this many frames
+fn get_total_samples(frame_count: u64, frame_duration: Duration, sample_rate: u32) -> u64 {
+    (frame_count as u128 * frame_duration.as_nanos() * sample_rate as u128 / 1_000_000_000) as u64
+}
+
+#[cfg(test)]
+mod recording_tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_follow_frame_count() {
+        let frame_duration = Region::NTSC.get_frame_duration();
+        let per_frame: Vec<u64> = (1..=4)
+            .map(|frame| get_total_samples(frame, frame_duration, 44100) - get_total_samples(frame - 1, frame_duration, 44100))
+            .collect();
+
+        assert_eq!(per_frame, [733, 734, 734, 734]);
+        // rounding doesn't add up over long recordings, 3606 frames is 60.0012 seconds
+        assert_eq!(get_total_samples(3606, frame_duration, 44100), 2646052);
+    }
+
+    #[test]
+    fn test_pal_samples() {
+        assert_eq!(get_total_samples(50, Region::PAL.get_frame_duration(), 48000), 47993);
+    }
+}
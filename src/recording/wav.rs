@@ -0,0 +1,74 @@
+use std::io::{ Seek, SeekFrom, Write };
+
+const HEADER_SIZE: u32 = 44;
+
+// 16 bit mono PCM. Sizes in header are updated after every write,
+// so file stays playable even if emulator is closed without finishing it.
+pub struct WavWriter<W: Write + Seek> {
+    output: W,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut output: W, sample_rate: u32) -> Result<Self, &'static str> {
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // mono
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // bytes per second
+        header.extend_from_slice(&2u16.to_le_bytes()); // bytes per sample
+        header.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        output.write_all(&header).map_err(|_| "Couldn't write WAV file")?;
+        Ok(Self { output, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), &'static str> {
+        let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        self.output.write_all(&bytes).map_err(|_| "Couldn't write WAV file")?;
+        self.data_size += bytes.len() as u32;
+        self.update_sizes().map_err(|_| "Couldn't write WAV file")
+    }
+
+    fn update_sizes(&mut self) -> std::io::Result<()> {
+        self.output.seek(SeekFrom::Start(4))?;
+        self.output.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.output.seek(SeekFrom::Start(40))?;
+        self.output.write_all(&self.data_size.to_le_bytes())?;
+        self.output.seek(SeekFrom::End(0))?;
+        self.output.flush()
+    }
+
+    #[allow(dead_code)] // for tests
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_header_tracks_data_size() {
+        let mut writer = WavWriter::new(Cursor::new(vec![]), 44100).unwrap();
+        writer.write_samples(&[1, -1]).unwrap();
+        writer.write_samples(&[0x1234]).unwrap();
+        let wav = writer.into_inner().into_inner();
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(wav[0..4], *b"RIFF");
+        assert_eq!(wav[4..8], 42u32.to_le_bytes());
+        assert_eq!(wav[24..28], 44100u32.to_le_bytes());
+        assert_eq!(wav[36..40], *b"data");
+        assert_eq!(wav[40..44], 6u32.to_le_bytes());
+        assert_eq!(wav[44..], [0x01, 0x00, 0xFF, 0xFF, 0x34, 0x12]);
+    }
+}
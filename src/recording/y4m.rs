@@ -0,0 +1,66 @@
+use std::io::Write;
+
+// Uncompressed YUV4MPEG2 video with full resolution chroma, ffmpeg and most players read it
+pub struct Y4mWriter<W: Write> {
+    output: W,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    // Frame rate is given as a fraction, like 60000:1001
+    pub fn new(mut output: W, width: usize, height: usize, frame_rate: (u64, u64)) -> Result<Self, &'static str> {
+        let header = format!("YUV4MPEG2 W{width} H{height} F{}:{} Ip A1:1 C444\n", frame_rate.0, frame_rate.1);
+        output.write_all(header.as_bytes()).map_err(|_| "Couldn't write video file")?;
+        Ok(Self { output })
+    }
+
+    // Pixels are 0xAARRGGBB
+    pub fn write_frame(&mut self, pixels: &[u32]) -> Result<(), &'static str> {
+        let mut frame = Vec::with_capacity(6 + pixels.len() * 3);
+        frame.extend_from_slice(b"FRAME\n");
+        let converted: Vec<(u8, u8, u8)> = pixels.iter().map(|pixel| rgb_to_ycbcr(*pixel)).collect();
+        frame.extend(converted.iter().map(|(y, _, _)| *y));
+        frame.extend(converted.iter().map(|(_, cb, _)| *cb));
+        frame.extend(converted.iter().map(|(_, _, cr)| *cr));
+        self.output.write_all(&frame).map_err(|_| "Couldn't write video file")?;
+        self.output.flush().map_err(|_| "Couldn't write video file")
+    }
+
+    #[allow(dead_code)] // for tests
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+// BT.601 limited range, which is what Y4M readers assume by default
+fn rgb_to_ycbcr(color: u32) -> (u8, u8, u8) {
+    let red = ((color >> 16) & 0xFF) as f64;
+    let green = ((color >> 8) & 0xFF) as f64;
+    let blue = (color & 0xFF) as f64;
+    let y = 16.0 + (65.481*red + 128.553*green + 24.966*blue) / 255.0;
+    let cb = 128.0 + (-37.797*red - 74.203*green + 112.0*blue) / 255.0;
+    let cr = 128.0 + (112.0*red - 93.786*green - 18.214*blue) / 255.0;
+    (y.round() as u8, cb.round() as u8, cr.round() as u8)
+}
+
+#[cfg(test)]
+mod y4m_tests {
+    use super::*;
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(rgb_to_ycbcr(0xFF000000), (16, 128, 128));
+        assert_eq!(rgb_to_ycbcr(0xFFFFFFFF), (235, 128, 128));
+        assert_eq!(rgb_to_ycbcr(0xFFFF0000), (81, 90, 240));
+    }
+
+    #[test]
+    fn test_stream_layout() {
+        let mut writer = Y4mWriter::new(vec![], 2, 1, (60, 1)).unwrap();
+        writer.write_frame(&[0xFF000000, 0xFFFFFFFF]).unwrap();
+        let video = writer.into_inner();
+
+        let header = b"YUV4MPEG2 W2 H1 F60:1 Ip A1:1 C444\n";
+        assert_eq!(video[..header.len()], *header);
+        assert_eq!(video[header.len()..], *b"FRAME\n\x10\xEB\x80\x80\x80\x80");
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use crate::memory::ines::ConsoleTiming;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Region {
+    #[default]
+    NTSC,
+    PAL,
+    Dendy,
+}
+
+impl Region {
+    pub fn from_console_timing(console_timing: Option<ConsoleTiming>) -> Self {
+        match console_timing {
+            Some(ConsoleTiming::PAL) => Region::PAL,
+            Some(ConsoleTiming::Dendy) => Region::Dendy,
+            _ => Region::NTSC, // multi-region games and iNES 1.0 roms default to NTSC
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, &'static str> {
+        match name.to_lowercase().as_str() {
+            "ntsc" => Ok(Region::NTSC),
+            "pal" => Ok(Region::PAL),
+            "dendy" => Ok(Region::Dendy),
+            _ => Err("Unknown region, expected ntsc, pal or dendy"),
+        }
+    }
+
+    // Master clock divided by CPU divider
+    pub fn get_cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::NTSC => 21_477_272.0 / 12.0,
+            Region::PAL => 26_601_712.0 / 16.0,
+            Region::Dendy => 26_601_712.0 / 15.0,
+        }
+    }
+
+    // CPU clock delta in nanosecs
+    pub fn get_cpu_clock_delta(&self) -> f64 {
+        1_000_000_000.0 / self.get_cpu_clock_rate()
+    }
+
+    // PPU dots per CPU cycles as a fraction, PAL runs 3.2 dots per cycle
+    pub fn get_ppu_clock_ratio(&self) -> (u32, u32) {
+        match self {
+            Region::NTSC => (3, 1),
+            Region::PAL => (16, 5),
+            Region::Dendy => (3, 1),
+        }
+    }
+
+    // Including post-render, vblank and pre-render lines
+    pub fn get_scanline_count(&self) -> usize {
+        match self {
+            Region::NTSC => 262,
+            Region::PAL | Region::Dendy => 312,
+        }
+    }
+
+    pub fn get_pre_render_line(&self) -> usize {
+        self.get_scanline_count() - 1
+    }
+
+    // Dendy has long post-render period and starts vblank 50 lines later than PAL
+    pub fn get_vblank_line(&self) -> usize {
+        match self {
+            Region::NTSC | Region::PAL => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Pre-render line is one dot shorter on odd frames, but only on NTSC and only while rendering
+    pub fn get_frame_length(&self, odd_frame: bool, rendering_enabled: bool) -> u64 {
+        let frame_length = (self.get_scanline_count() * 341) as u64;
+        if *self == Region::NTSC && odd_frame && rendering_enabled {
+            frame_length - 1
+        } else {
+            frame_length
+        }
+    }
+
+    // Average frame time since NTSC frames alternate in length
+    pub fn get_frame_duration(&self) -> Duration {
+        let average_dots = match self {
+            Region::NTSC => self.get_frame_length(false, false) as f64 - 0.5,
+            Region::PAL | Region::Dendy => self.get_frame_length(false, false) as f64,
+        };
+        let (dots, cycles) = self.get_ppu_clock_ratio();
+        let cpu_cycles = average_dots * cycles as f64 / dots as f64;
+        Duration::from_nanos((cpu_cycles * self.get_cpu_clock_delta()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod region_tests {
+    use super::*;
+
+    #[test]
+    fn test_from_console_timing() {
+        assert_eq!(Region::from_console_timing(None), Region::NTSC);
+        assert_eq!(Region::from_console_timing(Some(ConsoleTiming::NTSC)), Region::NTSC);
+        assert_eq!(Region::from_console_timing(Some(ConsoleTiming::PAL)), Region::PAL);
+        assert_eq!(Region::from_console_timing(Some(ConsoleTiming::MultiRegion)), Region::NTSC);
+        assert_eq!(Region::from_console_timing(Some(ConsoleTiming::Dendy)), Region::Dendy);
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Region::from_name("pal"), Ok(Region::PAL));
+        assert_eq!(Region::from_name("Dendy"), Ok(Region::Dendy));
+        assert!(Region::from_name("secam").is_err());
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        assert_eq!(Region::NTSC.get_frame_length(false, false), 89342);
+        assert_eq!(Region::NTSC.get_frame_length(false, true), 89342);
+        assert_eq!(Region::NTSC.get_frame_length(true, false), 89342);
+        assert_eq!(Region::NTSC.get_frame_length(true, true), 89341);
+        assert_eq!(Region::PAL.get_frame_length(true, true), 106392);
+        assert_eq!(Region::Dendy.get_frame_length(true, true), 106392);
+    }
+
+    #[test]
+    fn test_frame_rate() {
+        let frame_rate = |region: Region| 1.0 / region.get_frame_duration().as_secs_f64();
+        assert!((frame_rate(Region::NTSC) - 60.0988).abs() < 0.001);
+        assert!((frame_rate(Region::PAL) - 50.0070).abs() < 0.001);
+        assert!((frame_rate(Region::Dendy) - 50.0070).abs() < 0.01);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::VecDeque;
+
+use crate::{ input::movie::MovieFrame, processor::settings::Settings };
+
+// Snapshots are taken every few frames together with input of every frame, so going back
+// to any frame is loading the closest snapshot before it and replaying input from there.
+// Only the newest snapshot is kept whole, older ones are stored as compressed difference
+// to the snapshot after them, which is mostly zeros since little changes in a few frames.
+pub struct Rewind {
+    interval: usize, // frames between snapshots
+    capacity: usize, // snapshots
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // oldest first
+    frames: VecDeque<MovieFrame>, // every frame since the oldest snapshot
+}
+
+impl Rewind {
+    pub fn new(settings: &Settings) -> Self {
+        let interval = settings.rewind_interval.max(1);
+        Self {
+            interval,
+            capacity: settings.rewind_depth / interval + 1,
+            newest: None,
+            deltas: VecDeque::new(),
+            frames: VecDeque::new(),
+        }
+    }
+
+    fn get_snapshot_count(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    // Checked at the start of every frame, before its input is pushed
+    pub fn is_snapshot_due(&self) -> bool {
+        self.frames.len() == self.get_snapshot_count() * self.interval
+    }
+
+    pub fn push_snapshot(&mut self, state: Vec<u8>) {
+        if let Some(previous) = self.newest.replace(state) {
+            self.deltas.push_back(compress_delta(&previous, self.newest.as_ref().unwrap()));
+        }
+        if self.get_snapshot_count() > self.capacity {
+            self.deltas.pop_front();
+            self.frames.drain(..self.interval);
+        }
+    }
+
+    pub fn push_frame(&mut self, frame: MovieFrame) {
+        self.frames.push_back(frame);
+    }
+
+    // Goes one frame back. Returns snapshot to load and frames to replay after it,
+    // the last replayed frame is the one that should be shown.
+    pub fn step_back(&mut self) -> Option<(Vec<u8>, Vec<MovieFrame>)> {
+        if self.frames.len() < 2 {
+            return None;
+        }
+        let target = self.frames.len() - 1;
+        let snapshot_count = (target - 1) / self.interval + 1;
+        while self.get_snapshot_count() > snapshot_count {
+            let delta = self.deltas.pop_back()?;
+            self.newest = Some(decompress_delta(&delta, self.newest.as_ref()?));
+        }
+        self.frames.truncate(target);
+        let replayed = self.frames.range((snapshot_count - 1) * self.interval..).copied().collect();
+        Some((self.newest.clone()?, replayed))
+    }
+}
+
+// XOR with newer snapshot, stored as length of older one followed by
+// (zero run length, literal run length, literals) triples
+fn compress_delta(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    let difference: Vec<u8> = older.iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ newer.get(i).copied().unwrap_or(0))
+        .collect();
+    let mut delta = vec![];
+    write_varint(&mut delta, older.len());
+    let mut position = 0;
+    while position < difference.len() {
+        let zeros = difference[position..].iter().take_while(|byte| **byte == 0).count();
+        position += zeros;
+        let literals = difference[position..].iter().take_while(|byte| **byte != 0).count();
+        write_varint(&mut delta, zeros);
+        write_varint(&mut delta, literals);
+        delta.extend_from_slice(&difference[position..position + literals]);
+        position += literals;
+    }
+    delta
+}
+
+fn decompress_delta(delta: &[u8], newer: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+    let mut older: Vec<u8> = (0..length).map(|i| newer.get(i).copied().unwrap_or(0)).collect();
+    let mut output_position = 0;
+    while position < delta.len() {
+        output_position += read_varint(delta, &mut position);
+        let literals = read_varint(delta, &mut position);
+        for byte in &delta[position..position + literals] {
+            older[output_position] ^= byte;
+            output_position += 1;
+        }
+        position += literals;
+    }
+    older
+}
+
+// 7 bits per byte, highest bit set if more bytes follow
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn read_varint(input: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod rewind_tests {
+    use super::*;
+
+    fn create_rewind(depth: usize, interval: usize) -> Rewind {
+        Rewind::new(&Settings { rewind_depth: depth, rewind_interval: interval, ..Settings::default() })
+    }
+
+    fn frame(number: usize) -> MovieFrame {
+        MovieFrame { commands: 0, buttons: [number as u8, 0, 0, 0] }
+    }
+
+    // Plays frames with state being just the frame number
+    fn play(rewind: &mut Rewind, from: usize, to: usize) {
+        for number in from..to {
+            if rewind.is_snapshot_due() {
+                rewind.push_snapshot(vec![number as u8; 300]);
+            }
+            rewind.push_frame(frame(number));
+        }
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let newer: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut older = newer.clone();
+        older[3] = 0xFF;
+        older[500..510].fill(0);
+        older.extend_from_slice(&[1, 2, 3]);
+
+        let delta = compress_delta(&older, &newer);
+        assert!(delta.len() < 40);
+        assert_eq!(decompress_delta(&delta, &newer), older);
+        assert_eq!(decompress_delta(&compress_delta(&newer[..10], &newer), &newer), newer[..10]);
+    }
+
+    #[test]
+    fn test_step_back_replays_from_snapshot() {
+        let mut rewind = create_rewind(100, 4);
+        play(&mut rewind, 0, 10);
+
+        assert_eq!(rewind.step_back(), Some((vec![8; 300], vec![frame(8)])));
+        assert_eq!(rewind.step_back(), Some((vec![4; 300], vec![frame(4), frame(5), frame(6), frame(7)])));
+        assert_eq!(rewind.step_back(), Some((vec![4; 300], vec![frame(4), frame(5), frame(6)])));
+    }
+
+    #[test]
+    fn test_snapshots_are_retaken_after_rewind() {
+        let mut rewind = create_rewind(100, 4);
+        play(&mut rewind, 0, 10);
+        rewind.step_back();
+        rewind.step_back();
+        assert!(rewind.is_snapshot_due());
+
+        play(&mut rewind, 8, 10);
+        assert_eq!(rewind.step_back(), Some((vec![8; 300], vec![frame(8)])));
+    }
+
+    #[test]
+    fn test_depth_is_limited() {
+        let mut rewind = create_rewind(8, 4);
+        play(&mut rewind, 0, 20);
+
+        let mut steps = 0;
+        while rewind.step_back().is_some() {
+            steps += 1;
+        }
+        assert_eq!(steps, 11);
+        assert_eq!(rewind.newest, Some(vec![8; 300]));
+    }
+}
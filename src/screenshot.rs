@@ -0,0 +1,77 @@
+use std::path::Path;
+
+pub mod png;
+
+// Picture area that most TVs hid behind the bezel
+const OVERSCAN: usize = 8;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenshotOptions {
+    pub scale: usize,
+    pub crop_overscan: bool,
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> Self {
+        Self { scale: 1, crop_overscan: false }
+    }
+}
+
+// Framebuffer is 256x240 of 0xAARRGGBB, returns width, height and RGB pixels
+pub fn render_screenshot(framebuffer: &[u32], options: ScreenshotOptions) -> (usize, usize, Vec<u8>) {
+    let border = if options.crop_overscan { OVERSCAN } else { 0 };
+    let scale = options.scale.max(1);
+    let (width, height) = ((256 - 2*border) * scale, (240 - 2*border) * scale);
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let color = framebuffer[(border + x / scale) + (border + y / scale) * 256];
+            pixels.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+        }
+    }
+    (width, height, pixels)
+}
+
+pub fn save_screenshot(path: &str, framebuffer: &[u32], options: ScreenshotOptions) -> Result<(), &'static str> {
+    let (width, height, pixels) = render_screenshot(framebuffer, options);
+    std::fs::write(path, png::encode_png(width, height, &pixels)).map_err(|_| "Couldn't write screenshot file")
+}
+
+// First of name_001.png, name_002.png... that doesn't exist yet
+pub fn get_free_screenshot_path(name: &str) -> String {
+    (1..)
+        .map(|number| format!("{name}_{number:03}.png"))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod screenshot_tests {
+    use super::*;
+
+    fn create_framebuffer() -> Vec<u32> {
+        let mut framebuffer = vec![0xFF000000; 256*240];
+        framebuffer[0] = 0xFF123456;
+        framebuffer[8 + 8*256] = 0xFFABCDEF;
+        framebuffer
+    }
+
+    #[test]
+    fn test_native_size() {
+        let (width, height, pixels) = render_screenshot(&create_framebuffer(), ScreenshotOptions::default());
+
+        assert_eq!((width, height), (256, 240));
+        assert_eq!(pixels[..6], [0x12, 0x34, 0x56, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_scaled_and_cropped() {
+        let options = ScreenshotOptions { scale: 2, crop_overscan: true };
+        let (width, height, pixels) = render_screenshot(&create_framebuffer(), options);
+
+        assert_eq!((width, height), (480, 448));
+        assert_eq!(pixels.len(), 480*448*3);
+        assert_eq!(pixels[..9], [0xAB, 0xCD, 0xEF, 0xAB, 0xCD, 0xEF, 0x00, 0x00, 0x00]);
+        assert_eq!(pixels[width*3..width*3 + 6], [0xAB, 0xCD, 0xEF, 0xAB, 0xCD, 0xEF]);
+    }
+}
@@ -0,0 +1,137 @@
+// Minimal PNG encoder: 8 bit RGB, no filtering and zlib stream made of stored (uncompressed) blocks.
+// Files are bigger than they could be, but any PNG reader opens them.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+const CRC_TABLE: [u32; 256] = create_crc_table();
+
+const fn create_crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFFFFFF, |crc, byte| CRC_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![0x78, 0x01]; // deflate with 32K window, no compression
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]); // empty final block
+    }
+    while let Some(block) = blocks.next() {
+        output.push(blocks.peek().is_none() as u8); // final block flag, block type 00 is stored
+        output.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        output.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+fn write_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let crc_start = output.len();
+    output.extend_from_slice(chunk_type);
+    output.extend_from_slice(data);
+    let crc = crc32(&output[crc_start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Pixels are RGB triples, row by row
+pub fn encode_png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height * 3, "Pixel data doesn't match image size");
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit depth, RGB, deflate, no filter, no interlace
+
+    let mut scanlines = Vec::with_capacity((width * 3 + 1) * height);
+    for row in pixels.chunks(width * 3) {
+        scanlines.push(0); // filter type none
+        scanlines.extend_from_slice(row);
+    }
+
+    let mut output = SIGNATURE.to_vec();
+    write_chunk(&mut output, b"IHDR", &header);
+    write_chunk(&mut output, b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut output, b"IEND", &[]);
+    output
+}
+
+#[cfg(test)]
+mod png_tests {
+    use super::*;
+
+    // Undoes zlib_stored, only understands stored blocks
+    fn unzlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let mut position = 2;
+        loop {
+            let is_final = data[position] & 1 != 0;
+            let length = u16::from_le_bytes([data[position + 1], data[position + 2]]) as usize;
+            assert_eq!(u16::from_le_bytes([data[position + 3], data[position + 4]]), !(length as u16));
+            output.extend_from_slice(&data[position + 5..position + 5 + length]);
+            position += 5 + length;
+            if is_final { break; }
+        }
+        assert_eq!(data[position..], adler32(&output).to_be_bytes());
+        output
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE426082);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn test_zlib_blocks() {
+        let data: Vec<u8> = (0..150000).map(|i| (i % 251) as u8).collect();
+        let zlib = zlib_stored(&data);
+
+        assert_eq!(zlib.len(), 2 + 3*5 + data.len() + 4);
+        assert_eq!(unzlib_stored(&zlib), data);
+        assert_eq!(unzlib_stored(&zlib_stored(&[])), vec![]);
+    }
+
+    #[test]
+    fn test_png_layout() {
+        let pixels = [0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF];
+        let png = encode_png(2, 2, &pixels);
+
+        assert_eq!(png[..8], SIGNATURE);
+        assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert_eq!(png[29..33], crc32(&png[12..29]).to_be_bytes());
+        let idat_length = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(png[37..41], *b"IDAT");
+        assert_eq!(unzlib_stored(&png[41..41 + idat_length]), [
+            0, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00,
+            0, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+        ]);
+        assert_eq!(png[png.len() - 12..], [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+}
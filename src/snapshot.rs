@@ -0,0 +1,234 @@
+// Emulator state as plain bytes. Components write their fields one after another
+// and have to read them back in exactly the same order.
+pub trait Snapshot {
+    fn save_state(&self, state: &mut StateWriter);
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str>;
+}
+
+// Components that aren't always there only add their state when they are
+impl<T: Snapshot> Snapshot for Option<T> {
+    fn save_state(&self, state: &mut StateWriter) {
+        if let Some(component) = self {
+            component.save_state(state);
+        }
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+        match self {
+            Some(component) => component.load_state(state),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn save_all(components: &[&dyn Snapshot]) -> Vec<u8> {
+    let mut state = StateWriter::default();
+    for component in components {
+        component.save_state(&mut state);
+    }
+    state.data
+}
+
+// Snapshot that doesn't fit puts components back the way they were, instead of leaving them half loaded
+pub fn load_all(components: &mut [&mut dyn Snapshot], data: &[u8]) -> Result<(), &'static str> {
+    let mut previous = StateWriter::default();
+    for component in components.iter() {
+        component.save_state(&mut previous);
+    }
+    let result = load_components(components, data);
+    if result.is_err() {
+        load_components(components, &previous.data).expect("Components can't load their own state");
+    }
+    result
+}
+
+fn load_components(components: &mut [&mut dyn Snapshot], data: &[u8]) -> Result<(), &'static str> {
+    let mut state = StateReader { data };
+    for component in components {
+        component.load_state(&mut state)?;
+    }
+    if !state.data.is_empty() {
+        return Err("Snapshot has more data than emulator state");
+    }
+    Ok(())
+}
+
+// Save state slot is "<rom path>.state", one per rom
+pub fn get_state_path(rom_path: &str) -> String {
+    format!("{rom_path}.state")
+}
+
+pub fn save_file(path: &str, components: &[&dyn Snapshot]) -> Result<(), &'static str> {
+    std::fs::write(path, save_all(components)).map_err(|_| "Couldn't write save state file")
+}
+
+pub fn load_file(path: &str, components: &mut [&mut dyn Snapshot]) -> Result<(), &'static str> {
+    let data = std::fs::read(path).map_err(|_| "Couldn't read save state file")?;
+    load_all(components, &data)
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_option_u8(&mut self, value: Option<u8>) {
+        self.write_bool(value.is_some());
+        self.write_u8(value.unwrap_or_default());
+    }
+
+    pub fn write_option_u16(&mut self, value: Option<u16>) {
+        self.write_bool(value.is_some());
+        self.write_u16(value.unwrap_or_default());
+    }
+
+    // Length goes first, so loading into memory of different size fails instead of reading garbage
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl StateReader<'_> {
+    fn take(&mut self, count: usize) -> Result<&[u8], &'static str> {
+        if self.data.len() < count {
+            return Err("Snapshot is too short");
+        }
+        let (taken, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, &'static str> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, &'static str> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, &'static str> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, &'static str> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, &'static str> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_option_u8(&mut self) -> Result<Option<u8>, &'static str> {
+        let is_some = self.read_bool()?;
+        let value = self.read_u8()?;
+        Ok(is_some.then_some(value))
+    }
+
+    pub fn read_option_u16(&mut self) -> Result<Option<u16>, &'static str> {
+        let is_some = self.read_bool()?;
+        let value = self.read_u16()?;
+        Ok(is_some.then_some(value))
+    }
+
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), &'static str> {
+        if self.read_u64()? != bytes.len() as u64 {
+            return Err("Snapshot doesn't match memory size");
+        }
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct TestComponent {
+        flag: bool,
+        counter: u64,
+        memory: [u8; 4],
+    }
+
+    impl Snapshot for TestComponent {
+        fn save_state(&self, state: &mut StateWriter) {
+            state.write_bool(self.flag);
+            state.write_u64(self.counter);
+            state.write_bytes(&self.memory);
+        }
+
+        fn load_state(&mut self, state: &mut StateReader) -> Result<(), &'static str> {
+            self.flag = state.read_bool()?;
+            self.counter = state.read_u64()?;
+            state.read_bytes(&mut self.memory)
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let first = TestComponent { flag: true, counter: 0x0123_4567_89AB, memory: [1, 2, 3, 4] };
+        let second = TestComponent { flag: false, counter: 7, memory: [5, 6, 7, 8] };
+        let data = save_all(&[&first, &second]);
+
+        let mut loaded = [TestComponent::default(), TestComponent::default()];
+        let [loaded_first, loaded_second] = &mut loaded;
+        load_all(&mut [loaded_first, loaded_second], &data).unwrap();
+        assert_eq!(loaded, [first, second]);
+    }
+
+    #[test]
+    fn test_mismatched_data() {
+        let data = save_all(&[&TestComponent::default()]);
+        let mut component = TestComponent::default();
+
+        assert!(load_all(&mut [&mut component], &data[..data.len() - 1]).is_err());
+        assert!(load_all(&mut [&mut component], &[data.clone(), vec![0]].concat()).is_err());
+        assert!(load_all(&mut [], &data).is_err());
+    }
+
+    #[test]
+    fn test_failed_load_keeps_state() {
+        let data = save_all(&[&TestComponent { flag: true, counter: 1, memory: [1, 1, 1, 1] }, &TestComponent::default()]);
+        let mut component = TestComponent { flag: false, counter: 2, memory: [2, 2, 2, 2] };
+
+        assert!(load_all(&mut [&mut component], &data).is_err());
+        assert_eq!(component, TestComponent { flag: false, counter: 2, memory: [2, 2, 2, 2] });
+    }
+
+    #[test]
+    fn test_optional_component() {
+        let present = Some(TestComponent { flag: true, counter: 3, memory: [1, 2, 3, 4] });
+        let data = save_all(&[&TestComponent::default(), &present, &None::<TestComponent>]);
+        assert_eq!(data.len(), save_all(&[&TestComponent::default(), &TestComponent::default()]).len());
+
+        let mut loaded = Some(TestComponent::default());
+        load_all(&mut [&mut TestComponent::default(), &mut loaded, &mut None::<TestComponent>], &data).unwrap();
+        assert_eq!(loaded, present);
+    }
+}